@@ -0,0 +1,554 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use candid::{candid_method, CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use serde_bytes::ByteBuf;
+
+use crate::mqtt::enqueue;
+use crate::timedb::{Duration, Entry, Expression, Value};
+use crate::{ensure_owner, TIME_DB};
+
+// Measurement the transitions of the alerts are written to
+const ALERTS_MEASUREMENT: &str = "_alerts";
+// Seconds between the checks of alerts waiting for time to pass
+const CHECK_INTERVAL: u64 = 30;
+// A series that sent no points for a whole check interval is silent
+const SILENT_AFTER: u64 = CHECK_INTERVAL * 1_000_000_000;
+
+// Alerts by rule name and tags of the series
+type AlertKey = (String, Vec<(String, String)>);
+
+thread_local! {
+    static RULES: RefCell<Vec<AlertRule>> = const { RefCell::new(vec![]) };
+    static ALERTS: RefCell<BTreeMap<AlertKey, Alert>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Debug, PartialEq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub enum AlertCondition {
+    Threshold(Expression),      // the points of a series match the expression
+    Absent(Option<Expression>), // no points from a series, of those whose points match the expression
+}
+
+/// Outbound message published on every transition of the alerts of a rule.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct AlertCommand {
+    pub topic: String,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// Rule raising an alert for every series of `measurement` its condition holds for. The
+/// alert fires once the condition held for `for_duration` (e.g. "5m"), right away without it.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct AlertRule {
+    pub name: String,
+    pub measurement: String,
+    pub condition: AlertCondition,
+    pub for_duration: Option<String>,
+    pub severity: Severity,
+    pub command: Option<AlertCommand>,
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Debug, PartialEq)]
+pub enum AlertState {
+    Inactive, // the condition stopped holding before the alert fired
+    Pending,  // the condition holds, for less than the duration of the rule
+    Firing,
+    Resolved, // the condition stopped holding after the alert fired
+}
+
+impl AlertState {
+    fn name(&self) -> &'static str {
+        match self {
+            AlertState::Inactive => "inactive",
+            AlertState::Pending => "pending",
+            AlertState::Firing => "firing",
+            AlertState::Resolved => "resolved",
+        }
+    }
+}
+
+/// Alert of a rule for one series, identified by its tags.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct Alert {
+    pub rule: String,
+    pub tags: Vec<(String, String)>,
+    pub state: AlertState,
+    pub since: u64,     // when the condition started to hold, or stopped holding
+    pub last_seen: u64, // timestamp of the latest point of the series
+}
+
+struct Transition {
+    alert: Alert,
+    time: u64,
+    severity: Severity,
+    command: Option<AlertCommand>,
+}
+
+impl AlertRule {
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || self.measurement.is_empty() {
+            return Err("Alert rules need a name and a measurement".to_string());
+        }
+        if let Some(duration) = &self.for_duration {
+            Duration::parse(duration)
+                .map_err(|err| format!("Invalid duration '{}': {}", duration, err))?;
+        } else if matches!(self.condition, AlertCondition::Absent(_)) {
+            return Err(format!(
+                "Rule '{}' checks for absent data, it needs a duration",
+                self.name
+            ));
+        }
+        if let Some(command) = &self.command {
+            if command.topic.is_empty() || command.topic.contains(['+', '#']) {
+                return Err(format!("Invalid topic '{}'", command.topic));
+            }
+            if command.qos > 2 {
+                return Err(format!("Invalid QoS {}, expected 0, 1 or 2", command.qos));
+            }
+        }
+        Ok(())
+    }
+
+    // Time the alert fires when the condition holds from `since` on
+    fn due(&self, since: u64) -> u64 {
+        match self.for_duration.as_deref().map(Duration::parse) {
+            Some(Ok(duration)) => duration.add_to(since).unwrap_or(u64::MAX),
+            _ => since,
+        }
+    }
+
+    // Updates the alert of the series of the point
+    fn observe(&self, alerts: &mut BTreeMap<AlertKey, Alert>, entry: &Entry) -> Option<Transition> {
+        let time = entry.timestamp;
+        let (matched, absent) = match &self.condition {
+            AlertCondition::Threshold(expression) => (expression.evaluate(entry), false),
+            AlertCondition::Absent(filter) => (
+                filter.as_ref().is_none_or(|filter| filter.evaluate(entry)),
+                true,
+            ),
+        };
+
+        let tags = series_tags(&entry.tags);
+        let key = (self.name.clone(), tags.clone());
+        // series are tracked from their first matching point on
+        if !matched && (absent || !alerts.contains_key(&key)) {
+            return None;
+        }
+        let alert = alerts.entry(key).or_insert(Alert {
+            rule: self.name.clone(),
+            tags,
+            state: AlertState::Inactive,
+            since: time,
+            last_seen: time,
+        });
+        alert.last_seen = alert.last_seen.max(time);
+
+        let (state, since) = match (alert.state, matched) {
+            // a point of a series checked for absent data ends its silence
+            (AlertState::Firing, _) if absent => (AlertState::Resolved, time),
+            (AlertState::Pending, _) if absent => (AlertState::Inactive, time),
+            _ if absent => return None,
+            (AlertState::Inactive | AlertState::Resolved, true) if self.due(time) <= time => {
+                (AlertState::Firing, time)
+            }
+            (AlertState::Inactive | AlertState::Resolved, true) => (AlertState::Pending, time),
+            (AlertState::Pending, true) if self.due(alert.since) <= time => {
+                (AlertState::Firing, alert.since)
+            }
+            (AlertState::Pending, false) => (AlertState::Inactive, time),
+            (AlertState::Firing, false) => (AlertState::Resolved, time),
+            _ => return None,
+        };
+        Some(self.transition(alert, state, since, time))
+    }
+
+    // Marks silent series of absent data rules as pending, and fires the pending alerts
+    // once time passing made their condition hold long enough
+    fn check(&self, alert: &mut Alert, now: u64) -> Vec<Transition> {
+        let mut transitions = vec![];
+        if matches!(self.condition, AlertCondition::Absent(_))
+            && matches!(alert.state, AlertState::Inactive | AlertState::Resolved)
+            && alert.last_seen.saturating_add(SILENT_AFTER) <= now
+        {
+            // dated when the series went silent, so that it isn't overwritten in `_alerts` by
+            // the firing transition of the same check
+            let since = alert.last_seen;
+            let silent = since + SILENT_AFTER;
+            transitions.push(self.transition(alert, AlertState::Pending, since, silent));
+        }
+        if alert.state == AlertState::Pending && self.due(alert.since) <= now {
+            let since = alert.since;
+            transitions.push(self.transition(alert, AlertState::Firing, since, now));
+        }
+        transitions
+    }
+
+    fn transition(
+        &self,
+        alert: &mut Alert,
+        state: AlertState,
+        since: u64,
+        time: u64,
+    ) -> Transition {
+        alert.state = state;
+        alert.since = since;
+        Transition {
+            alert: alert.clone(),
+            time,
+            severity: self.severity,
+            command: self.command.clone(),
+        }
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn get_alert_rules() -> Vec<AlertRule> {
+    RULES.with(|r| r.borrow().clone())
+}
+
+/// Replaces the alert rules. Alerts of the rules that keep their name keep their state.
+#[update]
+#[candid_method(update)]
+pub(crate) fn set_alert_rules(rules: Vec<AlertRule>) -> Result<(), String> {
+    ensure_owner()?;
+    for (i, rule) in rules.iter().enumerate() {
+        rule.validate()?;
+        if rules[..i].iter().any(|other| other.name == rule.name) {
+            return Err(format!("Duplicate alert rule '{}'", rule.name));
+        }
+    }
+
+    ALERTS.with(|a| {
+        a.borrow_mut()
+            .retain(|(name, _), _| rules.iter().any(|rule| rule.name == *name))
+    });
+    RULES.with(|r| *r.borrow_mut() = rules);
+    Ok(())
+}
+
+/// Alerts that are pending or firing.
+#[query]
+#[candid_method(query)]
+pub(crate) fn get_alerts() -> Vec<Alert> {
+    ALERTS.with(|a| {
+        a.borrow()
+            .values()
+            .filter(|alert| matches!(alert.state, AlertState::Pending | AlertState::Firing))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Checks the alerts waiting for time to pass every 30 seconds.
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(CHECK_INTERVAL), || {
+        check(crate::now())
+    });
+}
+
+/// Evaluates the rules of the measurement on points written to it. The rules see the values
+/// as stored, after the transform rules.
+pub fn evaluate(measurement: &str, entries: &[Entry]) {
+    let rules: Vec<AlertRule> = RULES.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|rule| rule.measurement == measurement)
+            .cloned()
+            .collect()
+    });
+    if rules.is_empty() {
+        return;
+    }
+
+    let transforms = TIME_DB.with(|m| {
+        m.borrow()
+            .find_measurement(measurement)
+            .map(|measure| measure.transform_rules().clone())
+            .unwrap_or_default()
+    });
+    let mut points: Vec<Entry> = entries.to_vec();
+    points.sort_by_key(|entry| entry.timestamp);
+
+    let transitions: Vec<Transition> = ALERTS.with(|a| {
+        let mut alerts = a.borrow_mut();
+        points
+            .iter_mut()
+            .flat_map(|entry| {
+                transforms.apply(&mut entry.fields, &entry.tags, &[]);
+                rules
+                    .iter()
+                    .filter_map(|rule| rule.observe(&mut alerts, entry))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    });
+    record(transitions, crate::now());
+}
+
+fn check(now: u64) {
+    let rules = RULES.with(|r| r.borrow().clone());
+    let transitions: Vec<Transition> = ALERTS.with(|a| {
+        a.borrow_mut()
+            .iter_mut()
+            .flat_map(
+                |((name, _), alert)| match rules.iter().find(|rule| rule.name == *name) {
+                    Some(rule) => rule.check(alert, now),
+                    None => vec![],
+                },
+            )
+            .collect()
+    });
+    record(transitions, now);
+}
+
+// Writes the transitions to `_alerts` and queues their commands
+fn record(transitions: Vec<Transition>, now: u64) {
+    for transition in transitions {
+        let Transition {
+            alert,
+            time,
+            severity,
+            command,
+        } = transition;
+        let severity = format!("{:?}", severity).to_lowercase();
+
+        let mut tags: HashMap<String, Value> = alert
+            .tags
+            .iter()
+            .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+            .collect();
+        tags.insert("rule".to_string(), Value::String(alert.rule.clone()));
+        tags.insert("severity".to_string(), Value::String(severity.clone()));
+        let fields = HashMap::from([
+            (
+                "state".to_string(),
+                Value::String(alert.state.name().to_string()),
+            ),
+            ("since".to_string(), Value::UInt(alert.since as u128)),
+        ]);
+        // not through `store_entries`: rules on `_alerts` would evaluate the transitions
+        // they write themselves, without end
+        TIME_DB.with(|m| {
+            m.borrow_mut()
+                .get_measurement(ALERTS_MEASUREMENT)
+                .add_entry(time, &fields, &tags)
+        });
+
+        if let Some(command) = command {
+            let payload = serde_json::json!({
+                "rule": alert.rule,
+                "state": alert.state.name(),
+                "severity": severity,
+                "tags": alert.tags.iter().cloned().collect::<BTreeMap<_, _>>(),
+                "since": alert.since,
+                "timestamp": time,
+            });
+            let payload = ByteBuf::from(payload.to_string().into_bytes());
+            // the topic and QoS were validated with the rule
+            let _ = enqueue(command.topic, payload, command.qos, command.retain, now);
+        }
+    }
+}
+
+// Tags identifying the series of a point, sorted by name
+fn series_tags(tags: &HashMap<String, Value>) -> Vec<(String, String)> {
+    let mut tags: Vec<(String, String)> = tags
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Int(value) => value.to_string(),
+                Value::UInt(value) => value.to_string(),
+                Value::Float(value) => value.to_string(),
+                Value::Bool(value) => value.to_string(),
+                Value::None => String::new(),
+            };
+            (name.clone(), value)
+        })
+        .collect();
+    tags.sort();
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_types::HttpRequest;
+    use crate::timedb::{Action, QueryLimits};
+    use crate::{MessageQuery, OUT_MESSAGES};
+    use serde_bytes::ByteBuf;
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    fn point(timestamp: u64, sensor: &str, temperature: f32) -> Entry {
+        Entry {
+            timestamp,
+            fields: HashMap::from([("temperature".to_string(), Value::Float(temperature))]),
+            tags: HashMap::from([("sensor_id".to_string(), Value::String(sensor.to_string()))]),
+        }
+    }
+
+    fn rule(name: &str, condition: AlertCondition, for_duration: Option<&str>) -> AlertRule {
+        AlertRule {
+            name: name.to_string(),
+            measurement: "climate".to_string(),
+            condition,
+            for_duration: for_duration.map(str::to_string),
+            severity: Severity::Critical,
+            command: None,
+        }
+    }
+
+    fn states() -> Vec<String> {
+        TIME_DB.with(|m| {
+            m.borrow()
+                .find_measurement(ALERTS_MEASUREMENT)
+                .unwrap()
+                .apply(&[Action::Range(0, None)], &QueryLimits::default(), None)
+                .unwrap()
+                .entries
+                .iter()
+                .map(|entry| match entry.fields.get("state") {
+                    Some(Value::String(state)) => state.clone(),
+                    _ => panic!("no state"),
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_threshold_alerts() {
+        let hot = AlertCondition::Threshold(Expression::Gt(
+            "temperature".to_string(),
+            Value::Float(30.0),
+        ));
+        let mut hot = rule("hot", hot, Some("1m"));
+        hot.command = Some(AlertCommand {
+            topic: "sirens/hall".to_string(),
+            qos: 1,
+            retain: false,
+        });
+        set_alert_rules(vec![hot]).unwrap();
+
+        let start = crate::now() - 10 * MINUTE;
+        let points = vec![
+            point(start, "s1", 35.0),
+            point(start, "s2", 20.0),
+            point(start + MINUTE / 2, "s1", 36.0),
+            point(start + 2 * MINUTE, "s1", 37.0),
+            point(start + 3 * MINUTE, "s1", 20.0),
+        ];
+        crate::insert_bulk("climate".to_string(), points, None).unwrap();
+        assert_eq!(states(), vec!["pending", "firing", "resolved"]);
+        assert!(get_alerts().is_empty());
+
+        let commands = OUT_MESSAGES.with(|m| m.borrow().query(&MessageQuery::default()));
+        let commands = commands.unwrap().data;
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[1].topic, "sirens/hall");
+        let firing: serde_json::Value = serde_json::from_slice(&commands[1].message).unwrap();
+        assert_eq!(firing["state"], "firing");
+        assert_eq!(firing["severity"], "critical");
+        assert_eq!(firing["tags"]["sensor_id"], "s1");
+        assert_eq!(firing["since"], start);
+
+        // fires on the timer when no point tells otherwise
+        let time = start + 5 * MINUTE;
+        evaluate("climate", &[point(time, "s1", 40.0)]);
+        check(time + MINUTE / 2);
+        assert_eq!(get_alerts()[0].state, AlertState::Pending);
+        check(time + MINUTE);
+        let alerts = get_alerts();
+        assert_eq!(alerts[0].state, AlertState::Firing);
+        assert_eq!(alerts[0].since, time);
+        assert_eq!(
+            alerts[0].tags,
+            vec![("sensor_id".to_string(), "s1".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_absent_alerts() {
+        let silent = rule("silent", AlertCondition::Absent(None), Some("5m"));
+        assert!(set_alert_rules(vec![silent.clone(), silent.clone()]).is_err());
+        let mut without_duration = silent.clone();
+        without_duration.for_duration = None;
+        assert!(set_alert_rules(vec![without_duration]).is_err());
+        set_alert_rules(vec![silent]).unwrap();
+
+        let now = crate::now();
+        evaluate("climate", &[point(now - 10 * MINUTE, "s1", 20.0)]);
+        evaluate("climate", &[point(now - 9 * MINUTE, "s2", 20.0)]);
+        assert!(get_alerts().is_empty());
+
+        // pending once silent for a check interval, firing once silent for the duration
+        check(now - 8 * MINUTE);
+        let alerts = get_alerts();
+        assert_eq!(alerts.len(), 2);
+        assert!(alerts
+            .iter()
+            .all(|alert| alert.state == AlertState::Pending));
+        assert_eq!(alerts[0].since, now - 10 * MINUTE);
+
+        evaluate("climate", &[point(now - MINUTE / 6, "s2", 20.0)]);
+        check(now);
+        let alerts = get_alerts();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].state, AlertState::Firing);
+        assert_eq!(alerts[0].since, now - 10 * MINUTE);
+
+        evaluate("climate", &[point(now + 1, "s1", 20.0)]);
+        assert!(get_alerts().is_empty());
+        assert_eq!(
+            states(),
+            vec!["pending", "pending", "inactive", "firing", "resolved"]
+        );
+
+        // a silence found late still goes through pending
+        check(now + 10 * MINUTE);
+        let alerts = get_alerts();
+        assert_eq!(alerts.len(), 2);
+        assert!(alerts.iter().all(|alert| alert.state == AlertState::Firing));
+        assert_eq!(states().len(), 9);
+
+        set_alert_rules(vec![]).unwrap();
+        check(now + 20 * MINUTE);
+        assert_eq!(states().len(), 9);
+    }
+
+    #[test]
+    fn test_alerts_on_influx_writes() {
+        let hot = AlertCondition::Threshold(Expression::Gt(
+            "temperature".to_string(),
+            Value::Float(30.0),
+        ));
+        let hot = AlertRule {
+            measurement: "acme/edge/climate".to_string(),
+            ..rule("hot", hot, None)
+        };
+        set_alert_rules(vec![hot]).unwrap();
+
+        let lines = format!("climate,sensor_id=s1 temperature=35 {}", crate::now());
+        let req = HttpRequest {
+            method: "POST".to_string(),
+            url: "/api/v2/write?org=acme&bucket=edge".to_string(),
+            headers: vec![("Authorization".to_string(), "Token secret".to_string())],
+            body: ByteBuf::from(lines.into_bytes()),
+        };
+        crate::http::set_write_tokens(vec!["secret".to_string()]).unwrap();
+        crate::influx::write(&req).unwrap();
+        let alerts = get_alerts();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].state, AlertState::Firing);
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::{HashMap, VecDeque};
+
+use candid::Principal;
+
+// Batches are remembered for a day, and no more than this many of them per caller
+const BATCH_WINDOW: u64 = 24 * 3600 * 1_000_000_000;
+const MAX_BATCHES: usize = 10_000;
+
+/// Results of the batches applied recently, by caller and sequence number.
+///
+/// Gateways retry a batch when its call times out, without knowing whether it was applied.
+/// A retried batch isn't applied again but answered with the result of the first call.
+/// Failed batches aren't remembered, they stored nothing and can be retried.
+pub struct AppliedBatches<T> {
+    results: HashMap<(Principal, u64), T>,
+    applied: HashMap<Principal, VecDeque<(u64, u64)>>, // time and sequence of each batch, oldest first
+}
+
+impl<T> Default for AppliedBatches<T> {
+    fn default() -> Self {
+        Self {
+            results: HashMap::new(),
+            applied: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone> AppliedBatches<T> {
+    /// Calls `apply` unless `caller` already applied the batch `sequence`, and returns the
+    /// result of the first successful call. Batches without a sequence number are always
+    /// applied. Anonymous callers can't number their batches, they would share the numbers.
+    pub fn apply_once(
+        &mut self,
+        caller: Principal,
+        sequence: Option<u64>,
+        now: u64,
+        apply: impl FnOnce() -> Result<T, String>,
+    ) -> Result<T, String> {
+        let Some(sequence) = sequence else {
+            return apply();
+        };
+        if caller == Principal::anonymous() {
+            return Err("Anonymous callers can't number their batches".to_string());
+        }
+
+        self.forget_before(now.saturating_sub(BATCH_WINDOW));
+        if let Some(result) = self.results.get(&(caller, sequence)) {
+            return Ok(result.clone());
+        }
+
+        let result = apply()?;
+        // a caller over its cap forgets its own oldest batch, not those of the others
+        let applied = self.applied.entry(caller).or_default();
+        if applied.len() == MAX_BATCHES {
+            if let Some((_, oldest)) = applied.pop_front() {
+                self.results.remove(&(caller, oldest));
+            }
+        }
+        applied.push_back((now, sequence));
+        self.results.insert((caller, sequence), result.clone());
+        Ok(result)
+    }
+
+    fn forget_before(&mut self, time: u64) {
+        let results = &mut self.results;
+        self.applied.retain(|caller, applied| {
+            while let Some(&(applied_at, sequence)) = applied.front() {
+                if applied_at >= time {
+                    break;
+                }
+                applied.pop_front();
+                results.remove(&(*caller, sequence));
+            }
+            !applied.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_once() {
+        let mut batches = AppliedBatches::default();
+        let gateway = Principal::management_canister();
+        let other = Principal::from_slice(&[1]);
+        let mut calls = 0;
+        let mut apply = |result: Result<u32, String>| {
+            calls += 1;
+            result
+        };
+
+        assert_eq!(
+            batches.apply_once(gateway, Some(1), 0, || apply(Ok(10))),
+            Ok(10)
+        );
+        assert_eq!(
+            batches.apply_once(gateway, Some(1), 1, || apply(Ok(20))),
+            Ok(10)
+        );
+        assert_eq!(
+            batches.apply_once(other, Some(1), 2, || apply(Ok(30))),
+            Ok(30)
+        );
+        assert_eq!(
+            batches.apply_once(gateway, None, 3, || apply(Ok(40))),
+            Ok(40)
+        );
+        assert_eq!(
+            batches.apply_once(gateway, None, 4, || apply(Ok(50))),
+            Ok(50)
+        );
+
+        // failed batches are applied again
+        let failed = Err("failed".to_string());
+        assert_eq!(
+            batches.apply_once(gateway, Some(2), 5, || apply(failed.clone())),
+            failed
+        );
+        assert_eq!(
+            batches.apply_once(gateway, Some(2), 6, || apply(Ok(60))),
+            Ok(60)
+        );
+
+        // anonymous callers would share the sequence numbers
+        let anonymous = Principal::anonymous();
+        assert!(batches
+            .apply_once(anonymous, Some(3), 7, || apply(Ok(70)))
+            .is_err());
+        assert_eq!(
+            batches.apply_once(anonymous, None, 8, || apply(Ok(80))),
+            Ok(80)
+        );
+
+        // forgotten after a day
+        assert_eq!(
+            batches.apply_once(gateway, Some(1), BATCH_WINDOW + 7, || apply(Ok(90))),
+            Ok(90)
+        );
+        assert_eq!(calls, 8);
+        assert_eq!(batches.results.len(), 1);
+    }
+
+    #[test]
+    fn test_cap_per_caller() {
+        let mut batches = AppliedBatches::default();
+        let busy = Principal::management_canister();
+        let quiet = Principal::from_slice(&[1]);
+
+        batches.apply_once(quiet, Some(0), 0, || Ok(0)).unwrap();
+        for sequence in 0..=MAX_BATCHES as u64 {
+            batches
+                .apply_once(busy, Some(sequence), 1, || Ok(1))
+                .unwrap();
+        }
+
+        // the busy gateway forgot its oldest batch, the other one still has its own
+        assert_eq!(batches.apply_once(busy, Some(0), 2, || Ok(2)), Ok(2));
+        assert_eq!(batches.apply_once(busy, Some(2), 2, || Ok(2)), Ok(1));
+        assert_eq!(batches.apply_once(quiet, Some(0), 2, || Ok(2)), Ok(0));
+        assert_eq!(batches.results.len(), MAX_BATCHES + 1);
+    }
+}
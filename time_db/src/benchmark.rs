@@ -0,0 +1,116 @@
+#[cfg(feature = "benchmark")]
+use candid::candid_method;
+use candid::{CandidType, Deserialize};
+#[cfg(feature = "benchmark")]
+use ic_cdk_macros::query;
+
+use crate::timedb::{Action, QueryLimits, TimeDb};
+#[cfg(feature = "benchmark")]
+use crate::TIME_DB;
+
+/// Cost of a query run by the eager executor and by the iterator based one that replaced it.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct QueryBenchmark {
+    pub rows: u64,
+    pub eager_instructions: u64,
+    pub lazy_instructions: u64,
+}
+
+/// Runs the query through both executors and reports the instructions each of them used.
+/// Only built with the `benchmark` feature, it stays out of the regular interface.
+#[cfg(feature = "benchmark")]
+#[query]
+#[candid_method(query)]
+fn benchmark_query(measurement: String, actions: Vec<Action>) -> Result<QueryBenchmark, String> {
+    let limits = crate::caller_query_limits();
+    TIME_DB.with(|m| {
+        compare(&m.borrow(), &measurement, &actions, &limits, || {
+            ic_cdk::api::performance_counter(0)
+        })
+    })
+}
+
+// `counter` is the instruction counter in a canister, unit tests pass the elapsed time
+fn compare(
+    db: &TimeDb,
+    measurement: &str,
+    actions: &[Action],
+    limits: &QueryLimits,
+    counter: impl Fn() -> u64,
+) -> Result<QueryBenchmark, String> {
+    let measure = db
+        .find_measurement(measurement)
+        .ok_or_else(|| format!("Measurement '{}' not found", measurement))?;
+
+    let start = counter();
+    let eager = measure
+        .apply_eager(actions)
+        .map(|response| response.map(|response| response.eval()))
+        .map_err(|err| err.to_string())?;
+    let eager_instructions = counter() - start;
+
+    let start = counter();
+    let lazy = measure
+        .apply(actions, limits, None)
+        .map_err(|err| err.to_string())?;
+    let lazy_instructions = counter() - start;
+
+    let rows = lazy.entries.len() as u64;
+    if rows != eager.map_or(0, |entries| entries.len()) as u64 {
+        return Err("Eager and lazy execution returned different results".to_string());
+    }
+
+    Ok(QueryBenchmark {
+        rows,
+        eager_instructions,
+        lazy_instructions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::timedb::{test_helper::create_test_entries, AggregateFunction, Expression, Value};
+
+    #[test]
+    fn test_compare_executors() {
+        let mut db = TimeDb::new();
+        let measurement = db.get_measurement("climate");
+        for entry in create_test_entries() {
+            measurement.add_entry(entry.timestamp, &entry.fields, &entry.tags);
+        }
+
+        let queries = [
+            ("range", vec![Action::Range(1625230000, None)]),
+            (
+                "filter",
+                vec![
+                    Action::Filter(Expression::Gt("temperature".to_string(), Value::Int(20))),
+                    Action::Filter(Expression::FieldFilter(vec!["temperature".to_string()])),
+                ],
+            ),
+            (
+                "aggregate",
+                vec![Action::AggregateWindow(
+                    "100us".to_string(),
+                    AggregateFunction::Mean,
+                )],
+            ),
+        ];
+
+        // the instruction counter only exists in a canister, nanoseconds stand in for it
+        let started = Instant::now();
+        let counter = || started.elapsed().as_nanos() as u64;
+        for (name, actions) in queries {
+            let benchmark =
+                compare(&db, "climate", &actions, &QueryLimits::default(), counter).unwrap();
+            assert!(benchmark.rows > 0);
+            println!(
+                "{}: {} rows, eager {} ns, lazy {} ns",
+                name, benchmark.rows, benchmark.eager_instructions, benchmark.lazy_instructions
+            );
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use candid::candid_method;
+use ic_cdk_macros::{query, update};
+
+use crate::ensure_owner;
+use crate::timedb::{Device, DEVICES};
+
+/// Registers the device whose id is `id`, or replaces its metadata. Queries join it to the
+/// entries with `Action::Enrich`.
+#[update]
+#[candid_method(update)]
+fn set_device(id: String, device: Device) -> Result<(), String> {
+    ensure_owner()?;
+    DEVICES.with(|d| d.borrow_mut().set(id, device))
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_device(id: String) -> Result<(), String> {
+    ensure_owner()?;
+    match DEVICES.with(|d| d.borrow_mut().remove(&id)) {
+        Some(_) => Ok(()),
+        None => Err(format!("Device '{}' is not registered", id)),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn get_device(id: String) -> Option<Device> {
+    DEVICES.with(|d| d.borrow().get(&id).cloned())
+}
+
+/// Lists the registered devices ordered by id.
+#[query]
+#[candid_method(query)]
+fn list_devices() -> Vec<(String, Device)> {
+    DEVICES.with(|d| {
+        d.borrow()
+            .devices()
+            .map(|(id, device)| (id.clone(), device.clone()))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SETTINGS;
+    use candid::Principal;
+
+    #[test]
+    fn test_devices() {
+        let device = Device {
+            name: "Kitchen".to_string(),
+            unit: Some("°C".to_string()),
+            ..Default::default()
+        };
+        set_device("sensor_1".to_string(), device.clone()).unwrap();
+        assert_eq!(get_device("sensor_1".to_string()), Some(device.clone()));
+        assert_eq!(list_devices(), vec![("sensor_1".to_string(), device)]);
+
+        SETTINGS.with(|s| s.borrow_mut().owner = Principal::management_canister());
+        assert!(remove_device("sensor_1".to_string()).is_err());
+        assert!(set_device("sensor_2".to_string(), Device::default()).is_err());
+
+        SETTINGS.with(|s| s.borrow_mut().owner = Principal::anonymous());
+        assert!(remove_device("sensor_1".to_string()).is_ok());
+        assert!(remove_device("sensor_1".to_string()).is_err());
+        assert!(list_devices().is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+use std::{collections::HashMap, sync::Arc};
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Float32Array, Int64Array, RecordBatch, StringArray,
+    TimestampNanosecondArray, UInt64Array,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema, TimeUnit};
+
+use crate::timedb::{Entry, Value};
+
+use super::{cell, Columns};
+
+/// Metadata key telling whether a column holds a tag or a field.
+pub const KIND_METADATA: &str = "timedb.kind";
+
+/// Writes entries as an Arrow IPC stream holding a single record batch.
+pub fn to_arrow_ipc(entries: &[Entry]) -> Result<Vec<u8>, ArrowError> {
+    let batch = record_batch(entries)?;
+
+    let mut buffer = vec![];
+    let mut writer = StreamWriter::try_new(&mut buffer, &batch.schema())?;
+    writer.write(&batch)?;
+    writer.finish()?;
+    drop(writer);
+
+    Ok(buffer)
+}
+
+/// Writes entries as an uncompressed Parquet file with the same columns as `to_arrow_ipc`.
+#[cfg(feature = "parquet")]
+pub fn to_parquet(entries: &[Entry]) -> Result<Vec<u8>, parquet::errors::ParquetError> {
+    let batch = record_batch(entries)?;
+
+    let mut buffer = vec![];
+    let mut writer = parquet::arrow::ArrowWriter::try_new(&mut buffer, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+
+    Ok(buffer)
+}
+
+/// Builds the table of the entries: a non-nullable `timestamp` column in nanoseconds, then
+/// one nullable column per tag and one per field, both sorted by name.
+///
+/// Columns are typed after their values: `Int` values become `Int64`, `UInt` values `UInt64`,
+/// `Float` values `Float32`, `Bool` values `Boolean` and `String` values `Utf8`. Columns mixing
+/// several types, or holding integers that don't fit into 64 bits, are written as `Utf8`.
+fn record_batch(entries: &[Entry]) -> Result<RecordBatch, ArrowError> {
+    let columns = Columns::of(entries);
+
+    let timestamps = entries
+        .iter()
+        .map(|entry| {
+            i64::try_from(entry.timestamp).map_err(|_| {
+                ArrowError::InvalidArgumentError(format!(
+                    "timestamp {} does not fit into an Arrow timestamp",
+                    entry.timestamp
+                ))
+            })
+        })
+        .collect::<Result<Vec<i64>, ArrowError>>()?;
+
+    let mut fields = vec![Field::new(
+        "timestamp",
+        DataType::Timestamp(TimeUnit::Nanosecond, None),
+        false,
+    )];
+    let mut arrays: Vec<ArrayRef> = vec![Arc::new(TimestampNanosecondArray::from(timestamps))];
+
+    let tags = columns.tags.iter().map(|tag| {
+        let values: Vec<Option<&Value>> = entries.iter().map(|entry| entry.tags.get(tag)).collect();
+        (tag, "tag", values)
+    });
+    let entry_fields = columns.fields.iter().map(|field| {
+        let values: Vec<Option<&Value>> = entries
+            .iter()
+            .map(|entry| entry.fields.get(field))
+            .collect();
+        (field, "field", values)
+    });
+
+    for (name, kind, values) in tags.chain(entry_fields) {
+        let array = column(&values);
+        fields.push(
+            Field::new(name, array.data_type().clone(), true).with_metadata(HashMap::from([(
+                KIND_METADATA.to_string(),
+                kind.to_string(),
+            )])),
+        );
+        arrays.push(array);
+    }
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)
+}
+
+fn column(values: &[Option<&Value>]) -> ArrayRef {
+    match column_type(values) {
+        DataType::Int64 => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    Some(Value::Int(value)) => Some(*value as i64),
+                    _ => None,
+                })
+                .collect::<Int64Array>(),
+        ),
+        DataType::UInt64 => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    Some(Value::UInt(value)) => Some(*value as u64),
+                    _ => None,
+                })
+                .collect::<UInt64Array>(),
+        ),
+        DataType::Float32 => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    Some(Value::Float(value)) => Some(*value),
+                    _ => None,
+                })
+                .collect::<Float32Array>(),
+        ),
+        DataType::Boolean => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    Some(Value::Bool(value)) => Some(*value),
+                    _ => None,
+                })
+                .collect::<BooleanArray>(),
+        ),
+        _ => Arc::new(
+            values
+                .iter()
+                .map(|value| match value {
+                    None | Some(Value::None) => None,
+                    value => Some(cell(*value)),
+                })
+                .collect::<StringArray>(),
+        ),
+    }
+}
+
+fn column_type(values: &[Option<&Value>]) -> DataType {
+    let mut column_type = None;
+
+    for value in values.iter().flatten() {
+        let value_type = match value {
+            Value::Int(value) if i64::try_from(*value).is_ok() => DataType::Int64,
+            Value::UInt(value) if u64::try_from(*value).is_ok() => DataType::UInt64,
+            Value::Float(_) => DataType::Float32,
+            Value::Bool(_) => DataType::Boolean,
+            Value::None => continue,
+            _ => return DataType::Utf8,
+        };
+
+        match &column_type {
+            None => column_type = Some(value_type),
+            Some(current) if *current != value_type => return DataType::Utf8,
+            _ => {}
+        }
+    }
+
+    column_type.unwrap_or(DataType::Utf8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use arrow_array::Array;
+    use arrow_ipc::reader::StreamReader;
+
+    use super::*;
+
+    #[test]
+    fn test_arrow_ipc() {
+        let entry = |timestamp: u64, fields: Vec<(&str, Value)>| Entry {
+            timestamp,
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            tags: HashMap::from([("sensor_id".to_string(), Value::String("s1".to_string()))]),
+        };
+        let entries = vec![
+            entry(
+                10,
+                vec![
+                    ("temperature", Value::Float(20.5)),
+                    ("count", Value::Int(3)),
+                ],
+            ),
+            entry(
+                20,
+                vec![
+                    ("temperature", Value::Float(21.0)),
+                    ("count", Value::UInt(4)),
+                ],
+            ),
+            entry(30, vec![("on", Value::Bool(true))]),
+        ];
+
+        let ipc = to_arrow_ipc(&entries).unwrap();
+        let mut reader = StreamReader::try_new(Cursor::new(ipc), None).unwrap();
+        let batch = reader.next().unwrap().unwrap();
+        assert!(reader.next().is_none());
+
+        let schema = batch.schema();
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["timestamp", "sensor_id", "count", "on", "temperature"]
+        );
+        assert_eq!(
+            schema.field(1).metadata().get(KIND_METADATA),
+            Some(&"tag".to_string())
+        );
+        assert_eq!(schema.field(2).data_type(), &DataType::Utf8); // Int and UInt mixed
+        assert_eq!(schema.field(3).data_type(), &DataType::Boolean);
+
+        let temperature = batch
+            .column(4)
+            .as_any()
+            .downcast_ref::<Float32Array>()
+            .unwrap();
+        assert_eq!(temperature.value(1), 21.0);
+        assert!(temperature.is_null(2));
+
+        let timestamps = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<TimestampNanosecondArray>()
+            .unwrap();
+        assert_eq!(timestamps.values().to_vec(), vec![10, 20, 30]);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet() {
+        let parquet = to_parquet(&[]).unwrap();
+        assert!(parquet.starts_with(b"PAR1") && parquet.ends_with(b"PAR1"));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use crate::timedb::{format_rfc3339, Entry, Value};
+
+use super::{cell, Columns};
+
+const LINE_END: &str = "\r\n";
+
+/// Flattens entries into CSV: a `timestamp` column in nanoseconds, then one column per
+/// tag and one per field, both sorted by name. Missing values are left empty.
+pub fn to_csv(entries: &[Entry]) -> String {
+    let columns = Columns::of(entries);
+    let mut csv = String::new();
+
+    let header: Vec<&str> = std::iter::once("timestamp")
+        .chain(columns.tags.iter().map(|tag| tag.as_str()))
+        .chain(columns.fields.iter().map(|field| field.as_str()))
+        .collect();
+    write_row(&mut csv, header.into_iter().map(escape));
+
+    for entry in entries {
+        let row = std::iter::once(entry.timestamp.to_string())
+            .chain(columns.tags.iter().map(|tag| cell(entry.tags.get(tag))))
+            .chain(
+                columns
+                    .fields
+                    .iter()
+                    .map(|field| cell(entry.fields.get(field))),
+            );
+        write_row(&mut csv, row.map(|value| escape(&value)));
+    }
+
+    csv
+}
+
+/// Flattens entries into the annotated CSV read by InfluxDB tools.
+///
+/// Columns are the same as for `to_csv`, preceded by `result` and `table` and with the
+/// time in RFC 3339. Each tag set gets its own table, the `#datatype` annotation gives
+/// the type of every column (columns holding values of several types are strings) and
+/// `#group` marks the tag columns.
+pub fn to_annotated_csv(entries: &[Entry]) -> String {
+    let columns = Columns::of(entries);
+    let mut csv = String::new();
+
+    let tag_types = columns
+        .tags
+        .iter()
+        .map(|tag| datatype(entries.iter().map(|entry| entry.tags.get(tag))));
+    let field_types = columns
+        .fields
+        .iter()
+        .map(|field| datatype(entries.iter().map(|entry| entry.fields.get(field))));
+
+    write_row(
+        &mut csv,
+        ["#datatype", "string", "long", "dateTime:RFC3339Nano"]
+            .into_iter()
+            .chain(tag_types)
+            .chain(field_types)
+            .map(escape),
+    );
+    write_row(
+        &mut csv,
+        ["#group", "false", "false", "false"]
+            .into_iter()
+            .chain(columns.tags.iter().map(|_| "true"))
+            .chain(columns.fields.iter().map(|_| "false"))
+            .map(escape),
+    );
+    write_row(
+        &mut csv,
+        ["#default", "_result", "", ""]
+            .into_iter()
+            .chain(columns.tags.iter().map(|_| ""))
+            .chain(columns.fields.iter().map(|_| ""))
+            .map(escape),
+    );
+    write_row(
+        &mut csv,
+        ["", "result", "table", "_time"]
+            .into_iter()
+            .chain(columns.tags.iter().map(|tag| tag.as_str()))
+            .chain(columns.fields.iter().map(|field| field.as_str()))
+            .map(escape),
+    );
+
+    // Rows of a table have to be next to each other, tables are numbered in order of appearance
+    let mut tables: HashMap<Vec<String>, usize> = HashMap::new();
+    let mut rows: Vec<(usize, &Entry)> = entries
+        .iter()
+        .map(|entry| {
+            let key: Vec<String> = columns
+                .tags
+                .iter()
+                .map(|tag| format!("{:?}", entry.tags.get(tag)))
+                .collect();
+            let next = tables.len();
+            (*tables.entry(key).or_insert(next), entry)
+        })
+        .collect();
+    rows.sort_by_key(|(table, _)| *table);
+
+    for (table, entry) in rows {
+        let row = [
+            "".to_string(),
+            "".to_string(),
+            table.to_string(),
+            format_rfc3339(entry.timestamp),
+        ]
+        .into_iter()
+        .chain(columns.tags.iter().map(|tag| cell(entry.tags.get(tag))))
+        .chain(
+            columns
+                .fields
+                .iter()
+                .map(|field| cell(entry.fields.get(field))),
+        );
+        write_row(&mut csv, row.map(|value| escape(&value)));
+    }
+
+    csv.push_str(LINE_END);
+    csv
+}
+
+fn write_row(csv: &mut String, cells: impl Iterator<Item = String>) {
+    for (i, cell) in cells.enumerate() {
+        if i > 0 {
+            csv.push(',');
+        }
+        csv.push_str(&cell);
+    }
+    csv.push_str(LINE_END);
+}
+
+// Quotes cells containing separators, quotes or line breaks (RFC 4180)
+fn escape(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn datatype<'a>(values: impl Iterator<Item = Option<&'a Value>>) -> &'static str {
+    let mut datatype = None;
+
+    for value in values.flatten() {
+        let value_type = match value {
+            Value::String(_) => "string",
+            Value::Int(_) => "long",
+            Value::UInt(_) => "unsignedLong",
+            Value::Float(_) => "double",
+            Value::Bool(_) => "boolean",
+            Value::None => continue,
+        };
+
+        match datatype {
+            None => datatype = Some(value_type),
+            Some(current) if current != value_type => return "string",
+            _ => {}
+        }
+    }
+
+    datatype.unwrap_or("string")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<Entry> {
+        let entry = |timestamp: u64, sensor: &str, fields: Vec<(&str, Value)>| Entry {
+            timestamp,
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            tags: HashMap::from([("sensor_id".to_string(), Value::String(sensor.to_string()))]),
+        };
+
+        vec![
+            entry(
+                1_000_000_000,
+                "s1",
+                vec![
+                    ("temperature", Value::Float(20.5)),
+                    ("on", Value::Bool(true)),
+                ],
+            ),
+            entry(
+                2_000_000_000,
+                "s2",
+                vec![
+                    ("temperature", Value::Int(21)),
+                    ("note", Value::String("a, \"b\"".to_string())),
+                ],
+            ),
+            entry(
+                3_000_000_000,
+                "s1",
+                vec![("temperature", Value::Float(22.0))],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            to_csv(&entries()),
+            "timestamp,sensor_id,note,on,temperature\r\n\
+             1000000000,s1,,true,20.5\r\n\
+             2000000000,s2,\"a, \"\"b\"\"\",,21\r\n\
+             3000000000,s1,,,22\r\n"
+        );
+    }
+
+    #[test]
+    fn test_annotated_csv() {
+        assert_eq!(
+            to_annotated_csv(&entries()),
+            "#datatype,string,long,dateTime:RFC3339Nano,string,string,boolean,string\r\n\
+             #group,false,false,false,true,false,false,false\r\n\
+             #default,_result,,,,,,\r\n\
+             ,result,table,_time,sensor_id,note,on,temperature\r\n\
+             ,,0,1970-01-01T00:00:01Z,s1,,true,20.5\r\n\
+             ,,0,1970-01-01T00:00:03Z,s1,,,22\r\n\
+             ,,1,1970-01-01T00:00:02Z,s2,\"a, \"\"b\"\"\",,21\r\n\
+             \r\n"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+#[cfg(feature = "arrow")]
+mod arrow;
+mod csv;
+
+use std::str::FromStr;
+
+use candid::{CandidType, Deserialize};
+
+use crate::timedb::{Entry, Value};
+
+pub use self::csv::{to_annotated_csv, to_csv};
+
+/// Formats query results can be exported in.
+///
+/// `Arrow` and `Parquet` are only available when the canister is built with the `arrow`
+/// and `parquet` features.
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Deserialize)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    AnnotatedCsv, // InfluxDB annotated CSV
+    Arrow,        // Arrow IPC stream
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            "annotated-csv" | "annotated_csv" => Ok(ExportFormat::AnnotatedCsv),
+            "arrow" | "arrow-ipc" => Ok(ExportFormat::Arrow),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => Err(format!(
+                "Unknown format '{}' (expected one of json, csv, annotated-csv, arrow, parquet)",
+                format
+            )),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json; charset=utf-8",
+            ExportFormat::Csv | ExportFormat::AnnotatedCsv => "text/csv; charset=utf-8",
+            ExportFormat::Arrow => "application/vnd.apache.arrow.stream",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    /// Whether this build of the canister can produce the format.
+    #[allow(clippy::match_like_matches_macro)] // the outcome depends on the build features
+    pub fn is_available(&self) -> bool {
+        match self {
+            ExportFormat::Arrow => cfg!(feature = "arrow"),
+            ExportFormat::Parquet => cfg!(feature = "parquet"),
+            _ => true,
+        }
+    }
+}
+
+/// Serializes entries in the given format. JSON is the list of entries as `run_query`
+/// returns them.
+pub fn export(entries: &[Entry], format: ExportFormat) -> Result<Vec<u8>, String> {
+    if !format.is_available() {
+        return Err(format!(
+            "Format {:?} is not available, the canister was built without it",
+            format
+        ));
+    }
+
+    match format {
+        ExportFormat::Json => serde_json::to_vec(entries).map_err(|err| err.to_string()),
+        ExportFormat::Csv => Ok(to_csv(entries).into_bytes()),
+        ExportFormat::AnnotatedCsv => Ok(to_annotated_csv(entries).into_bytes()),
+        #[cfg(feature = "arrow")]
+        ExportFormat::Arrow => arrow::to_arrow_ipc(entries).map_err(|err| err.to_string()),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => arrow::to_parquet(entries).map_err(|err| err.to_string()),
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("unavailable formats are rejected above"),
+    }
+}
+
+/// Columns of a table of entries: sorted tag names followed by sorted field names.
+pub struct Columns {
+    pub tags: Vec<String>,
+    pub fields: Vec<String>,
+}
+
+impl Columns {
+    pub fn of(entries: &[Entry]) -> Self {
+        let mut tags: Vec<String> = vec![];
+        let mut fields: Vec<String> = vec![];
+
+        for entry in entries {
+            for tag in entry.tags.keys() {
+                if let Err(i) = tags.binary_search(tag) {
+                    tags.insert(i, tag.clone());
+                }
+            }
+            for field in entry.fields.keys() {
+                if let Err(i) = fields.binary_search(field) {
+                    fields.insert(i, field.clone());
+                }
+            }
+        }
+
+        Self { tags, fields }
+    }
+}
+
+// Text of a value, missing values and `None` are empty
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(value)) => value.clone(),
+        Some(Value::Int(value)) => value.to_string(),
+        Some(Value::UInt(value)) => value.to_string(),
+        Some(Value::Float(value)) => value.to_string(),
+        Some(Value::Bool(value)) => value.to_string(),
+        Some(Value::None) | None => String::new(),
+    }
+}
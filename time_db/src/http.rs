@@ -1,96 +1,966 @@
-use std::{io::Write};
+use std::{cell::RefCell, collections::BTreeSet, str::FromStr};
 
-use candid::candid_method;
-use flate2::{write::GzEncoder, Compression};
-use ic_cdk_macros::query;
+use candid::{candid_method, CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+use ic_metrics_encoder::MetricsEncoder;
+use serde::Serialize;
 
 use crate::{
+    caller_query_limits, ensure_owner,
+    export::{export, ExportFormat},
+    http_encoding::ContentEncoding,
     http_types::{HttpRequest, HttpResponse, HttpResponseBuilder},
-    timedb::Entry,
+    influx, metrics, prometheus,
+    timedb::{Action, AggregateFunction, Entry, Expression, QueryError},
     TIME_DB,
 };
 
-fn gzip_string(s: &str) -> std::io::Result<Vec<u8>> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(s.as_bytes())?;
-    encoder.finish()
+const JSON: &str = "application/json; charset=utf-8";
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub struct HttpSettings {
+    pub compression_threshold: u64, // bodies up to this size are never compressed
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            compression_threshold: 100,
+        }
+    }
+}
+
+thread_local! {
+    static HTTP_SETTINGS: RefCell<HttpSettings> = RefCell::new(HttpSettings::default());
+    static WRITE_TOKENS: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+#[query]
+#[candid_method(query)]
+fn get_http_settings() -> HttpSettings {
+    HTTP_SETTINGS.with(|s| s.borrow().clone())
+}
+
+#[update]
+#[candid_method(update)]
+fn set_http_settings(settings: HttpSettings) -> Result<(), String> {
+    ensure_owner()?;
+
+    HTTP_SETTINGS.with(|s| *s.borrow_mut() = settings);
+    Ok(())
+}
+
+/// Replaces the tokens accepted by the HTTP write endpoints, no writes are accepted
+/// without any.
+#[update]
+#[candid_method(update)]
+pub(crate) fn set_write_tokens(tokens: Vec<String>) -> Result<(), String> {
+    ensure_owner()?;
+    if tokens.iter().any(|token| token.is_empty()) {
+        return Err("Write tokens can't be empty".to_string());
+    }
+
+    WRITE_TOKENS.with(|t| *t.borrow_mut() = tokens.into_iter().collect());
+    Ok(())
+}
+
+/// Whether the `Authorization` header holds one of the write tokens, as `Token <token>` the
+/// way InfluxDB clients send it or as `Bearer <token>` the way Prometheus does.
+pub fn is_write_authorized(req: &HttpRequest) -> bool {
+    let token = req.header("Authorization").and_then(|value| {
+        value
+            .strip_prefix("Token ")
+            .or_else(|| value.strip_prefix("Bearer "))
+    });
+
+    token.is_some_and(|token| WRITE_TOKENS.with(|t| t.borrow().contains(token.trim())))
 }
 
 #[query]
 #[candid_method(query)]
 fn http_request(req: HttpRequest) -> HttpResponse {
     if req.path() == "/health" {
-        // let mut writer =
-        //     ic_metrics_encoder::MetricsEncoder::new(vec![], ic_cdk::api::time() as i64 / 1_000_000);
-        // match encode_metrics(&mut writer) {
-        //     Ok(()) =>
-        let mut response = HttpResponseBuilder::ok();
-        response.header("Content-Type", "application/json; charset=utf-8");
-        //         .with_body_and_content_length(writer.into_inner())
+        match serde_json::to_vec(&metrics::health()) {
+            Ok(body) => respond(&req, 200, JSON, body),
+            Err(err) => HttpError::new(500, err).into_response(&req),
+        }
+    } else if req.path() == "/metrics" {
+        let mut writer = MetricsEncoder::new(vec![], (crate::now() / 1_000_000) as i64);
+        match metrics::encode_metrics(&mut writer) {
+            Ok(()) => respond(&req, 200, PROMETHEUS_TEXT, writer.into_inner()),
+            Err(err) => HttpError::new(500, format!("Failed to encode metrics: {}", err))
+                .into_response(&req),
+        }
+    } else if req.path() == "/api/v1/write" || req.path() == "/api/v2/write" {
+        // writes change the state, the gateway has to send them to `http_request_update`
+        let mut response = HttpResponseBuilder::with_status(200);
+        response.upgrade();
         response.build()
-        //     Err(err) => {
-        //         HttpResponseBuilder::server_error(format!("Failed to encode metrics: {}", err))
-        //             .build()
-        //     }
-        // }
+    } else if req.path() == "/api/v1/read" {
+        metrics::record_query("remote_read");
+        prometheus::remote_read(&req).unwrap_or_else(|err| err.into_response(&req))
+    } else if req.path() == "/api/v1/query" {
+        metrics::record_query("promql");
+        prometheus::instant_query(&req)
+    } else if req.path() == "/api/v1/query_range" {
+        metrics::record_query("promql");
+        prometheus::range_query(&req)
+    } else if req.path() == "/api/v2/query" {
+        metrics::record_query("flux");
+        influx::query(&req).unwrap_or_else(|err| err.into_response(&req))
     } else if req.path() == "/query" {
-        let measurement = req.raw_query_param("measurement");
-
-        let mut body = String::new();
-
-        match measurement {
-            Some(measurement) => {
-                body = format!("{{\"measurement\":\"{}\"}}", measurement);
-
-                let items = TIME_DB.with(|m| {
-                    let mut db = m.borrow_mut();
-                    let measure = db.get_measurement(&measurement);
-
-                    let my_data: Vec<Entry> = measure
-                        .list_entries()
-                        .into_iter()
-                        .map(|x| (*x).clone())
-                        .collect();
-
-                    // let my_data: Vec<Entry> = vec![];
-
-                    serde_json::to_string(&my_data)
-                });
-
-                match items {
-                    Ok(items) => {
-                        body = format!(
-                            "{{\"measurement\":\"{}\", \"data\": {}}}",
-                            measurement, items
-                        );
-                    }
-                    _ => body = "Error while processing data".to_string(),
+        metrics::record_query("http");
+        match query(&req) {
+            Ok(output) => {
+                let mut response = respond(&req, 200, output.content_type, output.body);
+                if let Some(cursor) = output.cursor {
+                    // CSV and Arrow have no room for the cursor of a truncated result
+                    response
+                        .headers
+                        .push(("X-Query-Cursor".to_string(), cursor.to_string()));
                 }
+                response
             }
-            _ => {}
+            Err(err) => err.into_response(&req),
+        }
+    } else {
+        respond(
+            &req,
+            404,
+            "text/plain; charset=utf-8",
+            b"not found".to_vec(),
+        )
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
+    if req.path() == "/api/v1/write" {
+        match prometheus::remote_write(&req) {
+            Ok(()) => respond(&req, 204, "text/plain; charset=utf-8", vec![]),
+            Err(err) => err.into_response(&req),
+        }
+    } else if req.path() == "/api/v2/write" {
+        match influx::write(&req) {
+            Ok(()) => respond(&req, 204, "text/plain; charset=utf-8", vec![]),
+            Err(err) => err.into_response(&req),
         }
+    } else {
+        respond(
+            &req,
+            404,
+            "text/plain; charset=utf-8",
+            b"not found".to_vec(),
+        )
+    }
+}
 
-        let mut response = HttpResponseBuilder::ok();
-        response.header("Content-Type", "application/json; charset=utf-8");
+#[derive(Serialize)]
+struct QueryReply<'a> {
+    measurement: &'a str,
+    data: Vec<Entry>,
+    truncated: bool,
+    cursor: Option<u64>, // where to resume a truncated query
+}
 
-        if body.len() > 100 {
-            let gziped = gzip_string(&body);
+struct QueryOutput {
+    content_type: &'static str,
+    body: Vec<u8>,
+    cursor: Option<u64>,
+}
 
-            match gziped {
-                Ok(data) => {
-                    response.header("Content-Encoding", "gzip");
-                    response.with_body_and_content_length(data);
-                }
-                _ => {
-                    response
-                        .with_body_and_content_length("Error while compressing data".as_bytes());
-                }
+#[derive(Serialize)]
+struct ErrorReply {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<u64>,
+}
+
+/// Failure of a request, answered as `{"error": message}` with the status code.
+pub struct HttpError {
+    status: u16,
+    message: String,
+    cursor: Option<u64>,
+}
+
+impl HttpError {
+    pub fn new(status: u16, message: impl ToString) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+            cursor: None,
+        }
+    }
+
+    fn into_response(self, req: &HttpRequest) -> HttpResponse {
+        let reply = ErrorReply {
+            error: self.message,
+            cursor: self.cursor,
+        };
+
+        match serde_json::to_string(&reply) {
+            Ok(body) => respond(req, self.status, JSON, body.into_bytes()),
+            Err(err) => HttpResponseBuilder::server_error(err).build(),
+        }
+    }
+}
+
+impl From<QueryError> for HttpError {
+    fn from(err: QueryError) -> Self {
+        match err {
+            QueryError::LimitExceeded { cursor, .. } => HttpError {
+                status: 422,
+                message: err.to_string(),
+                cursor: Some(cursor),
+            },
+            QueryError::Invalid(message) => HttpError::new(400, message),
+        }
+    }
+}
+
+/// Runs a query described by the URL parameters and/or a JSON list of actions in the body of a POST.
+///
+/// Parameters are `measurement` (required), `start` and `stop` (timestamps), `enrich` (tag
+/// joining the entries to the device registry, applied before the filters), `filter` (see
+/// `Expression::from_str`), `window` and `fn` (aggregate window and function, `mean` by
+/// default), `fields` and `tags` (comma separated names to keep), `limit` (maximum number
+/// of entries), `cursor` (to continue a truncated query), `format` (`json`, `csv`,
+/// `annotated-csv`, `arrow` or `parquet`) and `pretty` (indented JSON). `filter`, `fields` and `tags` can be repeated.
+fn query(req: &HttpRequest) -> Result<QueryOutput, HttpError> {
+    if req.method != "GET" && req.method != "POST" {
+        return Err(HttpError::new(
+            405,
+            format!("Method {} is not allowed, use GET or POST", req.method),
+        ));
+    }
+
+    let measurement = req
+        .query_param("measurement")
+        .ok_or_else(|| HttpError::new(400, "Parameter 'measurement' is required"))?;
+
+    let mut actions = query_actions(req)?;
+    if req.method == "POST" && !req.body.is_empty() && !req.is_form() {
+        let body: Vec<Action> = serde_json::from_slice(&req.body).map_err(|err| {
+            HttpError::new(400, format!("Invalid list of actions in body: {}", err))
+        })?;
+        actions.extend(body);
+    }
+    if actions.is_empty() {
+        actions.push(Action::Range(0, None));
+    }
+
+    let limit = req.query_param_u64("limit").map_err(bad_request)?;
+    let cursor = req.query_param_u64("cursor").map_err(bad_request)?;
+    let pretty = req.query_param_bool("pretty").map_err(bad_request)?;
+    let format = req
+        .query_param("format")
+        .map(|format| ExportFormat::from_str(&format))
+        .transpose()
+        .map_err(bad_request)?
+        .unwrap_or(ExportFormat::Json);
+    if !format.is_available() {
+        return Err(HttpError::new(
+            501,
+            format!("Format {:?} is not available in this canister", format),
+        ));
+    }
+
+    let mut result = TIME_DB.with(|m| {
+        let db = m.borrow();
+        let measure = db.find_measurement(&measurement).ok_or_else(|| {
+            HttpError::new(404, format!("Measurement '{}' not found", measurement))
+        })?;
+
+        Ok::<_, HttpError>(measure.apply(&actions, &caller_query_limits(), cursor)?)
+    })?;
+
+    if let Some(limit) = limit {
+        result.truncate(limit.try_into().unwrap_or(usize::MAX));
+    }
+
+    let cursor = result.cursor;
+    let body = match format {
+        ExportFormat::Json => {
+            let reply = QueryReply {
+                measurement: &measurement,
+                data: result.entries,
+                truncated: result.truncated,
+                cursor: result.cursor,
+            };
+
+            match pretty {
+                Some(true) => serde_json::to_vec_pretty(&reply),
+                _ => serde_json::to_vec(&reply),
             }
+            .map_err(|err| HttpError::new(500, err))?
         }
+        format => export(&result.entries, format).map_err(|err| HttpError::new(500, err))?,
+    };
 
-        // let dashboard: Vec<u8> = build_dashboard();
-        response.build()
+    Ok(QueryOutput {
+        content_type: format.content_type(),
+        body,
+        cursor,
+    })
+}
+
+// Actions described by the URL parameters
+fn query_actions(req: &HttpRequest) -> Result<Vec<Action>, HttpError> {
+    let mut actions = vec![];
+
+    let start = req.query_param_u64("start").map_err(bad_request)?;
+    let stop = req.query_param_u64("stop").map_err(bad_request)?;
+    if start.is_some() || stop.is_some() {
+        actions.push(Action::Range(start.unwrap_or(0), stop));
+    }
+
+    // before the filters, so they can test the attributes of the devices
+    if let Some(tag) = req.query_param("enrich") {
+        actions.push(Action::Enrich(tag));
+    }
+
+    for filter in req.query_param_all("filter") {
+        let expression = Expression::from_str(&filter)
+            .map_err(|err| HttpError::new(400, format!("Invalid filter: {}", err)))?;
+        actions.push(Action::Filter(expression));
+    }
+
+    let fields = names(&req.query_param_all("fields"));
+    if !fields.is_empty() {
+        actions.push(Action::Filter(Expression::FieldFilter(fields)));
+    }
+
+    let tags = names(&req.query_param_all("tags"));
+    if !tags.is_empty() {
+        actions.push(Action::Filter(Expression::TagFilter(tags)));
+    }
+
+    let window = req.query_param_duration("window").map_err(bad_request)?;
+    let function = req
+        .query_param("fn")
+        .map(|function| AggregateFunction::from_str(&function))
+        .transpose()
+        .map_err(bad_request)?;
+    match (window, function) {
+        (Some(window), function) => actions.push(Action::AggregateWindow(
+            window.to_string(),
+            function.unwrap_or(AggregateFunction::Mean),
+        )),
+        (None, Some(_)) => {
+            return Err(HttpError::new(
+                400,
+                "Parameter 'fn' requires an aggregate 'window'",
+            ))
+        }
+        (None, None) => {}
+    }
+
+    Ok(actions)
+}
+
+pub fn bad_request(message: String) -> HttpError {
+    HttpError::new(400, message)
+}
+
+// Names given as comma separated lists, possibly in several parameters
+fn names(lists: &[String]) -> Vec<String> {
+    lists
+        .iter()
+        .flat_map(|list| list.split(','))
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Builds a response, compressing the body with the best coding the client accepts when
+/// it is larger than the compression threshold.
+pub fn respond(req: &HttpRequest, status: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    let threshold = HTTP_SETTINGS.with(|s| s.borrow().compression_threshold);
+
+    let encoding = if body.len() as u64 > threshold {
+        ContentEncoding::negotiate(req.header("Accept-Encoding"))
     } else {
-        HttpResponseBuilder::not_found().build()
+        Some(ContentEncoding::Identity)
+    };
+
+    let (status, content_type, encoding, body) = match encoding {
+        Some(encoding) => match encoding.encode(&body) {
+            Ok(encoded) => (status, content_type, encoding, encoded),
+            Err(err) => (
+                500,
+                "text/plain; charset=utf-8",
+                ContentEncoding::Identity,
+                format!("Error while compressing data: {}", err).into_bytes(),
+            ),
+        },
+        None => (
+            406,
+            "text/plain; charset=utf-8",
+            ContentEncoding::Identity,
+            b"none of the accepted content codings is supported".to_vec(),
+        ),
+    };
+
+    let mut response = HttpResponseBuilder::with_status(status);
+    response.header("Content-Type", content_type);
+    if encoding != ContentEncoding::Identity {
+        response.header("Content-Encoding", encoding.name());
+    }
+    response.header("Vary", "Accept-Encoding");
+    response.with_body_and_content_length(body);
+
+    response.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use flate2::read::GzDecoder;
+    use serde_bytes::ByteBuf;
+    use serde_json::Value as Json;
+
+    use crate::timedb::{Device, TimeDb, Value, DEVICES};
+
+    use super::*;
+
+    fn request(method: &str, url: &str, body: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: ByteBuf::from(body.as_bytes()),
+        }
+    }
+
+    // writes need one of the write tokens
+    fn authorize(req: &mut HttpRequest) {
+        set_write_tokens(vec!["secret".to_string()]).unwrap();
+        req.headers
+            .push(("Authorization".to_string(), "Token secret".to_string()));
+    }
+
+    fn body(response: &HttpResponse) -> Json {
+        let gzipped = response
+            .headers
+            .iter()
+            .any(|(name, value)| name == "Content-Encoding" && value == "gzip");
+
+        let mut body = String::new();
+        if gzipped {
+            std::io::Read::read_to_string(&mut GzDecoder::new(&response.body[..]), &mut body)
+                .unwrap();
+        } else {
+            body = String::from_utf8(response.body.to_vec()).unwrap();
+        }
+        serde_json::from_str(&body).unwrap()
+    }
+
+    fn populate() {
+        TIME_DB.with(|m| {
+            *m.borrow_mut() = TimeDb::new();
+            let mut db = m.borrow_mut();
+            let measurement = db.get_measurement("climate");
+            for (timestamp, sensor) in [(10, "s1"), (20, "s2"), (30, "s1"), (40, "s1")] {
+                measurement.add_entry(
+                    timestamp,
+                    &HashMap::from([("temperature".to_string(), Value::Int(timestamp as i128))]),
+                    &HashMap::from([("sensor_id".to_string(), Value::String(sensor.to_string()))]),
+                );
+            }
+        });
+    }
+
+    #[test]
+    fn test_query_parameters() {
+        populate();
+
+        let response = http_request(request(
+            "GET",
+            "/query?measurement=climate&start=15&filter=sensor_id+%3D%3D+%22s1%22&fields=temperature&tags=sensor_id&tags=location&limit=1",
+            "",
+        ));
+        assert_eq!(response.status_code, 200);
+        let reply = body(&response);
+        assert_eq!(reply["data"].as_array().unwrap().len(), 1);
+        assert_eq!(reply["data"][0]["timestamp"], 30);
+        assert_eq!(reply["truncated"], true);
+        assert_eq!(reply["cursor"], 40);
+        assert_eq!(
+            reply["data"][0]["tags"]["sensor_id"],
+            serde_json::json!({"String": "s1"})
+        );
+
+        // the name is returned as decoded, and escaped as JSON
+        let response = http_request(request("GET", "/query?measurement=room%20%22a%22", ""));
+        assert_eq!(response.status_code, 404);
+        assert_eq!(
+            body(&response)["error"],
+            "Measurement 'room \"a\"' not found"
+        );
+
+        let response = http_request(request(
+            "POST",
+            "/query?measurement=climate",
+            r#"[{"Range": [0, 25]}, {"AggregateWindow": ["100ns", "Max"]}]"#,
+        ));
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            body(&response)["data"][0]["fields"]["temperature"],
+            serde_json::json!({"Float": 20.0})
+        );
+
+        let device = Device {
+            name: "Hall".to_string(),
+            room: Some("hall".to_string()),
+            ..Default::default()
+        };
+        DEVICES.with(|d| d.borrow_mut().set("s2".to_string(), device).unwrap());
+        let response = http_request(request(
+            "GET",
+            "/query?measurement=climate&enrich=sensor_id&filter=room+%3D%3D+%22hall%22",
+            "",
+        ));
+        let reply = body(&response);
+        assert_eq!(reply["data"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            reply["data"][0]["tags"]["device"],
+            serde_json::json!({"String": "Hall"})
+        );
+    }
+
+    #[test]
+    fn test_query_limit_on_shared_timestamps() {
+        TIME_DB.with(|m| {
+            let mut db = m.borrow_mut();
+            let measurement = db.get_measurement("shared");
+            for (timestamp, sensor) in [(10, "s1"), (10, "s2"), (10, "s3"), (20, "s1")] {
+                measurement.add_entry(
+                    timestamp,
+                    &HashMap::from([("temperature".to_string(), Value::Int(20))]),
+                    &HashMap::from([("sensor_id".to_string(), Value::String(sensor.to_string()))]),
+                );
+            }
+        });
+
+        // a page can't end within the entries of a timestamp, it holds all of them
+        let response = http_request(request("GET", "/query?measurement=shared&limit=1", ""));
+        let reply = body(&response);
+        assert_eq!(reply["data"].as_array().unwrap().len(), 3);
+        assert_eq!(reply["truncated"], true);
+        assert_eq!(reply["cursor"], 20);
+
+        let response = http_request(request(
+            "GET",
+            "/query?measurement=shared&limit=1&cursor=20",
+            "",
+        ));
+        let reply = body(&response);
+        assert_eq!(reply["data"].as_array().unwrap().len(), 1);
+        assert_eq!(reply["truncated"], false);
+    }
+
+    #[test]
+    fn test_content_negotiation() {
+        populate();
+
+        let header = |response: &HttpResponse, name: &str| {
+            response
+                .headers
+                .iter()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        let mut req = request("GET", "/query?measurement=climate", "");
+        let plain = http_request(req.clone());
+        assert_eq!(header(&plain, "Content-Encoding"), None);
+
+        req.headers = vec![(
+            "accept-encoding".to_string(),
+            "br;q=0.9, deflate;q=0.5, gzip".to_string(),
+        )];
+        let gzipped = http_request(req.clone());
+        assert_eq!(
+            header(&gzipped, "Content-Encoding"),
+            Some("gzip".to_string())
+        );
+        assert_eq!(body(&gzipped), body(&plain));
+
+        req.headers = vec![("Accept-Encoding".to_string(), "identity;q=0".to_string())];
+        assert_eq!(http_request(req).status_code, 406);
+
+        for response in [plain, gzipped, http_request(request("GET", "/missing", ""))] {
+            assert_eq!(
+                header(&response, "Vary"),
+                Some("Accept-Encoding".to_string())
+            );
+            assert_eq!(
+                header(&response, "Content-Length"),
+                Some(response.body.len().to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_csv_format() {
+        populate();
+
+        let response = http_request(request(
+            "GET",
+            "/query?measurement=climate&stop=25&format=csv",
+            "",
+        ));
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .contains(&("Content-Type".to_string(), "text/csv; charset=utf-8".to_string())));
+        assert_eq!(
+            String::from_utf8(response.body.to_vec()).unwrap(),
+            "timestamp,sensor_id,temperature\r\n10,s1,10\r\n20,s2,20\r\n"
+        );
+
+        let response = http_request(request(
+            "GET",
+            "/query?measurement=climate&format=annotated-csv&limit=1",
+            "",
+        ));
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .contains(&("X-Query-Cursor".to_string(), "20".to_string())));
+
+        let response = http_request(request("GET", "/query?measurement=climate&format=xml", ""));
+        assert_eq!(response.status_code, 400);
+
+        let response = http_request(request("GET", "/query?measurement=climate&format=arrow", ""));
+        let status = if cfg!(feature = "arrow") { 200 } else { 501 };
+        assert_eq!(response.status_code, status);
+    }
+
+    #[test]
+    fn test_metrics_and_health() {
+        populate();
+        metrics::record_insert("insert", "climate", 1_500_000_000);
+        metrics::record_rejected_writes("insert_bulk", 3);
+
+        let response = http_request(request("GET", "/metrics", ""));
+        assert_eq!(response.status_code, 200);
+        let text = String::from_utf8(response.body.to_vec()).unwrap();
+        for line in [
+            "timedb_points{measurement=\"climate\"} 4 ",
+            "timedb_series{measurement=\"climate\"} 2 ",
+            "timedb_last_write_timestamp_seconds{measurement=\"climate\"} 1.5 ",
+            "timedb_insert_calls_total{method=\"insert\"} 1 ",
+            "timedb_rejected_writes_total{method=\"insert_bulk\"} 3 ",
+        ] {
+            assert!(text.contains(line), "{} missing in {}", line, text);
+        }
+
+        let health = body(&http_request(request("GET", "/health", "")));
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["measurements"], 1);
+        assert_eq!(health["points"], 4);
+    }
+
+    #[test]
+    fn test_prometheus_remote_write_and_read() {
+        use crate::prometheus::remote::{
+            self, Label, LabelMatcher, Query, ReadRequest, ReadResponse, Sample, TimeSeries,
+            WriteRequest,
+        };
+
+        populate();
+        let label = |name: &str, value: &str| Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let protobuf_request = |url: &str, body: Vec<u8>| HttpRequest {
+            method: "POST".to_string(),
+            url: url.to_string(),
+            headers: vec![("Content-Encoding".to_string(), "snappy".to_string())],
+            body: ByteBuf::from(body),
+        };
+
+        let write = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![label("__name__", "cpu"), label("host", "edge-1")],
+                samples: vec![Sample {
+                    value: 0.5,
+                    timestamp: 1_000,
+                }],
+            }],
+        };
+        let mut req = protobuf_request("/api/v1/write", remote::encode(&write).unwrap());
+        assert_eq!(http_request(req.clone()).upgrade, Some(true));
+        assert_eq!(http_request_update(req.clone()).status_code, 401);
+        req.headers
+            .push(("Authorization".to_string(), "Bearer wrong".to_string()));
+        assert_eq!(http_request_update(req.clone()).status_code, 401);
+        set_write_tokens(vec!["secret".to_string()]).unwrap();
+        req.headers[1].1 = "Bearer secret".to_string();
+        assert_eq!(http_request_update(req).status_code, 204);
+        assert!(set_write_tokens(vec![String::new()]).is_err());
+
+        let read = ReadRequest {
+            queries: vec![Query {
+                start_timestamp_ms: 0,
+                end_timestamp_ms: 1_000,
+                matchers: vec![LabelMatcher {
+                    r#type: 0,
+                    name: "__name__".to_string(),
+                    value: "cpu".to_string(),
+                }],
+            }],
+            accepted_response_types: vec![],
+        };
+        let response = http_request(protobuf_request(
+            "/api/v1/read",
+            remote::encode(&read).unwrap(),
+        ));
+        assert_eq!(response.status_code, 200);
+        let reply: ReadResponse = remote::decode(&response.body).unwrap();
+        assert_eq!(
+            reply.results[0].timeseries,
+            vec![TimeSeries {
+                labels: vec![label("__name__", "cpu"), label("host", "edge-1")],
+                samples: vec![Sample {
+                    value: 0.5,
+                    timestamp: 1_000,
+                }],
+            }]
+        );
+
+        let mut garbage = protobuf_request("/api/v1/write", b"not snappy".to_vec());
+        authorize(&mut garbage);
+        assert_eq!(http_request_update(garbage).status_code, 400);
+    }
+
+    #[test]
+    fn test_promql_queries() {
+        use crate::prometheus::remote::{self, Label, Sample, TimeSeries, WriteRequest};
+
+        let write = WriteRequest {
+            timeseries: ["a", "b"]
+                .iter()
+                .map(|job| TimeSeries {
+                    labels: vec![
+                        Label {
+                            name: "__name__".to_string(),
+                            value: "requests".to_string(),
+                        },
+                        Label {
+                            name: "job".to_string(),
+                            value: job.to_string(),
+                        },
+                    ],
+                    samples: (0..=12)
+                        .map(|i| Sample {
+                            value: i as f64 * 10.0,
+                            timestamp: i * 10_000,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+        let mut req = request("POST", "/api/v1/write", "");
+        authorize(&mut req);
+        req.body = ByteBuf::from(remote::encode(&write).unwrap());
+        assert_eq!(http_request_update(req).status_code, 204);
+
+        let response = http_request(request(
+            "GET",
+            "/api/v1/query_range?query=sum%20by%20(job)%20(rate(requests%5B1m%5D))&start=60&end=120&step=30s",
+            "",
+        ));
+        assert_eq!(response.status_code, 200);
+        let reply = body(&response);
+        assert_eq!(reply["status"], "success");
+        assert_eq!(reply["data"]["resultType"], "matrix");
+        assert_eq!(
+            reply["data"]["result"][0],
+            serde_json::json!({
+                "metric": {"job": "a"},
+                "values": [[60.0, "1"], [90.0, "1"], [120.0, "1"]],
+            })
+        );
+
+        // windows are aggregated per series by the measurement
+        let response = http_request(request(
+            "GET",
+            "/api/v1/query_range?query=avg_over_time(requests%5B30s%5D)&start=60&end=90&step=30s",
+            "",
+        ));
+        let result = &body(&response)["data"]["result"];
+        assert_eq!(result.as_array().unwrap().len(), 2);
+        assert_eq!(
+            result[1],
+            serde_json::json!({
+                "metric": {"job": "b"},
+                "values": [[60.0, "50"], [90.0, "80"]],
+            })
+        );
+
+        // Grafana posts the parameters as a form
+        let mut req = request(
+            "POST",
+            "/api/v1/query",
+            "query=max_over_time(requests{job%3D\"b\"}[1m])&time=1970-01-01T00:01:30Z",
+        );
+        req.headers.push((
+            "Content-Type".to_string(),
+            "application/x-www-form-urlencoded".to_string(),
+        ));
+        let reply = body(&http_request(req));
+        assert_eq!(
+            reply["data"],
+            serde_json::json!({
+                "resultType": "vector",
+                "result": [{"metric": {"job": "b"}, "value": [90.0, "90"]}],
+            })
+        );
+
+        let response = http_request(request("GET", "/api/v1/query?query=up%20%2B%201", ""));
+        assert_eq!(response.status_code, 400);
+        let reply = body(&response);
+        assert_eq!(reply["status"], "error");
+        assert_eq!(reply["errorType"], "bad_data");
+    }
+
+    #[test]
+    fn test_promql_limits_span_all_steps() {
+        use crate::prometheus::remote::{self, Label, Sample, TimeSeries, WriteRequest};
+        use crate::timedb::QueryLimits;
+        use candid::Principal;
+
+        let write = WriteRequest {
+            timeseries: vec![TimeSeries {
+                labels: vec![Label {
+                    name: "__name__".to_string(),
+                    value: "steps".to_string(),
+                }],
+                samples: (0..=12)
+                    .map(|i| Sample {
+                        value: i as f64,
+                        timestamp: i * 10_000,
+                    })
+                    .collect(),
+            }],
+        };
+        let mut req = request("POST", "/api/v1/write", "");
+        authorize(&mut req);
+        req.body = ByteBuf::from(remote::encode(&write).unwrap());
+        assert_eq!(http_request_update(req).status_code, 204);
+
+        // every step reads 3 points, the 13 steps together more than the limit
+        let limits = QueryLimits {
+            max_scanned_points: 10,
+            ..QueryLimits::default()
+        };
+        crate::set_query_limits(Some(Principal::anonymous()), Some(limits)).unwrap();
+        let query = |end: u32| {
+            http_request(request(
+                "GET",
+                &format!(
+                    "/api/v1/query_range?query=avg_over_time(steps%5B30s%5D)&start=0&end={}&step=10s",
+                    end
+                ),
+                "",
+            ))
+        };
+
+        assert_eq!(query(20).status_code, 200);
+        let response = query(120);
+        assert_eq!(response.status_code, 422);
+        assert_eq!(body(&response)["errorType"], "execution");
+
+        crate::set_query_limits(Some(Principal::anonymous()), None).unwrap();
+    }
+
+    #[test]
+    fn test_influx_write_and_query() {
+        let lines = "cpu,host=edge-1 usage=0.5,cores=4i 1000000000\n\
+                     cpu,host=edge-2 usage=0.25,cores=2i 2000000000\n";
+        let mut req = request(
+            "POST",
+            "/api/v2/write?org=acme&bucket=edge&precision=ns",
+            "",
+        );
+        req.headers
+            .push(("Content-Encoding".to_string(), "gzip".to_string()));
+        req.body = ByteBuf::from(ContentEncoding::Gzip.encode(lines.as_bytes()).unwrap());
+        assert_eq!(http_request(req.clone()).upgrade, Some(true));
+        let response = http_request_update(req.clone());
+        assert_eq!(response.status_code, 401);
+        assert_eq!(body(&response)["code"], "unauthorized");
+        authorize(&mut req);
+        assert_eq!(http_request_update(req).status_code, 204);
+
+        let flux = r#"from(bucket: "edge")
+            |> range(start: 1970-01-01T00:00:00Z, stop: 1970-01-01T00:00:03Z)
+            |> filter(fn: (r) => r._measurement == "cpu" and r.host == "edge-2")
+            |> filter(fn: (r) => r._field == "usage")"#;
+        let mut req = request(
+            "POST",
+            "/api/v2/query?org=acme",
+            &serde_json::json!({"query": flux, "type": "flux"}).to_string(),
+        );
+        req.headers
+            .push(("Content-Type".to_string(), "application/json".to_string()));
+        let response = http_request(req);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            String::from_utf8(response.body.to_vec()).unwrap(),
+            "#datatype,string,long,dateTime:RFC3339Nano,string,double\r\n\
+             #group,false,false,false,true,false\r\n\
+             #default,_result,,,,\r\n\
+             ,result,table,_time,host,usage\r\n\
+             ,,0,1970-01-01T00:00:02Z,edge-2,0.25\r\n\
+             \r\n"
+        );
+
+        // another org doesn't see the bucket
+        let req = request("POST", "/api/v2/query?org=other", flux);
+        let response = http_request(req);
+        assert_eq!(response.status_code, 200);
+        assert!(!String::from_utf8(response.body.to_vec())
+            .unwrap()
+            .contains("edge-2"));
+
+        let mut invalid = request("POST", "/api/v2/write?org=acme&bucket=edge", "cpu usage=x");
+        authorize(&mut invalid);
+        let response = http_request_update(invalid);
+        assert_eq!(response.status_code, 400);
+        assert_eq!(body(&response)["code"], "invalid");
+
+        let req = request("POST", "/api/v2/query", flux);
+        assert_eq!(http_request(req).status_code, 400);
+    }
+
+    #[test]
+    fn test_query_errors() {
+        populate();
+
+        let cases = [
+            ("GET", "/query", 400),
+            ("GET", "/query?measurement=missing", 404),
+            ("GET", "/query?measurement=climate&start=abc", 400),
+            (
+                "GET",
+                "/query?measurement=climate&filter=temperature%3E",
+                400,
+            ),
+            ("GET", "/query?measurement=climate&fn=max", 400),
+            ("DELETE", "/query?measurement=climate", 405),
+        ];
+
+        for (method, url, status) in cases {
+            let response = http_request(request(method, url, ""));
+            assert_eq!(response.status_code, status, "{} {}", method, url);
+            assert!(body(&response)["error"].is_string());
+        }
     }
 }
@@ -0,0 +1,224 @@
+use std::io::{self, Read, Write};
+
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+/// Content codings the canister can apply to HTTP response bodies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// Supported codings, the first one wins when the client likes several equally.
+    #[cfg(feature = "brotli")]
+    const SUPPORTED: &'static [ContentEncoding] = &[
+        ContentEncoding::Brotli,
+        ContentEncoding::Gzip,
+        ContentEncoding::Deflate,
+        ContentEncoding::Identity,
+    ];
+
+    #[cfg(not(feature = "brotli"))]
+    const SUPPORTED: &'static [ContentEncoding] = &[
+        ContentEncoding::Gzip,
+        ContentEncoding::Deflate,
+        ContentEncoding::Identity,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+            #[cfg(feature = "brotli")]
+            ContentEncoding::Brotli => "br",
+        }
+    }
+
+    /// Coding of a `Content-Encoding` header, `None` for codings the canister can't decode.
+    pub fn from_name(name: &str) -> Option<ContentEncoding> {
+        let name = name.trim().to_lowercase();
+        ContentEncoding::SUPPORTED
+            .iter()
+            .find(|encoding| {
+                encoding.name() == name || (name == "x-gzip" && encoding.name() == "gzip")
+            })
+            .copied()
+    }
+
+    /// Picks the coding with the highest weight in an `Accept-Encoding` header.
+    ///
+    /// Without the header only `identity` is used. `identity` is acceptable unless it is
+    /// excluded with `q=0` (directly or through `*`), when nothing acceptable is left `None`
+    /// is returned.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Option<ContentEncoding> {
+        let accept_encoding = match accept_encoding {
+            Some(accept_encoding) => accept_encoding,
+            None => return Some(ContentEncoding::Identity),
+        };
+
+        let mut weights: Vec<(String, f32)> = vec![];
+        for coding in accept_encoding.split(',') {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or_default().trim().to_lowercase();
+            if name.is_empty() {
+                continue;
+            }
+
+            let weight = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            weights.push((name, weight));
+        }
+
+        let weight_of = |encoding: &ContentEncoding| -> Option<f32> {
+            let name = encoding.name();
+            weights
+                .iter()
+                .find(|(coding, _)| coding == name || (name == "gzip" && coding == "x-gzip"))
+                .or_else(|| weights.iter().find(|(coding, _)| coding == "*"))
+                .map(|(_, weight)| *weight)
+                .or(match encoding {
+                    ContentEncoding::Identity => Some(0.001), // acceptable unless refused
+                    _ => None,
+                })
+        };
+
+        let mut best: Option<(ContentEncoding, f32)> = None;
+        for encoding in ContentEncoding::SUPPORTED {
+            match weight_of(encoding) {
+                Some(weight) if weight > 0.0 => match best {
+                    Some((_, best_weight)) if best_weight >= weight => {}
+                    _ => best = Some((*encoding, weight)),
+                },
+                _ => {}
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+
+    pub fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Identity => Ok(body.to_vec()),
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            ContentEncoding::Deflate => {
+                // `deflate` is the zlib format, not raw deflate
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "brotli")]
+            ContentEncoding::Brotli => {
+                let mut encoded = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                    encoder.write_all(body)?;
+                }
+                Ok(encoded)
+            }
+        }
+    }
+
+    /// Decodes a request body, failing when it decodes to more than `max_len` bytes.
+    pub fn decode(&self, body: &[u8], max_len: u64) -> io::Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            ContentEncoding::Identity => Box::new(body),
+            ContentEncoding::Gzip => Box::new(GzDecoder::new(body)),
+            ContentEncoding::Deflate => Box::new(ZlibDecoder::new(body)),
+            #[cfg(feature = "brotli")]
+            ContentEncoding::Brotli => Box::new(brotli::Decompressor::new(body, 4096)),
+        };
+
+        let mut decoded = Vec::new();
+        reader.take(max_len + 1).read_to_end(&mut decoded)?;
+        if decoded.len() as u64 > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("body is larger than {} bytes once decoded", max_len),
+            ));
+        }
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let negotiate = |header| ContentEncoding::negotiate(header);
+
+        assert_eq!(negotiate(None), Some(ContentEncoding::Identity));
+        assert_eq!(negotiate(Some("")), Some(ContentEncoding::Identity));
+        assert_eq!(negotiate(Some("gzip")), Some(ContentEncoding::Gzip));
+        assert_eq!(
+            negotiate(Some("gzip;q=0.5, deflate")),
+            Some(ContentEncoding::Deflate)
+        );
+        assert_eq!(
+            negotiate(Some("GZIP, deflate;q=1.0")),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(negotiate(Some("compress")), Some(ContentEncoding::Identity));
+        assert_eq!(negotiate(Some("gzip;q=0")), Some(ContentEncoding::Identity));
+        assert_eq!(negotiate(Some("identity;q=0")), None);
+        assert_eq!(negotiate(Some("*;q=0")), None);
+        assert_eq!(
+            negotiate(Some("*;q=0, deflate")),
+            Some(ContentEncoding::Deflate)
+        );
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let body = "temperature ".repeat(100);
+
+        for encoding in ContentEncoding::SUPPORTED {
+            let encoded = encoding.encode(body.as_bytes()).unwrap();
+            assert_eq!(encoding.decode(&encoded, 1200).unwrap(), body.as_bytes());
+            assert!(encoding.decode(&encoded, 1199).is_err());
+        }
+        assert_eq!(
+            ContentEncoding::from_name("X-Gzip"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(ContentEncoding::from_name("compress"), None);
+
+        let mut decoded = String::new();
+        GzDecoder::new(&ContentEncoding::Gzip.encode(body.as_bytes()).unwrap()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        let mut decoded = String::new();
+        ZlibDecoder::new(&ContentEncoding::Deflate.encode(body.as_bytes()).unwrap()[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        #[cfg(feature = "brotli")]
+        {
+            let encoded = ContentEncoding::Brotli.encode(body.as_bytes()).unwrap();
+            let mut decoded = String::new();
+            brotli::Decompressor::new(&encoded[..], 4096)
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, body);
+        }
+    }
+}
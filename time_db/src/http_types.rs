@@ -1,8 +1,12 @@
 //Taken from https://github.com/dfinity/ic/blob/1c7f774ae0c096d98f873fa66f1d200b85a64ecf/rs/rust_canisters/http_types/src/lib.rs
 
+use std::str::FromStr;
+
 use candid::{CandidType, Deserialize};
 use serde_bytes::ByteBuf;
 
+use crate::timedb::Duration;
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct HttpRequest {
     pub method: String,
@@ -19,6 +23,90 @@ impl HttpRequest {
         }
     }
 
+    /// Value of a request header, header names are case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All parameters of the query string in order, followed by those of a form encoded
+    /// body (`Content-Type: application/x-www-form-urlencoded`). Names and values are
+    /// percent-decoded, with `+` read as a space.
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        let mut params = match self.url.split_once('?') {
+            Some((_, query_string)) => parse_params(query_string),
+            None => vec![],
+        };
+
+        if self.is_form() {
+            params.extend(parse_params(&String::from_utf8_lossy(&self.body)));
+        }
+
+        params
+    }
+
+    /// Whether the body holds form encoded parameters.
+    pub fn is_form(&self) -> bool {
+        self.header("Content-Type").is_some_and(|content_type| {
+            content_type
+                .trim_start()
+                .to_lowercase()
+                .starts_with("application/x-www-form-urlencoded")
+        })
+    }
+
+    /// Decoded value of the first appearance of a parameter.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_params()
+            .into_iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Decoded values of all appearances of a parameter, e.g. `tag=a&tag=b`.
+    pub fn query_param_all(&self, name: &str) -> Vec<String> {
+        self.query_params()
+            .into_iter()
+            .filter(|(param, _)| param == name)
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    pub fn query_param_u64(&self, name: &str) -> Result<Option<u64>, String> {
+        self.parse_query_param(name, u64::from_str)
+    }
+
+    /// Accepts `true`/`false`, `1`/`0` and `yes`/`no`. A parameter without a value, like
+    /// `?pretty`, is true.
+    pub fn query_param_bool(&self, name: &str) -> Result<Option<bool>, String> {
+        self.parse_query_param(name, |value| match value.to_lowercase().as_str() {
+            "" | "true" | "1" | "yes" => Ok(true),
+            "false" | "0" | "no" => Ok(false),
+            _ => Err("expected true or false"),
+        })
+    }
+
+    /// Durations use the syntax of aggregate windows, e.g. `15m` or `1h30m`.
+    pub fn query_param_duration(&self, name: &str) -> Result<Option<Duration>, String> {
+        self.parse_query_param(name, Duration::parse)
+    }
+
+    fn parse_query_param<T, E: ToString>(
+        &self,
+        name: &str,
+        parse: impl Fn(&str) -> Result<T, E>,
+    ) -> Result<Option<T>, String> {
+        self.query_param(name)
+            .map(|value| {
+                parse(&value).map_err(|err| {
+                    format!("Invalid parameter '{}': {}", name, err.to_string())
+                })
+            })
+            .transpose()
+    }
+
     /// Searches for the first appearance of a parameter in the request URL.
     /// Returns `None` if the given parameter does not appear in the query.
     #[allow(dead_code)]
@@ -41,21 +129,74 @@ impl HttpRequest {
     }
 }
 
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    params
+        .split('&')
+        .filter(|chunk| !chunk.is_empty())
+        .map(|chunk| {
+            let (name, value) = chunk.split_once('=').unwrap_or((chunk, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+/// Decodes `%XX` escapes and `+`, malformed escapes are kept as they are.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct HttpResponse {
     pub status_code: u16,
     pub headers: Vec<(String, String)>,
     pub body: ByteBuf,
+    pub upgrade: Option<bool>, // asks the gateway to repeat the request as an update call
 }
 
 pub struct HttpResponseBuilder(HttpResponse);
 
 impl HttpResponseBuilder {
+    #[allow(dead_code)]
     pub fn ok() -> Self {
         Self(HttpResponse {
             status_code: 200,
             headers: vec![],
             body: ByteBuf::default(),
+            upgrade: None,
+        })
+    }
+
+    pub fn with_status(status_code: u16) -> Self {
+        Self(HttpResponse {
+            status_code,
+            headers: vec![],
+            body: ByteBuf::default(),
+            upgrade: None,
         })
     }
 
@@ -65,14 +206,17 @@ impl HttpResponseBuilder {
             status_code: 400,
             headers: vec![],
             body: ByteBuf::from("bad request"),
+            upgrade: None,
         })
     }
 
+    #[allow(dead_code)]
     pub fn not_found() -> Self {
         Self(HttpResponse {
             status_code: 404,
             headers: vec![],
             body: ByteBuf::from("not found"),
+            upgrade: None,
         })
     }
 
@@ -82,6 +226,7 @@ impl HttpResponseBuilder {
             status_code: 500,
             headers: vec![],
             body: ByteBuf::from(reason.to_string()),
+            upgrade: None,
         })
     }
 
@@ -101,6 +246,11 @@ impl HttpResponseBuilder {
         self.body(bytes);
     }
 
+    /// Makes the gateway send the request again to `http_request_update`.
+    pub fn upgrade(&mut self) {
+        self.0.upgrade = Some(true);
+    }
+
     pub fn build(self) -> HttpResponse {
         self.0
     }
@@ -124,3 +274,41 @@ fn test_raw_query_param() {
         request_with_url("/endpoint?time=1000&time=1001&other=abcde&time=1002".to_string());
     assert_eq!(http_request.raw_query_param("time"), Some("1000"));
 }
+
+#[test]
+fn test_query_params() {
+    let http_request = HttpRequest {
+        method: "GET".to_string(),
+        url: "/query?measurement=room%201&filter=a+%3E%3D+1&tag=x&tag=y%2Cz&flag&n=42&d=1h30m&bad=%zz%"
+            .to_string(),
+        headers: vec![],
+        body: Default::default(),
+    };
+
+    assert_eq!(http_request.query_param("measurement"), Some("room 1".to_string()));
+    assert_eq!(http_request.query_param("filter"), Some("a >= 1".to_string()));
+    assert_eq!(http_request.query_param_all("tag"), vec!["x", "y,z"]);
+    assert_eq!(http_request.query_param("bad"), Some("%zz%".to_string()));
+    assert_eq!(http_request.query_param("missing"), None);
+
+    assert_eq!(http_request.query_param_u64("n"), Ok(Some(42)));
+    assert!(http_request.query_param_u64("measurement").is_err());
+    assert_eq!(http_request.query_param_bool("flag"), Ok(Some(true)));
+    assert!(http_request.query_param_bool("n").is_err());
+    assert_eq!(
+        http_request.query_param_duration("d"),
+        Ok(Some(Duration::parse("90m").unwrap()))
+    );
+
+    let form = HttpRequest {
+        method: "POST".to_string(),
+        url: "/api/v1/query?time=1".to_string(),
+        headers: vec![(
+            "content-type".to_string(),
+            "application/x-www-form-urlencoded".to_string(),
+        )],
+        body: ByteBuf::from("query=up%7Bjob%3D%22edge%22%7D&time=2"),
+    };
+    assert_eq!(form.query_param("query"), Some("up{job=\"edge\"}".to_string()));
+    assert_eq!(form.query_param_all("time"), vec!["1", "2"]);
+}
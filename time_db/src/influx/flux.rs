@@ -0,0 +1,640 @@
+use std::str::FromStr;
+
+use crate::timedb::{parse_rfc3339, Action, AggregateFunction, Duration, Expression, Value};
+
+/// A Flux query compiled to the measurement it reads and the actions to run on it.
+#[derive(Debug)]
+pub struct FluxQuery {
+    pub bucket: String,
+    pub measurement: String,
+    pub actions: Vec<Action>,
+}
+
+/// Compiles the subset of Flux used by Grafana and the InfluxDB UI:
+///
+/// ```text
+/// from(bucket: "edge")
+///   |> range(start: -1h, stop: now())
+///   |> filter(fn: (r) => r._measurement == "cpu" and r.host == "edge-1")
+///   |> filter(fn: (r) => r._field == "usage" or r._field == "load")
+///   |> aggregateWindow(every: 1m, fn: mean, createEmpty: false)
+///   |> yield(name: "mean")
+/// ```
+///
+/// The pipeline starts with `from` and needs a `range` and a filter on `_measurement`.
+/// Filters on `_field` select fields, other columns name tags or fields. Times are
+/// relative durations, RFC 3339, Unix seconds or `now()`, all resolved against `now`
+/// (nanoseconds).
+pub fn compile(query: &str, now: u64) -> Result<FluxQuery, String> {
+    let mut parser = Parser {
+        tokens: tokenize(query)?,
+        next: 0,
+    };
+
+    let mut calls = vec![parser.call()?];
+    while parser.accept(&Token::Pipe) {
+        calls.push(parser.call()?);
+    }
+    if let Some((token, position)) = parser.tokens.get(parser.next) {
+        return Err(format!("unexpected {} at position {}", token, position));
+    }
+
+    let mut calls = calls.into_iter();
+    let bucket = match calls.next() {
+        Some(mut call) if call.name == "from" => {
+            let bucket = call.string("bucket")?;
+            call.done()?;
+            bucket
+        }
+        _ => return Err("queries have to start with from(bucket: ...)".to_string()),
+    };
+
+    let mut measurement = None;
+    let mut range = false;
+    let mut actions = vec![];
+    for mut call in calls {
+        match call.name.as_str() {
+            "range" => {
+                let start = call
+                    .time("start", now)?
+                    .ok_or_else(|| "range() needs a start".to_string())?;
+                let stop = call.time("stop", now)?.unwrap_or(now);
+                // the stop of a Flux range is exclusive
+                actions.push(Action::Range(start, Some(stop.saturating_sub(1))));
+                range = true;
+            }
+            "filter" => match call.take("fn") {
+                Some(Argument::Predicate(predicate)) => {
+                    filter(predicate, &mut measurement, &mut actions)?
+                }
+                _ => return Err("filter() needs a predicate fn: (r) => ...".to_string()),
+            },
+            "aggregateWindow" => {
+                let every = match call.take("every") {
+                    Some(Argument::Duration(false, every)) => every,
+                    _ => return Err("aggregateWindow() needs a duration every".to_string()),
+                };
+                let function = match call.take("fn") {
+                    Some(Argument::Identifier(function)) => AggregateFunction::from_str(&function)
+                        .map_err(|_| {
+                            format!(
+                                "aggregateWindow() supports fn: mean, max, min or sum, not '{}'",
+                                function
+                            )
+                        })?,
+                    _ => return Err("aggregateWindow() needs a function fn".to_string()),
+                };
+                if let Some(Argument::Identifier(create_empty)) = call.take("createEmpty") {
+                    if create_empty != "false" {
+                        return Err(
+                            "aggregateWindow() only supports createEmpty: false".to_string()
+                        );
+                    }
+                }
+                actions.push(Action::AggregateWindow(every, function));
+            }
+            "yield" => {
+                call.take("name");
+            }
+            name => return Err(format!("function {}() is not supported", name)),
+        }
+        call.done()?;
+    }
+
+    if !range {
+        return Err("queries need a range(start: ...)".to_string());
+    }
+    let measurement = measurement
+        .ok_or_else(|| "queries need a filter on r._measurement == \"...\"".to_string())?;
+
+    Ok(FluxQuery {
+        bucket,
+        measurement,
+        actions,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Number(String),
+    Duration(String),
+    Time(u64),
+    Regex(String),
+    Pipe,  // |>
+    Arrow, // =>
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Identifier(name) => write!(f, "'{}'", name),
+            Token::String(string) => write!(f, "\"{}\"", string),
+            Token::Number(number) | Token::Duration(number) => write!(f, "'{}'", number),
+            Token::Time(time) => write!(f, "time {}", time),
+            Token::Regex(regex) => write!(f, "/{}/", regex),
+            Token::Pipe => write!(f, "'|>'"),
+            Token::Arrow => write!(f, "'=>'"),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 15] = [
+    "==", "!=", "<=", ">=", "=~", "!~", "<", ">", "(", ")", "[", "]", ",", ":", ".",
+];
+
+fn tokenize(query: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = vec![];
+    let mut rest = query;
+
+    loop {
+        rest = rest.trim_start();
+        let position = query.len() - rest.len();
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Ok(tokens),
+        };
+
+        let (token, len) = if let Some(symbol) = ["|>", "=>"].iter().find(|s| rest.starts_with(*s))
+        {
+            let token = match *symbol {
+                "|>" => Token::Pipe,
+                _ => Token::Arrow,
+            };
+            (token, 2)
+        } else if let Some(symbol) = SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+            (Token::Symbol(symbol), symbol.len())
+        } else if c == '"' {
+            let mut string = String::new();
+            let mut chars = rest.char_indices().skip(1);
+            let end = loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => string.push('\n'),
+                        Some((_, 't')) => string.push('\t'),
+                        Some((_, escaped)) => string.push(escaped),
+                        None => break None,
+                    },
+                    Some((i, '"')) => break Some(i + 1),
+                    Some((_, c)) => string.push(c),
+                    None => break None,
+                }
+            };
+            let end = end.ok_or_else(|| format!("unterminated string at position {}", position))?;
+            (Token::String(string), end)
+        } else if c == '/' {
+            let end = rest[1..].find('/').ok_or_else(|| {
+                format!("unterminated regular expression at position {}", position)
+            })?;
+            (Token::Regex(rest[1..end + 1].to_string()), end + 2)
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (Token::Identifier(rest[..len].to_string()), len)
+        } else if c.is_ascii_digit() || c == '-' {
+            // numbers, durations like `-1h30m` and times like `2024-01-01T00:00:00Z`
+            let len = rest
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| !(c.is_ascii_alphanumeric() || matches!(c, '.' | ':' | '-' | '+')))
+                .map_or(rest.len(), |(i, _)| i);
+            let literal = &rest[..len];
+            let body = literal.strip_prefix('-').unwrap_or(literal);
+
+            let token = if body.contains('T') && !literal.starts_with('-') {
+                Token::Time(
+                    parse_rfc3339(literal)
+                        .map_err(|err| format!("{} at position {}", err, position))?,
+                )
+            } else if body.starts_with(|c: char| c.is_ascii_digit())
+                && body.ends_with(|c: char| c.is_ascii_alphabetic())
+            {
+                Token::Duration(literal.to_string())
+            } else if body.parse::<f64>().is_ok() {
+                Token::Number(literal.to_string())
+            } else {
+                return Err(format!(
+                    "invalid literal '{}' at position {}",
+                    literal, position
+                ));
+            };
+            (token, len)
+        } else {
+            return Err(format!("unexpected '{}' at position {}", c, position));
+        };
+
+        tokens.push((token, position));
+        rest = &rest[len..];
+    }
+}
+
+#[derive(Debug)]
+enum Argument {
+    String(String),
+    Number(String),
+    Duration(bool, String), // negative, duration spec
+    Time(u64),
+    Now,
+    Identifier(String),
+    Predicate(Predicate),
+}
+
+#[derive(Debug)]
+enum Predicate {
+    Compare(String, &'static str, Value), // column, operator, value
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+}
+
+struct Call {
+    name: String,
+    arguments: Vec<(String, Argument)>,
+}
+
+impl Call {
+    fn take(&mut self, name: &str) -> Option<Argument> {
+        let i = self.arguments.iter().position(|(arg, _)| arg == name)?;
+        Some(self.arguments.remove(i).1)
+    }
+
+    fn string(&mut self, name: &str) -> Result<String, String> {
+        match self.take(name) {
+            Some(Argument::String(value)) => Ok(value),
+            _ => Err(format!("{}() needs a string {}", self.name, name)),
+        }
+    }
+
+    fn time(&mut self, name: &str, now: u64) -> Result<Option<u64>, String> {
+        let time = match self.take(name) {
+            None => return Ok(None),
+            Some(Argument::Now) => now,
+            Some(Argument::Time(time)) => time,
+            Some(Argument::Number(seconds)) => seconds
+                .parse::<u64>()
+                .ok()
+                .and_then(|seconds| seconds.checked_mul(1_000_000_000))
+                .ok_or_else(|| format!("invalid {} '{}'", name, seconds))?,
+            Some(Argument::Duration(negative, spec)) => {
+                let nanos = Duration::parse(&spec)
+                    .ok()
+                    .and_then(|duration| duration.fixed_nanos())
+                    .ok_or_else(|| format!("invalid {} '{}'", name, spec))?;
+                match negative {
+                    true => now.saturating_sub(nanos),
+                    false => now.saturating_add(nanos),
+                }
+            }
+            Some(_) => return Err(format!("{}() needs a time {}", self.name, name)),
+        };
+        Ok(Some(time))
+    }
+
+    // Fails on arguments that weren't taken
+    fn done(&self) -> Result<(), String> {
+        match self.arguments.first() {
+            Some((name, _)) => Err(format!(
+                "argument {} of {}() is not supported",
+                name, self.name
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+}
+
+impl Parser {
+    fn call(&mut self) -> Result<Call, String> {
+        let name = self.identifier()?;
+        self.expect(&Token::Symbol("("))?;
+
+        let mut arguments = vec![];
+        while !self.accept(&Token::Symbol(")")) {
+            if !arguments.is_empty() {
+                self.expect(&Token::Symbol(","))?;
+            }
+            let name = self.identifier()?;
+            self.expect(&Token::Symbol(":"))?;
+            arguments.push((name, self.argument()?));
+        }
+
+        Ok(Call { name, arguments })
+    }
+
+    fn argument(&mut self) -> Result<Argument, String> {
+        match self.advance()? {
+            Token::String(string) => Ok(Argument::String(string)),
+            Token::Number(number) => Ok(Argument::Number(number)),
+            Token::Time(time) => Ok(Argument::Time(time)),
+            Token::Duration(duration) => Ok(match duration.strip_prefix('-') {
+                Some(duration) => Argument::Duration(true, duration.to_string()),
+                None => Argument::Duration(false, duration),
+            }),
+            Token::Identifier(name) if name == "now" => {
+                self.expect(&Token::Symbol("("))?;
+                self.expect(&Token::Symbol(")"))?;
+                Ok(Argument::Now)
+            }
+            Token::Identifier(name) => Ok(Argument::Identifier(name)),
+            Token::Symbol("(") => {
+                let record = self.identifier()?;
+                self.expect(&Token::Symbol(")"))?;
+                self.expect(&Token::Arrow)?;
+                Ok(Argument::Predicate(self.or(&record)?))
+            }
+            token => Err(self.unexpected(&token)),
+        }
+    }
+
+    fn or(&mut self, record: &str) -> Result<Predicate, String> {
+        let mut predicate = self.and(record)?;
+        while self.accept(&Token::Identifier("or".to_string())) {
+            predicate = Predicate::Or(Box::new(predicate), Box::new(self.and(record)?));
+        }
+        Ok(predicate)
+    }
+
+    fn and(&mut self, record: &str) -> Result<Predicate, String> {
+        let mut predicate = self.unary(record)?;
+        while self.accept(&Token::Identifier("and".to_string())) {
+            predicate = Predicate::And(Box::new(predicate), Box::new(self.unary(record)?));
+        }
+        Ok(predicate)
+    }
+
+    fn unary(&mut self, record: &str) -> Result<Predicate, String> {
+        if self.accept(&Token::Identifier("not".to_string())) {
+            return Ok(Predicate::Not(Box::new(self.unary(record)?)));
+        }
+        if self.accept(&Token::Symbol("(")) {
+            let predicate = self.or(record)?;
+            self.expect(&Token::Symbol(")"))?;
+            return Ok(predicate);
+        }
+
+        // r.column or r["column"]
+        match self.advance()? {
+            Token::Identifier(name) if name == record => {}
+            token => return Err(self.unexpected(&token)),
+        }
+        let column = match self.advance()? {
+            Token::Symbol(".") => self.identifier()?,
+            Token::Symbol("[") => match self.advance()? {
+                Token::String(column) => {
+                    self.expect(&Token::Symbol("]"))?;
+                    column
+                }
+                token => return Err(self.unexpected(&token)),
+            },
+            token => return Err(self.unexpected(&token)),
+        };
+
+        let operator = match self.advance()? {
+            Token::Symbol(operator @ ("==" | "!=" | "<" | "<=" | ">" | ">=")) => operator,
+            Token::Symbol("=~" | "!~") => {
+                return Err("regular expression comparisons are not supported".to_string())
+            }
+            token => return Err(self.unexpected(&token)),
+        };
+
+        let value = match self.advance()? {
+            Token::String(string) => Value::String(string),
+            Token::Identifier(name) if name == "true" => Value::Bool(true),
+            Token::Identifier(name) if name == "false" => Value::Bool(false),
+            Token::Number(number) => match number.parse::<i128>() {
+                Ok(int) => Value::Int(int),
+                Err(_) => Value::Float(number.parse::<f64>().unwrap_or_default() as f32),
+            },
+            token => return Err(self.unexpected(&token)),
+        };
+
+        Ok(Predicate::Compare(column, operator, value))
+    }
+
+    fn identifier(&mut self) -> Result<String, String> {
+        match self.advance()? {
+            Token::Identifier(name) => Ok(name),
+            token => Err(self.unexpected(&token)),
+        }
+    }
+
+    fn expect(&mut self, expected: &Token) -> Result<(), String> {
+        match self.advance()? {
+            token if &token == expected => Ok(()),
+            token => Err(format!(
+                "expected {}, {}",
+                expected,
+                self.unexpected(&token)
+            )),
+        }
+    }
+
+    fn accept(&mut self, expected: &Token) -> bool {
+        match self.tokens.get(self.next) {
+            Some((token, _)) if token == expected => {
+                self.next += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn advance(&mut self) -> Result<Token, String> {
+        let (token, _) = self
+            .tokens
+            .get(self.next)
+            .cloned()
+            .ok_or_else(|| "unexpected end of query".to_string())?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    // Error for the token just read
+    fn unexpected(&self, token: &Token) -> String {
+        format!(
+            "unexpected {} at position {}",
+            token,
+            self.tokens[self.next - 1].1
+        )
+    }
+}
+
+// Turns the conjuncts of a filter into the measurement, field selections and expressions
+fn filter(
+    predicate: Predicate,
+    measurement: &mut Option<String>,
+    actions: &mut Vec<Action>,
+) -> Result<(), String> {
+    let mut conjuncts = vec![];
+    split_and(predicate, &mut conjuncts);
+
+    for conjunct in conjuncts {
+        match conjunct {
+            Predicate::Compare(column, "==", Value::String(name)) if column == "_measurement" => {
+                match measurement {
+                    Some(current) if *current != name => {
+                        return Err(format!(
+                            "the query filters two measurements, '{}' and '{}'",
+                            current, name
+                        ))
+                    }
+                    _ => *measurement = Some(name),
+                }
+            }
+            conjunct => match field_names(&conjunct) {
+                Some(fields) => actions.push(Action::Filter(Expression::FieldFilter(fields))),
+                None => actions.push(Action::Filter(expression(conjunct)?)),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn split_and(predicate: Predicate, conjuncts: &mut Vec<Predicate>) {
+    match predicate {
+        Predicate::And(left, right) => {
+            split_and(*left, conjuncts);
+            split_and(*right, conjuncts);
+        }
+        predicate => conjuncts.push(predicate),
+    }
+}
+
+// Fields of `r._field == "a" or r._field == "b" ...`
+fn field_names(predicate: &Predicate) -> Option<Vec<String>> {
+    match predicate {
+        Predicate::Compare(column, "==", Value::String(name)) if column == "_field" => {
+            Some(vec![name.clone()])
+        }
+        Predicate::Or(left, right) => {
+            let mut fields = field_names(left)?;
+            fields.extend(field_names(right)?);
+            Some(fields)
+        }
+        _ => None,
+    }
+}
+
+fn expression(predicate: Predicate) -> Result<Expression, String> {
+    Ok(match predicate {
+        Predicate::Compare(column, _, _) if column.starts_with('_') => {
+            return Err(format!(
+                "column {} can only be used as r._measurement == \"...\" or r._field == \"...\" \
+                 (joined with or)",
+                column
+            ))
+        }
+        Predicate::Compare(column, operator, value) => match operator {
+            "==" => Expression::Eq(column, value),
+            "!=" => Expression::Not(Box::new(Expression::Eq(column, value))),
+            "<" => Expression::Lt(column, value),
+            "<=" => Expression::Le(column, value),
+            ">" => Expression::Gt(column, value),
+            _ => Expression::Ge(column, value),
+        },
+        Predicate::And(left, right) => {
+            Expression::And(Box::new(expression(*left)?), Box::new(expression(*right)?))
+        }
+        Predicate::Or(left, right) => {
+            Expression::Or(Box::new(expression(*left)?), Box::new(expression(*right)?))
+        }
+        Predicate::Not(predicate) => Expression::Not(Box::new(expression(*predicate)?)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    #[test]
+    fn test_compile() {
+        let query = compile(
+            r#"from(bucket: "edge")
+                |> range(start: -1h, stop: 2023-11-14T22:13:20Z)
+                |> filter(fn: (r) => r["_measurement"] == "cpu" and r.host != "edge-1")
+                |> filter(fn: (r) => r._field == "usage" or r._field == "load")
+                |> filter(fn: (r) => not (r.usage > 0.5 or r.cores <= 2))
+                |> aggregateWindow(every: 1m, fn: mean, createEmpty: false)
+                |> yield(name: "mean")"#,
+            NOW,
+        )
+        .unwrap();
+
+        assert_eq!(query.bucket, "edge");
+        assert_eq!(query.measurement, "cpu");
+        assert_eq!(
+            format!("{:?}", query.actions),
+            format!(
+                "{:?}",
+                vec![
+                    Action::Range(NOW - 3_600_000_000_000, Some(NOW - 1)),
+                    Action::Filter(Expression::Not(Box::new(Expression::Eq(
+                        "host".to_string(),
+                        Value::String("edge-1".to_string())
+                    )))),
+                    Action::Filter(Expression::FieldFilter(vec![
+                        "usage".to_string(),
+                        "load".to_string()
+                    ])),
+                    Action::Filter(Expression::Not(Box::new(Expression::Or(
+                        Box::new(Expression::Gt("usage".to_string(), Value::Float(0.5))),
+                        Box::new(Expression::Le("cores".to_string(), Value::Int(2))),
+                    )))),
+                    Action::AggregateWindow("1m".to_string(), AggregateFunction::Mean),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        for (query, error) in [
+            (
+                r#"range(start: -1h)"#,
+                "queries have to start with from(bucket: ...)",
+            ),
+            (
+                r#"from(bucket: "b") |> filter(fn: (r) => r._measurement == "cpu")"#,
+                "queries need a range(start: ...)",
+            ),
+            (
+                r#"from(bucket: "b") |> range(start: -1h)"#,
+                "queries need a filter on r._measurement == \"...\"",
+            ),
+            (
+                r#"from(bucket: "b") |> range(start: -1h) |> last()"#,
+                "function last() is not supported",
+            ),
+            (
+                r#"from(bucket: "b") |> range(start: -1h) |> filter(fn: (r) => r.host =~ /e/)"#,
+                "regular expression comparisons are not supported",
+            ),
+            (
+                r#"from(bucket: "b") |> range(start: -1h, offset: 1h)"#,
+                "argument offset of range() is not supported",
+            ),
+            (
+                r#"from(bucket: "b") |> range(start: -1h) |> filter(fn: (r) => r._value > 1)"#,
+                "column _value can only be used as r._measurement == \"...\" or r._field == \"...\" (joined with or)",
+            ),
+            (
+                r#"from(bucket: "b") |> range(start: -1h) |> aggregateWindow(every: 1m, fn: last)"#,
+                "aggregateWindow() supports fn: mean, max, min or sum, not 'last'",
+            ),
+            (r#"from(bucket: "b"))"#, "unexpected ')' at position 17"),
+        ] {
+            assert_eq!(compile(query, NOW).unwrap_err(), error, "{}", query);
+        }
+    }
+}
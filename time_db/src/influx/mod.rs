@@ -0,0 +1,237 @@
+mod flux;
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    caller_query_limits,
+    export::{export, ExportFormat},
+    http::{is_write_authorized, respond},
+    http_encoding::ContentEncoding,
+    http_types::{HttpRequest, HttpResponse, HttpResponseBuilder},
+    line_protocol::{self, Precision},
+    metrics,
+    timedb::{Entry, QueryError},
+    TIME_DB,
+};
+
+const JSON: &str = "application/json; charset=utf-8";
+
+// Largest body accepted once decompressed
+const MAX_BODY_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Failure of a call to the InfluxDB API, answered as `{"code", "message"}`.
+#[derive(Debug)]
+pub struct InfluxError {
+    status: u16,
+    code: &'static str,
+    message: String,
+}
+
+impl InfluxError {
+    fn invalid(message: impl ToString) -> Self {
+        Self {
+            status: 400,
+            code: "invalid",
+            message: message.to_string(),
+        }
+    }
+
+    fn new(status: u16, code: &'static str, message: impl ToString) -> Self {
+        Self {
+            status,
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn into_response(self, req: &HttpRequest) -> HttpResponse {
+        #[derive(Serialize)]
+        struct Reply {
+            code: &'static str,
+            message: String,
+        }
+
+        let reply = Reply {
+            code: self.code,
+            message: self.message,
+        };
+        match serde_json::to_vec(&reply) {
+            Ok(body) => respond(req, self.status, JSON, body),
+            Err(err) => HttpResponseBuilder::server_error(err).build(),
+        }
+    }
+}
+
+impl From<QueryError> for InfluxError {
+    fn from(err: QueryError) -> Self {
+        match err {
+            QueryError::LimitExceeded { .. } => InfluxError::new(422, "unprocessable entity", err),
+            QueryError::Invalid(message) => InfluxError::invalid(message),
+        }
+    }
+}
+
+/// Stores the line protocol in the body of `POST /api/v2/write?org=..&bucket=..`.
+///
+/// Points go to the measurement `<org>/<bucket>/<measurement>`. Timestamps are read with
+/// the `precision` parameter (`ns` by default), the body may be gzip or deflate
+/// compressed. Nothing is written when a line is invalid. The request has to carry a
+/// write token in its `Authorization` header.
+pub fn write(req: &HttpRequest) -> Result<(), InfluxError> {
+    ensure_post(req)?;
+    if !is_write_authorized(req) {
+        return Err(InfluxError::new(
+            401,
+            "unauthorized",
+            "A valid write token is required",
+        ));
+    }
+
+    let prefix = prefix(req, &required(req, "bucket")?)?;
+    let precision = req
+        .query_param("precision")
+        .map(|precision| precision.parse::<Precision>())
+        .transpose()
+        .map_err(InfluxError::invalid)?
+        .unwrap_or_default();
+
+    let body = body(req)?;
+    let text = std::str::from_utf8(&body)
+        .map_err(|_| InfluxError::invalid("line protocol has to be UTF-8"))?;
+
+    let time = crate::now();
+    let points = line_protocol::parse(text, precision, time).map_err(|err| {
+        metrics::record_rejected_writes("influx_write", text.lines().count() as u64);
+        InfluxError::invalid(err)
+    })?;
+
+    let mut measurements: BTreeMap<String, Vec<Entry>> = BTreeMap::new();
+    for (measurement, entry) in points {
+        measurements
+            .entry(format!("{}{}", prefix, measurement))
+            .or_default()
+            .push(entry);
+    }
+
+    for (name, entries) in &measurements {
+        crate::store_entries("influx_write", name, entries);
+    }
+
+    Ok(())
+}
+
+/// Runs the Flux query in the body of `POST /api/v2/query?org=..` and answers with
+/// annotated CSV, see `flux::compile` for the supported subset.
+///
+/// The body is the query itself (`application/vnd.flux`) or a JSON object with a `query`.
+pub fn query(req: &HttpRequest) -> Result<HttpResponse, InfluxError> {
+    #[derive(Deserialize)]
+    struct QueryBody {
+        query: String,
+        #[serde(rename = "type")]
+        query_type: Option<String>,
+    }
+
+    ensure_post(req)?;
+
+    let body = body(req)?;
+    let json = req
+        .header("Content-Type")
+        .is_some_and(|content_type| content_type.trim_start().starts_with("application/json"));
+    let text = if json {
+        let body: QueryBody = serde_json::from_slice(&body)
+            .map_err(|err| InfluxError::invalid(format!("Invalid query body: {}", err)))?;
+        if body
+            .query_type
+            .as_deref()
+            .is_some_and(|query_type| query_type != "flux")
+        {
+            return Err(InfluxError::invalid("Only flux queries are supported"));
+        }
+        body.query
+    } else {
+        String::from_utf8(body).map_err(|_| InfluxError::invalid("query has to be UTF-8"))?
+    };
+
+    let query = flux::compile(&text, crate::now())
+        .map_err(|err| InfluxError::invalid(format!("Invalid flux query: {}", err)))?;
+    let measurement = format!("{}{}", prefix(req, &query.bucket)?, query.measurement);
+
+    let limits = caller_query_limits();
+    let result = TIME_DB.with(|m| {
+        let db = m.borrow();
+        db.find_measurement(&measurement)
+            .map(|measure| measure.apply(&query.actions, &limits, None))
+            .transpose()
+    })?;
+
+    let entries = match result {
+        Some(result) if result.truncated => {
+            return Err(InfluxError::new(
+                422,
+                "unprocessable entity",
+                "The query exceeds the query limits, read a shorter time range",
+            ))
+        }
+        Some(result) => result.entries,
+        None => vec![],
+    };
+
+    let body = export(&entries, ExportFormat::AnnotatedCsv)
+        .map_err(|err| InfluxError::new(500, "internal error", err))?;
+    Ok(respond(
+        req,
+        200,
+        ExportFormat::AnnotatedCsv.content_type(),
+        body,
+    ))
+}
+
+// Measurements of a bucket are stored as `<org>/<bucket>/<measurement>`
+fn prefix(req: &HttpRequest, bucket: &str) -> Result<String, InfluxError> {
+    let org = req
+        .query_param("org")
+        .or_else(|| req.query_param("orgID"))
+        .filter(|org| !org.is_empty())
+        .ok_or_else(|| InfluxError::invalid("Parameter 'org' is required"))?;
+    if bucket.is_empty() {
+        return Err(InfluxError::invalid("The bucket must not be empty"));
+    }
+    Ok(format!("{}/{}/", org, bucket))
+}
+
+fn required(req: &HttpRequest, name: &str) -> Result<String, InfluxError> {
+    req.query_param(name)
+        .ok_or_else(|| InfluxError::invalid(format!("Parameter '{}' is required", name)))
+}
+
+// Telegraf compresses with gzip by default
+fn body(req: &HttpRequest) -> Result<Vec<u8>, InfluxError> {
+    let encoding = match req.header("Content-Encoding") {
+        Some(name) => ContentEncoding::from_name(name).ok_or_else(|| {
+            InfluxError::new(
+                415,
+                "unsupported media type",
+                format!("Content-Encoding {} is not supported", name),
+            )
+        })?,
+        None => ContentEncoding::Identity,
+    };
+
+    encoding
+        .decode(&req.body, MAX_BODY_BYTES)
+        .map_err(|err| InfluxError::invalid(format!("Invalid {} body: {}", encoding.name(), err)))
+}
+
+fn ensure_post(req: &HttpRequest) -> Result<(), InfluxError> {
+    match req.method.as_str() {
+        "POST" => Ok(()),
+        method => Err(InfluxError::new(
+            405,
+            "method not allowed",
+            format!("Method {} is not allowed, use POST", method),
+        )),
+    }
+}
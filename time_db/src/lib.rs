@@ -1,22 +1,41 @@
+mod alerts;
+mod batches;
+#[cfg(any(test, feature = "benchmark"))]
+mod benchmark;
+mod devices;
+mod export;
 mod http;
+mod http_encoding;
 mod http_types;
+mod influx;
+mod line_protocol;
+mod metrics;
+mod mqtt;
+mod prometheus;
+mod transforms;
 
 mod timedb;
 
 use candid::{candid_method, export_service, CandidType, Deserialize, Principal};
-use ic_cdk::api::time;
-use ic_cdk_macros::{init, query, update};
+use ic_cdk_macros::{init, post_upgrade, query, update};
 use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
 
-use timedb::{Action, Entry, TimeDb};
+use batches::AppliedBatches;
+use export::ExportFormat;
+use serde_bytes::ByteBuf;
+
+use mqtt::{ensure_gateway, Subscription, TopicRule};
+use timedb::{Action, Entry, MeasurementStats, QueryError, QueryLimits, QueryResult, TimeDb};
 
 #[derive(Clone, CandidType, Deserialize)]
 pub struct Message {
     index: u64,
     topic: String,
-    message: String,
+    message: ByteBuf,
     timestamp: u64,
+    received_at: u64, // time the canister stored the message, the logs are trimmed by it
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -27,36 +46,126 @@ pub struct PagedResult {
     data: Vec<Message>,
 }
 
+/// Selects messages of a message log, `topic` is an MQTT topic filter and the time range
+/// excludes `end`. Pass the index of the last message seen as `after` to page through
+/// the log without missing or repeating messages when old ones get trimmed.
+#[derive(Clone, CandidType, Deserialize, Default)]
+pub struct MessageQuery {
+    skip: Option<u64>,
+    limit: Option<u64>,
+    topic: Option<String>,
+    start: Option<u64>,
+    end: Option<u64>,
+    after: Option<u64>,
+}
+
 pub struct MessageStore {
-    messages: Vec<Message>,
+    messages: VecDeque<Message>,
+    next_index: u64,
 }
 
 #[derive(Clone, CandidType, Deserialize)]
 pub struct Settings {
     owner: Principal,
-    interval: u64,
+    interval: u64, // seconds between the polls of the gateway
+    subscriptions: Vec<Subscription>,
+    rules: Vec<TopicRule>,
+    inbound_batch_size: u64,  // most messages of an `on_messages` call
+    outbound_batch_size: u64, // most messages handed out by a `poll_outbound` call
+    retention: u64,           // seconds messages are kept in the logs, 0 keeps them forever
+    version: u64,             // increased on every change
 }
 
+#[derive(Default)]
+pub struct QueryLimitSettings {
+    default: QueryLimits,
+    callers: HashMap<Principal, QueryLimits>,
+}
+
+// Largest page of messages returned by a query
+const MAX_MESSAGE_PAGE: u64 = 1000;
+
 impl MessageStore {
     pub fn new() -> Self {
         Self {
-            messages: Vec::new(),
+            messages: VecDeque::new(),
+            next_index: 0,
         }
     }
 
-    pub fn add_message(&mut self, message: &Message) {
+    /// Appends a copy of the message and returns the index it was stored at. Indexes keep
+    /// counting up when old messages are removed.
+    pub fn add_message(&mut self, message: &Message) -> u64 {
+        let index = self.next_index;
         let msg = Message {
-            index: self.messages.len() as u64,
+            index,
             topic: message.topic.clone(),
             message: message.message.clone(),
             timestamp: message.timestamp,
+            received_at: message.received_at,
         };
 
-        self.messages.push(msg);
+        self.messages.push_back(msg);
+        self.next_index += 1;
+        index
     }
 
-    pub fn get_messages(&self) -> &Vec<Message> {
-        &self.messages
+    pub fn get_message(&self, index: u64) -> Option<&Message> {
+        let first = self.messages.front()?.index;
+        self.messages.get(index.checked_sub(first)? as usize)
+    }
+
+    /// Removes the messages the canister received before `time`, whatever their timestamp.
+    /// Returns the index of the oldest message kept.
+    pub fn remove_before(&mut self, time: u64) -> u64 {
+        while self
+            .messages
+            .front()
+            .is_some_and(|message| message.received_at < time)
+        {
+            self.messages.pop_front();
+        }
+        self.messages
+            .front()
+            .map_or(self.next_index, |message| message.index)
+    }
+
+    pub fn query(&self, query: &MessageQuery) -> Result<PagedResult, String> {
+        if let Some(topic) = &query.topic {
+            mqtt::validate_filter(topic)?;
+        }
+
+        let skip = query.skip.unwrap_or_default();
+        let limit = query.limit.unwrap_or(100).min(MAX_MESSAGE_PAGE);
+        let first = query.after.map_or(0, |after| after.saturating_add(1));
+        let matches = self
+            .messages
+            .iter()
+            .skip_while(|message| message.index < first)
+            .filter(|message| {
+                query.start.is_none_or(|start| message.timestamp >= start)
+                    && query.end.is_none_or(|end| message.timestamp < end)
+                    && query
+                        .topic
+                        .as_ref()
+                        .is_none_or(|topic| mqtt::topic_matches(topic, &message.topic))
+            });
+
+        let mut total = 0;
+        let mut data = vec![];
+        for message in matches {
+            if total >= skip && (data.len() as u64) < limit {
+                data.push(message.clone());
+            }
+            total += 1;
+        }
+
+        Ok(PagedResult {
+            skip,
+            limit,
+            total,
+            data,
+        })
     }
 }
 
@@ -69,7 +178,50 @@ thread_local! {
     pub static SETTINGS: Rc<RefCell<Settings>> =  Rc::new(RefCell::new(Settings {
         owner: Principal::anonymous(),
         interval: 1,
+        subscriptions: Vec::new(),
+        rules: Vec::new(),
+        inbound_batch_size: 1000,
+        outbound_batch_size: 100,
+        retention: 7 * 24 * 3600,
+        version: 0,
     }));
+
+    pub static QUERY_LIMITS: RefCell<QueryLimitSettings> = RefCell::new(QueryLimitSettings::default());
+
+    static INSERT_BATCHES: RefCell<AppliedBatches<()>> = RefCell::new(AppliedBatches::default());
+}
+
+#[cfg(target_arch = "wasm32")]
+fn caller() -> Principal {
+    ic_cdk::caller()
+}
+
+// Calls made by unit tests run outside of a canister, they are anonymous
+#[cfg(not(target_arch = "wasm32"))]
+fn caller() -> Principal {
+    Principal::anonymous()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+fn ensure_owner() -> Result<(), String> {
+    SETTINGS.with(|s| {
+        if s.borrow().owner == caller() {
+            Ok(())
+        } else {
+            Err("Only the owner of the canister can do this".to_string())
+        }
+    })
 }
 
 #[init]
@@ -77,55 +229,74 @@ thread_local! {
 fn init() {
     SETTINGS.with(|s| {
         let mut settings = s.borrow_mut();
-        settings.owner = ic_cdk::caller();
+        settings.owner = caller();
     });
+    alerts::start_timer();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    alerts::start_timer();
 }
 
 #[update]
 #[candid_method(update)]
 fn insert(measurement: String, entry: Entry) -> Result<(), String> {
-    TIME_DB.with(|m| {
-        let mut db = m.borrow_mut();
-        let timestamp = time() as u64;
-
-        let measurement = db.get_measurement(&measurement);
-        measurement.add_entry(timestamp, &entry.fields, &entry.tags);
-    });
+    let timestamp = now();
+    store_entries("insert", &measurement, &[Entry { timestamp, ..entry }]);
 
     Ok(())
 }
 
+/// Inserts the entries. When the caller numbers its batches with `sequence`, a retried batch
+/// isn't inserted twice but answered with the result of the first call.
 #[update]
 #[candid_method(update)]
-fn insert_bulk(measurement: String, entries: Vec<Entry>) -> Result<(), String> {
+fn insert_bulk(
+    measurement: String,
+    entries: Vec<Entry>,
+    sequence: Option<u64>,
+) -> Result<(), String> {
+    INSERT_BATCHES.with(|b| {
+        b.borrow_mut()
+            .apply_once(caller(), sequence, now(), || write_bulk(measurement, entries))
+    })
+}
+
+fn write_bulk(measurement: String, entries: Vec<Entry>) -> Result<(), String> {
+    store_entries("insert_bulk", &measurement, &entries);
+
+    Ok(())
+}
+
+/// Writes the entries to the measurement, records the write in the metrics and evaluates
+/// the alert rules of the measurement on the entries. All write paths go through here.
+fn store_entries(method: &'static str, measurement: &str, entries: &[Entry]) {
     TIME_DB.with(|m| {
         let mut db = m.borrow_mut();
-        let timestamp = time() as u64;
-
-        let measurement = db.get_measurement(&measurement);
+        let measurement = db.get_measurement(measurement);
         for entry in entries {
             measurement.add_entry(entry.timestamp, &entry.fields, &entry.tags);
         }
     });
-
-    Ok(())
+    metrics::record_insert(method, measurement, now());
+    alerts::evaluate(measurement, entries);
 }
 
 #[query]
 #[candid_method(query)]
 fn run_query(measurement: String, actions: Vec<Action>) -> Result<Vec<Entry>, String> {
+    metrics::record_query("run_query");
+    let limits = caller_query_limits();
     let items = TIME_DB.with(|m| {
         let mut db = m.borrow_mut();
         let measure = db.get_measurement(&measurement);
 
-        measure.apply(&actions)
+        measure.apply(&actions, &limits, None)
     });
 
     match items {
-        Ok(items) => match items {
-            Some(items) => Ok(items.eval()),
-            None => Err("Query failed to return values".to_string()),
-        },
+        Ok(items) => Ok(items.entries),
         Err(err) => {
             let msg = err.to_string();
             Err(format!(
@@ -136,6 +307,133 @@ fn run_query(measurement: String, actions: Vec<Action>) -> Result<Vec<Entry>, St
     }
 }
 
+/// Same as `run_query`, but reports when the result was cut short by the caller's query
+/// limits together with the cursor to continue from.
+#[query]
+#[candid_method(query)]
+fn run_query_paged(
+    measurement: String,
+    actions: Vec<Action>,
+    cursor: Option<u64>,
+) -> Result<QueryResult, QueryError> {
+    metrics::record_query("run_query_paged");
+    let limits = caller_query_limits();
+    TIME_DB.with(|m| {
+        let mut db = m.borrow_mut();
+        let measure = db.get_measurement(&measurement);
+
+        measure.apply(&actions, &limits, cursor)
+    })
+}
+
+/// Runs the query and returns its entries serialized in `format`, e.g. as an Arrow IPC
+/// stream with a typed column per tag and field. Exports fail rather than return a result
+/// truncated by the query limits.
+#[query]
+#[candid_method(query)]
+fn export_query(
+    measurement: String,
+    actions: Vec<Action>,
+    format: ExportFormat,
+) -> Result<ByteBuf, String> {
+    metrics::record_query("export_query");
+    let limits = caller_query_limits();
+    let result = TIME_DB.with(|m| {
+        let db = m.borrow();
+        match db.find_measurement(&measurement) {
+            Some(measure) => measure
+                .apply(&actions, &limits, None)
+                .map_err(|err| format!("Error occurred during processing of query: {}", err)),
+            None => Err(format!("Measurement '{}' not found", measurement)),
+        }
+    })?;
+    if result.truncated {
+        return Err("The query exceeds the query limits, export a shorter time range".to_string());
+    }
+
+    export::export(&result.entries, format).map(ByteBuf::from)
+}
+
+fn caller_query_limits() -> QueryLimits {
+    QUERY_LIMITS.with(|l| {
+        let limits = l.borrow();
+        limits
+            .callers
+            .get(&caller())
+            .unwrap_or(&limits.default)
+            .clone()
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_query_limits() -> QueryLimits {
+    caller_query_limits()
+}
+
+/// Sets the query limits of `caller`, or the default limits when no caller is given.
+/// Passing no limits removes the caller's own limits or restores the built in default.
+#[update]
+#[candid_method(update)]
+fn set_query_limits(caller: Option<Principal>, limits: Option<QueryLimits>) -> Result<(), String> {
+    ensure_owner()?;
+
+    QUERY_LIMITS.with(|l| {
+        let mut query_limits = l.borrow_mut();
+        match (caller, limits) {
+            (Some(caller), Some(limits)) => {
+                query_limits.callers.insert(caller, limits);
+            }
+            (Some(caller), None) => {
+                query_limits.callers.remove(&caller);
+            }
+            (None, limits) => query_limits.default = limits.unwrap_or_default(),
+        }
+    });
+
+    Ok(())
+}
+
+#[query]
+#[candid_method(query)]
+fn explain_query(measurement: String, actions: Vec<Action>) -> Result<String, String> {
+    TIME_DB.with(|m| {
+        let mut db = m.borrow_mut();
+        let measure = db.get_measurement(&measurement);
+
+        measure
+            .explain(&actions)
+            .map_err(|err| format!("Error occurred during planning of query: {}", err))
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_measurement_stats(measurement: String) -> MeasurementStats {
+    TIME_DB.with(|m| {
+        let mut db = m.borrow_mut();
+        db.get_measurement(&measurement).stats()
+    })
+}
+
+/// Returns the messages received from the gateway, oldest first. Only the owner and the
+/// authorized gateways can do this.
+#[query]
+#[candid_method(query)]
+fn get_in_messages(query: MessageQuery) -> Result<PagedResult, String> {
+    ensure_gateway()?;
+    IN_MESSAGES.with(|m| m.borrow().query(&query))
+}
+
+/// Returns the messages published for the gateway, oldest first. Only the owner and the
+/// authorized gateways can do this.
+#[query]
+#[candid_method(query)]
+fn get_out_messages(query: MessageQuery) -> Result<PagedResult, String> {
+    ensure_gateway()?;
+    OUT_MESSAGES.with(|m| m.borrow().query(&query))
+}
+
 #[query]
 #[candid_method(query)]
 fn get_settings() -> Result<Settings, String> {
@@ -145,7 +443,16 @@ fn get_settings() -> Result<Settings, String> {
     })
 }
 
+use crate::alerts::{Alert, AlertRule};
+#[cfg(feature = "benchmark")]
+use crate::benchmark::QueryBenchmark;
+use crate::http::HttpSettings;
 use crate::http_types::*;
+use crate::mqtt::{
+    GatewayCounters, GatewayInfo, GatewayMessage, GatewayStatus, IngestSummary, OutboundMessage,
+    PagedDeadLetters,
+};
+use crate::timedb::{Device, ReprocessResult, TransformRule, TransformRules};
 
 #[query(name = "__get_candid_interface_tmp_hack")]
 fn export_candid() -> String {
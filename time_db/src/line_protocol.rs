@@ -0,0 +1,265 @@
+use std::{collections::HashMap, str::FromStr};
+
+use candid::{CandidType, Deserialize};
+
+use crate::timedb::{Entry, Value};
+
+/// Unit of the timestamps in line protocol.
+#[derive(Clone, Copy, CandidType, Deserialize, Debug, Default, PartialEq)]
+pub enum Precision {
+    #[default]
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    pub fn nanos(&self) -> u64 {
+        match self {
+            Precision::Nanoseconds => 1,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(precision: &str) -> Result<Self, Self::Err> {
+        match precision {
+            "ns" => Ok(Precision::Nanoseconds),
+            "us" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            _ => Err(format!(
+                "Unknown precision '{}' (expected ns, us, ms or s)",
+                precision
+            )),
+        }
+    }
+}
+
+/// Parses InfluxDB line protocol into (measurement, entry) pairs, one per line.
+///
+/// Each line reads `measurement,tag=value field=value,field2=value timestamp`. Tags are
+/// strings, field values are floats, integers (`1i`), unsigned integers (`1u`), booleans
+/// or quoted strings. Lines without a timestamp are taken at `now` (nanoseconds), empty
+/// lines and comments starting with `#` are skipped. Any invalid line fails the whole
+/// text, with the line number in the error.
+pub fn parse(text: &str, precision: Precision, now: u64) -> Result<Vec<(String, Entry)>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            parse_line(line, precision, now).map_err(|err| format!("line {}: {}", number, err))
+        })
+        .collect()
+}
+
+fn parse_line(line: &str, precision: Precision, now: u64) -> Result<(String, Entry), String> {
+    let (series, rest) = split_once_unescaped(line, ' ', false)
+        .ok_or_else(|| "expected fields after the measurement".to_string())?;
+    let (fields, timestamp) = match split_once_unescaped(rest, ' ', true) {
+        Some((fields, timestamp)) => (fields, Some(timestamp.trim())),
+        None => (rest, None),
+    };
+
+    let mut series = split_unescaped(series, ',', false).into_iter();
+    let measurement = unescape(series.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err("missing measurement".to_string());
+    }
+
+    let mut tags = HashMap::new();
+    for tag in series {
+        let (name, value) = key_value(tag)?;
+        if value.is_empty() {
+            return Err(format!("tag '{}' has no value", name));
+        }
+        tags.insert(name, Value::String(unescape(value)));
+    }
+
+    let mut field_values = HashMap::new();
+    for field in split_unescaped(fields, ',', true) {
+        let (name, value) = key_value(field)?;
+        let value = field_value(value).map_err(|err| format!("field '{}': {}", name, err))?;
+        field_values.insert(name, value);
+    }
+
+    let timestamp = match timestamp {
+        None | Some("") => now,
+        Some(timestamp) => timestamp
+            .parse::<u64>()
+            .ok()
+            .and_then(|timestamp| timestamp.checked_mul(precision.nanos()))
+            .ok_or_else(|| format!("invalid timestamp '{}'", timestamp))?,
+    };
+
+    Ok((
+        measurement,
+        Entry {
+            timestamp,
+            fields: field_values,
+            tags,
+        },
+    ))
+}
+
+fn key_value(pair: &str) -> Result<(String, &str), String> {
+    match split_once_unescaped(pair, '=', false) {
+        Some((name, value)) if !name.is_empty() => Ok((unescape(name), value)),
+        _ => Err(format!("expected name=value, found '{}'", pair)),
+    }
+}
+
+fn field_value(value: &str) -> Result<Value, String> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let string = quoted
+            .strip_suffix('"')
+            .ok_or_else(|| "unterminated string".to_string())?;
+        return Ok(Value::String(
+            string.replace("\\\"", "\"").replace("\\\\", "\\"),
+        ));
+    }
+
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok(Value::Bool(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+
+    let invalid = || format!("invalid value '{}'", value);
+    if let Some(int) = value.strip_suffix('i') {
+        int.parse().map(Value::Int).map_err(|_| invalid())
+    } else if let Some(uint) = value.strip_suffix('u') {
+        uint.parse().map(Value::UInt).map_err(|_| invalid())
+    } else {
+        match value.parse::<f64>() {
+            Ok(float) if float.is_finite() => Ok(Value::Float(float as f32)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// Splits at the first `separator` not escaped with a backslash (nor quoted, with `quotes`)
+fn split_once_unescaped(text: &str, separator: char, quotes: bool) -> Option<(&str, &str)> {
+    separators(text, separator, quotes)
+        .next()
+        .map(|i| (&text[..i], &text[i + separator.len_utf8()..]))
+}
+
+fn split_unescaped(text: &str, separator: char, quotes: bool) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    for i in separators(text, separator, quotes) {
+        parts.push(&text[start..i]);
+        start = i + separator.len_utf8();
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn separators(text: &str, separator: char, quotes: bool) -> impl Iterator<Item = usize> + '_ {
+    let mut escaped = false;
+    let mut quoted = false;
+    text.char_indices().filter_map(move |(i, c)| {
+        if escaped {
+            escaped = false;
+            return None;
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            c if c == separator && !quoted => return Some(i),
+            _ => {}
+        }
+        None
+    })
+}
+
+// Measurements, tags and field names escape commas, equal signs and spaces
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(next @ (',' | '=' | ' '))) => {
+                unescaped.push(*next);
+                chars.next();
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = "# comment\n\
+            cpu,host=edge\\ 1,region=eu usage=0.5,cores=4i,free=2u,up=T,note=\"a \\\"b\\\", c\" 1700000000\n\
+            \n\
+            my\\,metric value=1 \r\n";
+
+        let points = parse(text, Precision::Seconds, 42).unwrap();
+        assert_eq!(points.len(), 2);
+
+        let (measurement, entry) = &points[0];
+        assert_eq!(measurement, "cpu");
+        assert_eq!(entry.timestamp, 1_700_000_000_000_000_000);
+        assert_eq!(
+            entry.tags.get("host"),
+            Some(&Value::String("edge 1".to_string()))
+        );
+        assert_eq!(entry.fields.get("usage"), Some(&Value::Float(0.5)));
+        assert_eq!(entry.fields.get("cores"), Some(&Value::Int(4)));
+        assert_eq!(entry.fields.get("free"), Some(&Value::UInt(2)));
+        assert_eq!(entry.fields.get("up"), Some(&Value::Bool(true)));
+        assert_eq!(
+            entry.fields.get("note"),
+            Some(&Value::String("a \"b\", c".to_string()))
+        );
+
+        let (measurement, entry) = &points[1];
+        assert_eq!(measurement, "my,metric");
+        assert_eq!(entry.timestamp, 42);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (text, error) in [
+            ("cpu", "line 1: expected fields after the measurement"),
+            (
+                "cpu,host usage=1",
+                "line 1: expected name=value, found 'host'",
+            ),
+            (
+                "cpu usage=1\ncpu usage=x",
+                "line 2: field 'usage': invalid value 'x'",
+            ),
+            (
+                "cpu note=\"open",
+                "line 1: field 'note': unterminated string",
+            ),
+            ("cpu usage=1 -5", "line 1: invalid timestamp '-5'"),
+            (
+                "cpu usage=nan",
+                "line 1: field 'usage': invalid value 'nan'",
+            ),
+        ] {
+            assert_eq!(
+                parse(text, Precision::Nanoseconds, 0).err().as_deref(),
+                Some(error),
+                "{}",
+                text
+            );
+        }
+    }
+}
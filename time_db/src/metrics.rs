@@ -0,0 +1,189 @@
+use std::{cell::RefCell, collections::BTreeMap, io};
+
+use ic_metrics_encoder::MetricsEncoder;
+use serde::Serialize;
+
+use crate::TIME_DB;
+
+#[cfg(target_arch = "wasm32")]
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Counters kept next to the database for the `/metrics` endpoint.
+///
+/// The IC discards state changes made by query calls, so calls of query methods are only
+/// counted when they run as (replicated) update calls.
+#[derive(Default)]
+pub struct Metrics {
+    inserts: BTreeMap<&'static str, u64>,
+    queries: BTreeMap<&'static str, u64>,
+    rejected_writes: BTreeMap<&'static str, u64>,
+    last_write: BTreeMap<String, u64>, // time of the last write per measurement
+}
+
+thread_local! {
+    static METRICS: RefCell<Metrics> = RefCell::new(Metrics::default());
+}
+
+/// Counts a call of `method` that wrote to `measurement` at `time`.
+pub fn record_insert(method: &'static str, measurement: &str, time: u64) {
+    METRICS.with(|m| {
+        let mut metrics = m.borrow_mut();
+        *metrics.inserts.entry(method).or_default() += 1;
+        metrics.last_write.insert(measurement.to_string(), time);
+    });
+}
+
+pub fn record_query(method: &'static str) {
+    METRICS.with(|m| *m.borrow_mut().queries.entry(method).or_default() += 1);
+}
+
+/// Counts entries `method` refused to write.
+pub fn record_rejected_writes(method: &'static str, count: u64) {
+    METRICS.with(|m| *m.borrow_mut().rejected_writes.entry(method).or_default() += count);
+}
+
+/// Writes the metrics in the Prometheus text format.
+pub fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> io::Result<()> {
+    w.encode_gauge(
+        "canister_heap_memory_bytes",
+        heap_memory_bytes() as f64,
+        "Size of the canister's heap memory in bytes.",
+    )?;
+    w.encode_gauge(
+        "canister_stable_memory_bytes",
+        stable_memory_bytes() as f64,
+        "Size of the canister's stable memory in bytes.",
+    )?;
+    w.encode_gauge(
+        "canister_cycles_balance",
+        cycles_balance() as f64,
+        "Cycles balance of the canister.",
+    )?;
+
+    TIME_DB.with(|m| -> io::Result<()> {
+        let db = m.borrow();
+        // Stats walk all series and chunks of a measurement, they are computed once
+        let stats: Vec<_> = db
+            .measurements()
+            .map(|(name, measurement)| (name, measurement.stats()))
+            .collect();
+
+        let mut points = w.gauge_vec("timedb_points", "Number of points per measurement.")?;
+        for (name, stats) in &stats {
+            points = points.value(&[("measurement", name)], stats.points as f64)?;
+        }
+
+        let mut series = w.gauge_vec("timedb_series", "Number of series per measurement.")?;
+        for (name, stats) in &stats {
+            series = series.value(&[("measurement", name)], stats.series as f64)?;
+        }
+
+        Ok(())
+    })?;
+
+    METRICS.with(|m| -> io::Result<()> {
+        let metrics = m.borrow();
+
+        let mut last_write = w.gauge_vec(
+            "timedb_last_write_timestamp_seconds",
+            "Time of the last write per measurement.",
+        )?;
+        for (measurement, time) in &metrics.last_write {
+            last_write = last_write.value(
+                &[("measurement", measurement)],
+                *time as f64 / 1_000_000_000.0,
+            )?;
+        }
+
+        let counters = [
+            (
+                "timedb_insert_calls_total",
+                "Number of calls writing points per method.",
+                &metrics.inserts,
+            ),
+            (
+                "timedb_query_calls_total",
+                "Number of queries per method.",
+                &metrics.queries,
+            ),
+            (
+                "timedb_rejected_writes_total",
+                "Number of entries rejected per method.",
+                &metrics.rejected_writes,
+            ),
+        ];
+        for (name, help, counts) in counters {
+            let mut counter = w.counter_vec(name, help)?;
+            for (method, count) in counts {
+                counter = counter.value(&[("method", method)], *count as f64)?;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[derive(Serialize)]
+pub struct Health {
+    status: &'static str,
+    version: &'static str,
+    time: u64,
+    measurements: u64,
+    points: u64,
+    heap_memory_bytes: u64,
+    stable_memory_bytes: u64,
+    cycles_balance: u128,
+}
+
+/// Status document served on `/health`.
+pub fn health() -> Health {
+    let (measurements, points) = TIME_DB.with(|m| {
+        let db = m.borrow();
+        db.measurements()
+            .fold((0, 0), |(measurements, points), (_, measurement)| {
+                (measurements + 1, points + measurement.stats().points)
+            })
+    });
+
+    Health {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+        time: crate::now(),
+        measurements,
+        points,
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: stable_memory_bytes(),
+        cycles_balance: cycles_balance(),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_memory_bytes() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+#[cfg(target_arch = "wasm32")]
+fn stable_memory_bytes() -> u64 {
+    ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE
+}
+
+#[cfg(target_arch = "wasm32")]
+fn cycles_balance() -> u128 {
+    ic_cdk::api::canister_balance128()
+}
+
+// Outside of a canister there is no memory or balance to report
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_memory_bytes() -> u64 {
+    0
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn stable_memory_bytes() -> u64 {
+    0
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn cycles_balance() -> u128 {
+    0
+}
@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+
+use candid::{CandidType, Deserialize};
+use serde_json::{Map, Number, Value as Json};
+
+use super::senml;
+use crate::line_protocol::{self, Precision};
+use crate::timedb::{parse_rfc3339, Entry, Value};
+
+/// Interpretation of the payloads of the topics of a rule.
+#[derive(Clone, CandidType, Deserialize, Debug, Default, PartialEq)]
+pub enum Decoder {
+    #[default]
+    Raw, // a single number, boolean or string stored in the field of the rule
+    Json(JsonDecoder),
+    Cbor(JsonDecoder), // maps and arrays are selected the same way as JSON
+    LineProtocol(Option<Precision>), // the measurement of a line becomes the `_measurement` tag
+    SenML,             // JSON records of RFC 8428, one entry per record
+}
+
+/// Selects the records, fields, tags and time of JSON documents. Paths read like
+/// `$.sensor.id`, `$.readings[0]` or `$['odd key']`, the leading `$` is optional.
+#[derive(Clone, CandidType, Deserialize, Debug, Default, PartialEq)]
+pub struct JsonDecoder {
+    pub records: Option<String>, // an array gives one entry per element, the document by default
+    pub fields: Vec<JsonSelector>, // all scalar values when empty, nested keys joined with `.`
+    pub tags: Vec<JsonSelector>,
+    pub timestamp: Option<String>, // number in `precision` (ns by default) or RFC 3339
+    pub precision: Option<Precision>,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub struct JsonSelector {
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl Decoder {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Decoder::Json(json) | Decoder::Cbor(json) => json.validate(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Entries of a payload, values without a name of their own go to `field`. Times
+    /// missing from the payload are taken from `timestamp`, the time the message arrived.
+    pub fn decode(
+        &self,
+        payload: &[u8],
+        field: &str,
+        timestamp: u64,
+    ) -> Result<Vec<Entry>, String> {
+        // an empty payload clears the retained message of a topic
+        if payload.iter().all(u8::is_ascii_whitespace) {
+            return Ok(vec![]);
+        }
+
+        match self {
+            Decoder::Raw => {
+                let fields = HashMap::from([(field.to_string(), raw_value(text(payload)?))]);
+                Ok(vec![Entry {
+                    timestamp,
+                    fields,
+                    tags: HashMap::new(),
+                }])
+            }
+            Decoder::Json(json) => {
+                let document = serde_json::from_slice(payload)
+                    .map_err(|err| format!("invalid JSON: {}", err))?;
+                json.decode(&document, timestamp)
+            }
+            Decoder::Cbor(json) => {
+                let document: ciborium::Value = ciborium::de::from_reader(payload)
+                    .map_err(|err| format!("invalid CBOR: {}", err))?;
+                json.decode(&cbor_to_json(document), timestamp)
+            }
+            Decoder::LineProtocol(precision) => {
+                let points =
+                    line_protocol::parse(text(payload)?, precision.unwrap_or_default(), timestamp)?;
+                Ok(points
+                    .into_iter()
+                    .map(|(measurement, mut entry)| {
+                        entry
+                            .tags
+                            .insert("_measurement".to_string(), Value::String(measurement));
+                        entry
+                    })
+                    .collect())
+            }
+            Decoder::SenML => senml::decode(payload, field, timestamp),
+        }
+    }
+}
+
+impl JsonDecoder {
+    fn validate(&self) -> Result<(), String> {
+        let paths = self
+            .fields
+            .iter()
+            .chain(&self.tags)
+            .map(|selector| &selector.path);
+        for path in paths.chain(&self.records).chain(&self.timestamp) {
+            parse_path(path)?;
+        }
+        match self
+            .fields
+            .iter()
+            .chain(&self.tags)
+            .find(|selector| selector.name.is_empty())
+        {
+            Some(selector) => Err(format!("No name for the value at '{}'", selector.path)),
+            None => Ok(()),
+        }
+    }
+
+    fn decode(&self, document: &Json, timestamp: u64) -> Result<Vec<Entry>, String> {
+        let records = match &self.records {
+            Some(path) => select(document, &parse_path(path)?)
+                .ok_or_else(|| format!("no records at '{}'", path))?,
+            None => document,
+        };
+
+        match records {
+            Json::Array(records) => records
+                .iter()
+                .enumerate()
+                .map(|(i, record)| {
+                    self.entry(record, timestamp)
+                        .map_err(|err| format!("record {}: {}", i, err))
+                })
+                .collect(),
+            record => self.entry(record, timestamp).map(|entry| vec![entry]),
+        }
+    }
+
+    fn entry(&self, record: &Json, timestamp: u64) -> Result<Entry, String> {
+        let mut tags = HashMap::new();
+        let mut used = vec![];
+        for selector in &self.tags {
+            let path = parse_path(&selector.path)?;
+            let tag = match select(record, &path) {
+                Some(Json::String(tag)) => tag.clone(),
+                Some(value @ (Json::Number(_) | Json::Bool(_))) => value.to_string(),
+                _ => continue,
+            };
+            tags.insert(selector.name.clone(), Value::String(tag));
+            used.push(path);
+        }
+
+        let mut time = timestamp;
+        if let Some(path) = &self.timestamp {
+            let path = parse_path(path)?;
+            if let Some(value) = select(record, &path) {
+                time = json_timestamp(value, self.precision.unwrap_or_default())?;
+            }
+            used.push(path);
+        }
+
+        let mut fields = HashMap::new();
+        if self.fields.is_empty() {
+            flatten(record, &mut vec![], &used, &mut fields);
+        }
+        for selector in &self.fields {
+            if let Some(value) = select(record, &parse_path(&selector.path)?).and_then(json_value) {
+                fields.insert(selector.name.clone(), value);
+            }
+        }
+        if fields.is_empty() {
+            return Err("no fields".to_string());
+        }
+
+        Ok(Entry {
+            timestamp: time,
+            fields,
+            tags,
+        })
+    }
+}
+
+fn text(payload: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(payload).map_err(|_| "payload is not UTF-8".to_string())
+}
+
+// Numbers and booleans are stored as such, anything else as a string
+fn raw_value(payload: &str) -> Value {
+    match payload.trim() {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        payload => match (payload.parse::<i128>(), payload.parse::<f64>()) {
+            (Ok(int), _) => Value::Int(int),
+            (_, Ok(float)) if float.is_finite() => Value::from_f64(float),
+            _ => Value::String(payload.to_string()),
+        },
+    }
+}
+
+fn json_value(json: &Json) -> Option<Value> {
+    match json {
+        Json::Bool(value) => Some(Value::Bool(*value)),
+        Json::Number(number) => Some(match (number.as_i64(), number.as_u64()) {
+            (Some(int), _) => Value::Int(int as i128),
+            (_, Some(uint)) => Value::UInt(uint as u128),
+            _ => Value::from_f64(number.as_f64()?),
+        }),
+        Json::String(value) => Some(Value::String(value.clone())),
+        _ => None,
+    }
+}
+
+fn json_timestamp(json: &Json, precision: Precision) -> Result<u64, String> {
+    let invalid = || format!("invalid timestamp {}", json);
+    match json {
+        Json::String(time) => parse_rfc3339(time),
+        Json::Number(number) => {
+            let nanos = precision.nanos() as f64;
+            match (number.as_u64(), number.as_f64()) {
+                (Some(time), _) => time.checked_mul(precision.nanos()).ok_or_else(invalid),
+                (_, Some(time)) if time >= 0.0 && time * nanos < u64::MAX as f64 => {
+                    Ok((time * nanos) as u64)
+                }
+                _ => Err(invalid()),
+            }
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// Every scalar value of the record, except those already used for tags or the time
+fn flatten(
+    json: &Json,
+    path: &mut Vec<Segment>,
+    used: &[Vec<Segment>],
+    fields: &mut HashMap<String, Value>,
+) {
+    match json {
+        Json::Object(members) => {
+            for (key, value) in members {
+                path.push(Segment::Key(key.clone()));
+                flatten(value, path, used, fields);
+                path.pop();
+            }
+        }
+        json if !path.is_empty() && !used.contains(path) => {
+            if let Some(value) = json_value(json) {
+                let name = path
+                    .iter()
+                    .map(|segment| match segment {
+                        Segment::Key(key) => key.clone(),
+                        Segment::Index(index) => index.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(".");
+                fields.insert(name, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn select<'a>(json: &'a Json, path: &[Segment]) -> Option<&'a Json> {
+    path.iter().try_fold(json, |json, segment| match segment {
+        Segment::Key(key) => json.get(key),
+        Segment::Index(index) => json.get(index),
+    })
+}
+
+fn parse_path(path: &str) -> Result<Vec<Segment>, String> {
+    let invalid = || format!("Invalid path '{}'", path);
+    let relative;
+    let mut rest = match path.strip_prefix('$') {
+        Some(rest) => rest,
+        None if path.is_empty() || path.starts_with(['.', '[']) => path,
+        // `sensor.id` reads as `$.sensor.id`
+        None => {
+            relative = format!(".{}", path);
+            &relative
+        }
+    };
+    let mut segments = vec![];
+
+    while !rest.is_empty() {
+        if let Some(member) = rest.strip_prefix('.') {
+            let end = member.find(['.', '[']).unwrap_or(member.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            segments.push(Segment::Key(member[..end].to_string()));
+            rest = &member[end..];
+        } else if let Some(bracket) = rest.strip_prefix('[') {
+            let end = bracket.find(']').ok_or_else(invalid)?;
+            let inner = &bracket[..end];
+            let quoted = inner
+                .strip_prefix('\'')
+                .and_then(|key| key.strip_suffix('\''))
+                .or_else(|| {
+                    inner
+                        .strip_prefix('"')
+                        .and_then(|key| key.strip_suffix('"'))
+                });
+            segments.push(match quoted {
+                Some(key) => Segment::Key(key.to_string()),
+                None => Segment::Index(inner.parse().map_err(|_| invalid())?),
+            });
+            rest = &bracket[end + 1..];
+        } else {
+            return Err(invalid());
+        }
+    }
+
+    Ok(segments)
+}
+
+fn cbor_to_json(cbor: ciborium::Value) -> Json {
+    use ciborium::Value as Cbor;
+
+    match cbor {
+        Cbor::Integer(int) => {
+            let int = i128::from(int);
+            i64::try_from(int)
+                .map(Json::from)
+                .or_else(|_| u64::try_from(int).map(Json::from))
+                .unwrap_or(Json::Null)
+        }
+        Cbor::Float(float) => Number::from_f64(float).map_or(Json::Null, Json::Number),
+        Cbor::Text(text) => Json::String(text),
+        Cbor::Bool(value) => Json::Bool(value),
+        Cbor::Tag(_, value) => cbor_to_json(*value),
+        Cbor::Array(values) => Json::Array(values.into_iter().map(cbor_to_json).collect()),
+        Cbor::Map(members) => Json::Object(
+            members
+                .into_iter()
+                .filter_map(|(key, value)| {
+                    let key = match key {
+                        Cbor::Text(key) => key,
+                        Cbor::Integer(key) => i128::from(key).to_string(),
+                        _ => return None,
+                    };
+                    Some((key, cbor_to_json(value)))
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        // byte strings have no JSON counterpart
+        _ => Json::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector(name: &str, path: &str) -> JsonSelector {
+        JsonSelector {
+            name: name.to_string(),
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_path() {
+        let key = |key: &str| Segment::Key(key.to_string());
+        assert_eq!(parse_path("$").unwrap(), vec![]);
+        assert_eq!(
+            parse_path("$.sensor.id").unwrap(),
+            vec![key("sensor"), key("id")]
+        );
+        assert_eq!(
+            parse_path("sensor.id").unwrap(),
+            parse_path("$.sensor.id").unwrap()
+        );
+        assert_eq!(
+            parse_path("$.readings[1]['odd key']").unwrap(),
+            vec![key("readings"), Segment::Index(1), key("odd key")]
+        );
+        assert!(parse_path("$..id").is_err());
+        assert!(parse_path("$.readings[x]").is_err());
+        assert!(parse_path("$.readings[1").is_err());
+    }
+
+    #[test]
+    fn test_decode_json() {
+        let decoder = Decoder::Json(JsonDecoder {
+            records: Some("$.readings".to_string()),
+            fields: vec![],
+            tags: vec![selector("sensor", "$.sensor.id")],
+            timestamp: Some("$.time".to_string()),
+            precision: Some(Precision::Seconds),
+        });
+        let payload = br#"{"readings": [
+            {"sensor": {"id": "s1", "room": "hall"}, "time": 1700000000, "temp": 21.5, "ok": true},
+            {"sensor": {"id": 2}, "time": "2023-11-14T22:13:20.5Z", "temp": -3}
+        ]}"#;
+
+        let entries = decoder.decode(payload, "value", 42).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].timestamp, 1_700_000_000_000_000_000);
+        assert_eq!(
+            entries[0].tags,
+            HashMap::from([("sensor".to_string(), Value::String("s1".to_string()))])
+        );
+        assert_eq!(
+            entries[0].fields,
+            HashMap::from([
+                ("sensor.room".to_string(), Value::String("hall".to_string())),
+                ("temp".to_string(), Value::Float(21.5)),
+                ("ok".to_string(), Value::Bool(true)),
+            ])
+        );
+        assert_eq!(entries[1].timestamp, 1_700_000_000_500_000_000);
+        assert_eq!(
+            entries[1].tags.get("sensor"),
+            Some(&Value::String("2".to_string()))
+        );
+        assert_eq!(entries[1].fields.get("temp"), Some(&Value::Int(-3)));
+
+        let decoder = Decoder::Json(JsonDecoder {
+            fields: vec![selector("temp", "$.data[0]")],
+            ..JsonDecoder::default()
+        });
+        let entries = decoder
+            .decode(br#"{"data": [1.5, 2]}"#, "value", 42)
+            .unwrap();
+        assert_eq!(entries[0].timestamp, 42);
+        assert_eq!(
+            entries[0].fields,
+            HashMap::from([("temp".to_string(), Value::Float(1.5))])
+        );
+        let entries = decoder
+            .decode(br#"{"data": [16777217.0]}"#, "value", 42)
+            .unwrap();
+        assert_eq!(
+            entries[0].fields,
+            HashMap::from([("temp".to_string(), Value::Int(16_777_217))])
+        );
+
+        assert_eq!(
+            decoder
+                .decode(br#"{"other": 1}"#, "value", 42)
+                .err()
+                .as_deref(),
+            Some("no fields")
+        );
+        assert!(decoder.decode(b"{", "value", 42).is_err());
+    }
+
+    #[test]
+    fn test_decode_other_formats() {
+        // {"temp": 21.5, "id": "s1"}
+        let cbor = [
+            0xa2, 0x64, b't', b'e', b'm', b'p', 0xfb, 0x40, 0x35, 0x80, 0, 0, 0, 0, 0, 0x62, b'i',
+            b'd', 0x62, b's', b'1',
+        ];
+        let decoder = Decoder::Cbor(JsonDecoder {
+            tags: vec![selector("sensor", "id")],
+            ..JsonDecoder::default()
+        });
+        let entries = decoder.decode(&cbor, "value", 42).unwrap();
+        assert_eq!(
+            entries[0].fields,
+            HashMap::from([("temp".to_string(), Value::Float(21.5))])
+        );
+        assert_eq!(
+            entries[0].tags,
+            HashMap::from([("sensor".to_string(), Value::String("s1".to_string()))])
+        );
+
+        let entries = Decoder::LineProtocol(Some(Precision::Seconds))
+            .decode(b"cpu,host=a usage=0.5 10\nmem used=3i", "value", 42)
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].timestamp, 10_000_000_000);
+        assert_eq!(
+            entries[0].tags.get("_measurement"),
+            Some(&Value::String("cpu".to_string()))
+        );
+        assert_eq!(entries[1].timestamp, 42);
+
+        let entries = Decoder::Raw.decode(b" 21.5\n", "temp", 42).unwrap();
+        assert_eq!(
+            entries[0].fields,
+            HashMap::from([("temp".to_string(), Value::Float(21.5))])
+        );
+        let entries = Decoder::Raw.decode(b"16777217.0", "temp", 42).unwrap();
+        assert_eq!(entries[0].fields.get("temp"), Some(&Value::Int(16_777_217)));
+        assert!(Decoder::Raw.decode(b"", "temp", 42).unwrap().is_empty());
+        assert!(Decoder::Raw.decode(&[0xff, 0xfe], "temp", 42).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+
+use crate::timedb::{Entry, Value};
+use crate::{caller, ensure_owner, SETTINGS};
+
+// Measurement the heartbeats are written to
+const GATEWAYS_MEASUREMENT: &str = "_gateways";
+
+// A gateway is offline when it missed this many polling intervals, but at least a minute
+const MISSED_INTERVALS: u64 = 3;
+const MIN_OFFLINE_AFTER: u64 = 60;
+
+thread_local! {
+    static GATEWAYS: RefCell<BTreeMap<Principal, Gateway>> = const { RefCell::new(BTreeMap::new()) };
+    static AUTHORIZED: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// What a gateway tells about itself when it registers.
+#[derive(Clone, CandidType, Deserialize, Debug, Default, PartialEq)]
+pub struct GatewayInfo {
+    pub site: String,
+    pub version: String,
+    pub broker: String, // address of the MQTT broker the gateway is connected to
+}
+
+/// Counters of a gateway since it started, sent with every heartbeat.
+#[derive(Clone, CandidType, Deserialize, Debug, Default, PartialEq)]
+pub struct GatewayCounters {
+    pub received: u64,  // messages received from the broker
+    pub forwarded: u64, // messages sent to the canister
+    pub published: u64, // outbound messages published to the broker
+    pub errors: u64,
+}
+
+#[derive(Clone, Debug)]
+struct Gateway {
+    info: GatewayInfo,
+    registered_at: u64,
+    last_seen: Option<u64>,
+    heartbeats: u64,
+    counters: GatewayCounters,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct GatewayStatus {
+    pub principal: Principal,
+    pub info: GatewayInfo,
+    pub registered_at: u64,
+    pub last_seen: Option<u64>,
+    pub heartbeats: u64,
+    pub counters: GatewayCounters,
+    pub online: bool,
+}
+
+/// Registers the calling gateway, or updates the info of a registered one. Only the owner
+/// and the authorized gateways can register, anonymously never.
+#[update]
+#[candid_method(update)]
+fn register_gateway(info: GatewayInfo) -> Result<(), String> {
+    let principal = caller();
+    if principal == Principal::anonymous() {
+        return Err("Gateways can't register anonymously".to_string());
+    }
+    ensure_gateway()?;
+    register(principal, info, crate::now());
+    Ok(())
+}
+
+fn register(principal: Principal, info: GatewayInfo, now: u64) {
+    GATEWAYS.with(|g| {
+        let mut gateways = g.borrow_mut();
+        match gateways.get_mut(&principal) {
+            Some(gateway) => gateway.info = info,
+            None => {
+                gateways.insert(
+                    principal,
+                    Gateway {
+                        info,
+                        registered_at: now,
+                        last_seen: None,
+                        heartbeats: 0,
+                        counters: GatewayCounters::default(),
+                    },
+                );
+            }
+        }
+    });
+}
+
+/// Records that the calling gateway is alive, with its counters. The counters are also
+/// written to the `_gateways` measurement, tagged with the gateway and its site.
+#[update]
+#[candid_method(update)]
+fn heartbeat(counters: GatewayCounters) -> Result<(), String> {
+    record_heartbeat(caller(), counters, crate::now())
+}
+
+/// Lists the registered gateways, a gateway is online when its last heartbeat is no older
+/// than three polling intervals (and at least a minute).
+#[query]
+#[candid_method(query)]
+fn list_gateways() -> Vec<GatewayStatus> {
+    let now = crate::now();
+    let offline_after = SETTINGS
+        .with(|s| s.borrow().interval)
+        .saturating_mul(MISSED_INTERVALS)
+        .max(MIN_OFFLINE_AFTER)
+        .saturating_mul(1_000_000_000);
+
+    GATEWAYS.with(|g| {
+        g.borrow()
+            .iter()
+            .map(|(principal, gateway)| GatewayStatus {
+                principal: *principal,
+                info: gateway.info.clone(),
+                registered_at: gateway.registered_at,
+                last_seen: gateway.last_seen,
+                heartbeats: gateway.heartbeats,
+                counters: gateway.counters.clone(),
+                online: gateway
+                    .last_seen
+                    .is_some_and(|last_seen| now.saturating_sub(last_seen) <= offline_after),
+            })
+            .collect()
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_gateway(principal: Principal) -> Result<(), String> {
+    ensure_owner()?;
+    match GATEWAYS.with(|g| g.borrow_mut().remove(&principal)) {
+        Some(_) => Ok(()),
+        None => Err(format!("Gateway {} is not registered", principal)),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn get_authorized_gateways() -> Vec<Principal> {
+    AUTHORIZED.with(|a| a.borrow().iter().cloned().collect())
+}
+
+/// Replaces the principals allowed to act as gateways besides the owner.
+#[update]
+#[candid_method(update)]
+fn set_authorized_gateways(gateways: Vec<Principal>) -> Result<(), String> {
+    ensure_owner()?;
+    AUTHORIZED.with(|a| *a.borrow_mut() = gateways.into_iter().collect());
+    Ok(())
+}
+
+pub fn ensure_gateway() -> Result<(), String> {
+    let caller = caller();
+    let owner = SETTINGS.with(|s| s.borrow().owner);
+    if caller == owner || AUTHORIZED.with(|a| a.borrow().contains(&caller)) {
+        Ok(())
+    } else {
+        Err("Only the owner and the authorized gateways of the canister can do this".to_string())
+    }
+}
+
+fn record_heartbeat(
+    principal: Principal,
+    counters: GatewayCounters,
+    now: u64,
+) -> Result<(), String> {
+    let site = GATEWAYS.with(|g| {
+        let mut gateways = g.borrow_mut();
+        let gateway = gateways
+            .get_mut(&principal)
+            .ok_or_else(|| format!("Gateway {} is not registered", principal))?;
+        gateway.last_seen = Some(now);
+        gateway.heartbeats += 1;
+        gateway.counters = counters.clone();
+        Ok::<_, String>(gateway.info.site.clone())
+    })?;
+
+    let tags = HashMap::from([
+        ("gateway".to_string(), Value::String(principal.to_text())),
+        ("site".to_string(), Value::String(site)),
+    ]);
+    let fields: HashMap<String, Value> = [
+        ("received", counters.received),
+        ("forwarded", counters.forwarded),
+        ("published", counters.published),
+        ("errors", counters.errors),
+    ]
+    .into_iter()
+    .map(|(name, count)| (name.to_string(), Value::UInt(count as u128)))
+    .collect();
+    let entry = Entry {
+        timestamp: now,
+        fields,
+        tags,
+    };
+    crate::store_entries("heartbeat", GATEWAYS_MEASUREMENT, &[entry]);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timedb::{Action, QueryLimits};
+    use crate::TIME_DB;
+
+    #[test]
+    fn test_gateways() {
+        let counters = GatewayCounters {
+            received: 10,
+            forwarded: 9,
+            published: 2,
+            errors: 1,
+        };
+        assert!(heartbeat(counters.clone()).is_err());
+
+        let info = GatewayInfo {
+            site: "plant-1".to_string(),
+            version: "0.3.0".to_string(),
+            broker: "mqtt://broker:1883".to_string(),
+        };
+        // unit tests call anonymously
+        assert!(register_gateway(info.clone()).is_err());
+        assert!(list_gateways().is_empty());
+
+        let principal = Principal::management_canister();
+        register(principal, info.clone(), crate::now());
+        let gateways = list_gateways();
+        assert_eq!(gateways.len(), 1);
+        assert_eq!(gateways[0].info, info);
+        assert!(!gateways[0].online);
+
+        record_heartbeat(principal, counters.clone(), crate::now()).unwrap();
+        let gateways = list_gateways();
+        assert!(gateways[0].online);
+        assert_eq!(gateways[0].heartbeats, 1);
+        assert_eq!(gateways[0].counters, counters);
+
+        // missed more than a minute of heartbeats
+        record_heartbeat(principal, counters, crate::now() - 61_000_000_000).unwrap();
+        assert!(!list_gateways()[0].online);
+
+        TIME_DB.with(|m| {
+            let db = m.borrow();
+            let entries = db
+                .find_measurement(GATEWAYS_MEASUREMENT)
+                .unwrap()
+                .apply(&[Action::Range(0, None)], &QueryLimits::default(), None)
+                .unwrap()
+                .entries;
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].fields.get("received"), Some(&Value::UInt(10)));
+            assert_eq!(
+                entries[0].tags.get("site"),
+                Some(&Value::String("plant-1".to_string()))
+            );
+        });
+
+        assert!(remove_gateway(principal).is_ok());
+        assert!(list_gateways().is_empty());
+        assert!(remove_gateway(principal).is_err());
+    }
+
+    #[test]
+    fn test_alerts_on_heartbeats() {
+        use crate::alerts::{get_alerts, set_alert_rules, AlertCondition, AlertRule, Severity};
+        use crate::timedb::Expression;
+
+        let failing = AlertRule {
+            name: "failing".to_string(),
+            measurement: GATEWAYS_MEASUREMENT.to_string(),
+            condition: AlertCondition::Threshold(Expression::Gt(
+                "errors".to_string(),
+                Value::UInt(5),
+            )),
+            for_duration: None,
+            severity: Severity::Warning,
+            command: None,
+        };
+        set_alert_rules(vec![failing]).unwrap();
+
+        let principal = Principal::management_canister();
+        register(principal, GatewayInfo::default(), crate::now());
+        let counters = GatewayCounters {
+            errors: 6,
+            ..GatewayCounters::default()
+        };
+        record_heartbeat(principal, counters, crate::now()).unwrap();
+
+        let alerts = get_alerts();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].rule, "failing");
+        assert!(alerts[0]
+            .tags
+            .contains(&("gateway".to_string(), principal.to_text())));
+    }
+
+    #[test]
+    fn test_authorized_gateways() {
+        assert!(ensure_gateway().is_ok());
+
+        SETTINGS.with(|s| s.borrow_mut().owner = Principal::management_canister());
+        assert!(ensure_gateway().is_err());
+        assert!(set_authorized_gateways(vec![Principal::anonymous()]).is_err());
+
+        AUTHORIZED.with(|a| a.borrow_mut().insert(Principal::anonymous()));
+        assert!(ensure_gateway().is_ok());
+        assert_eq!(get_authorized_gateways(), vec![Principal::anonymous()]);
+    }
+}
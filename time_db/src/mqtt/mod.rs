@@ -0,0 +1,499 @@
+mod decoder;
+mod gateways;
+mod outbound;
+mod rules;
+mod senml;
+mod settings;
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use serde_bytes::ByteBuf;
+
+pub use gateways::{ensure_gateway, GatewayCounters, GatewayInfo, GatewayStatus};
+pub use outbound::{enqueue, OutboundMessage};
+pub use rules::{topic_matches, validate_filter, TopicRule};
+pub use settings::{retention_cutoff, Subscription};
+
+use crate::batches::AppliedBatches;
+use crate::timedb::Entry;
+use crate::{caller, ensure_owner, metrics, Message, IN_MESSAGES, SETTINGS};
+
+// The oldest dead letters are dropped beyond this
+const MAX_DEAD_LETTERS: usize = 1000;
+
+thread_local! {
+    static DEAD_LETTERS: RefCell<DeadLetterStore> = RefCell::new(DeadLetterStore::default());
+    static INGEST_BATCHES: RefCell<AppliedBatches<IngestSummary>> =
+        RefCell::new(AppliedBatches::default());
+}
+
+/// Message relayed by the gateway from one of the subscribed MQTT topics.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct GatewayMessage {
+    pub topic: String,
+    pub payload: ByteBuf,
+    pub timestamp: u64, // nanoseconds, 0 when the gateway didn't record the time of arrival
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, Default, PartialEq)]
+pub struct IngestSummary {
+    pub messages: u64, // messages stored in the inbox
+    pub routed: u64,   // entries written to a measurement
+    pub failed: u64,   // messages moved to the dead letters
+}
+
+/// Message the decoder of its topic rule failed on.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct DeadLetter {
+    pub index: u64,
+    pub topic: String,
+    pub payload: ByteBuf,
+    pub timestamp: u64,
+    pub error: String,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct PagedDeadLetters {
+    skip: u64,
+    limit: u64,
+    total: u64,
+    data: Vec<DeadLetter>,
+}
+
+#[derive(Default)]
+struct DeadLetterStore {
+    letters: VecDeque<DeadLetter>,
+    next_index: u64,
+}
+
+impl DeadLetterStore {
+    fn add(&mut self, topic: String, payload: ByteBuf, timestamp: u64, error: String) {
+        if self.letters.len() == MAX_DEAD_LETTERS {
+            self.letters.pop_front();
+        }
+        self.letters.push_back(DeadLetter {
+            index: self.next_index,
+            topic,
+            payload,
+            timestamp,
+            error,
+        });
+        self.next_index += 1;
+    }
+}
+
+/// Stores a message received by the gateway and writes it to the measurement of the first
+/// topic rule matching its topic. Only the owner and the authorized gateways can do this.
+#[update]
+#[candid_method(update)]
+fn on_message(topic: String, payload: ByteBuf, timestamp: u64) -> Result<IngestSummary, String> {
+    ensure_gateway()?;
+    ingest(
+        "on_message",
+        vec![GatewayMessage {
+            topic,
+            payload,
+            timestamp,
+        }],
+        crate::now(),
+    )
+}
+
+/// Same as `on_message` for a batch of messages, nothing is stored when one of them is invalid.
+/// A retried batch with the same `sequence` isn't stored twice but answered with the summary
+/// of the first call.
+#[update]
+#[candid_method(update)]
+fn on_messages(
+    messages: Vec<GatewayMessage>,
+    sequence: Option<u64>,
+) -> Result<IngestSummary, String> {
+    ensure_gateway()?;
+    ingest_batch(caller(), messages, sequence, crate::now())
+}
+
+/// Returns the messages that failed to decode, oldest first. Only the latest 1000 are kept.
+#[query]
+#[candid_method(query)]
+fn get_dead_letters(skip: u64, limit: u64) -> PagedDeadLetters {
+    DEAD_LETTERS.with(|d| {
+        let store = d.borrow();
+        PagedDeadLetters {
+            skip,
+            limit,
+            total: store.letters.len() as u64,
+            data: store
+                .letters
+                .iter()
+                .skip(skip as usize)
+                .take(limit as usize)
+                .cloned()
+                .collect(),
+        }
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn clear_dead_letters() -> Result<(), String> {
+    ensure_owner()?;
+    DEAD_LETTERS.with(|d| d.borrow_mut().letters.clear());
+    Ok(())
+}
+
+fn ingest_batch(
+    caller: Principal,
+    messages: Vec<GatewayMessage>,
+    sequence: Option<u64>,
+    time: u64,
+) -> Result<IngestSummary, String> {
+    INGEST_BATCHES.with(|b| {
+        b.borrow_mut().apply_once(caller, sequence, time, || {
+            ingest("on_messages", messages, time)
+        })
+    })
+}
+
+// Messages without a timestamp take `time`, the time they arrived at
+fn ingest(
+    method: &'static str,
+    messages: Vec<GatewayMessage>,
+    time: u64,
+) -> Result<IngestSummary, String> {
+    if let Some(message) = messages
+        .iter()
+        .find(|message| message.topic.is_empty() || message.topic.contains(['+', '#']))
+    {
+        metrics::record_rejected_writes(method, messages.len() as u64);
+        return Err(format!("Invalid topic '{}'", message.topic));
+    }
+
+    let batch_size = SETTINGS.with(|s| s.borrow().inbound_batch_size);
+    if messages.len() as u64 > batch_size {
+        metrics::record_rejected_writes(method, messages.len() as u64);
+        return Err(format!(
+            "{} messages exceed the batch size of {}",
+            messages.len(),
+            batch_size
+        ));
+    }
+
+    let mut summary = IngestSummary::default();
+    let mut measurements: BTreeMap<String, Vec<Entry>> = BTreeMap::new();
+    SETTINGS.with(|s| {
+        let rules = &s.borrow().rules;
+        for message in messages {
+            let timestamp = match message.timestamp {
+                0 => time,
+                timestamp => timestamp,
+            };
+
+            if let Some((rule, captures)) = rules::route(rules, &message.topic) {
+                match rule
+                    .decoder()
+                    .decode(&message.payload, rule.field(), timestamp)
+                {
+                    Ok(entries) if entries.is_empty() => {}
+                    Ok(entries) => {
+                        let topic_tags = rule.tags(&captures);
+                        let routed = measurements.entry(rule.measurement.clone()).or_default();
+                        for mut entry in entries {
+                            entry.tags.extend(topic_tags.clone());
+                            routed.push(entry);
+                            summary.routed += 1;
+                        }
+                    }
+                    Err(error) => {
+                        DEAD_LETTERS.with(|d| {
+                            d.borrow_mut().add(
+                                message.topic.clone(),
+                                message.payload.clone(),
+                                timestamp,
+                                error,
+                            )
+                        });
+                        summary.failed += 1;
+                    }
+                }
+            }
+
+            IN_MESSAGES.with(|m| {
+                m.borrow_mut().add_message(&Message {
+                    index: 0,
+                    topic: message.topic,
+                    message: message.payload,
+                    timestamp,
+                    received_at: time,
+                })
+            });
+            summary.messages += 1;
+        }
+    });
+
+    if let Some(cutoff) = retention_cutoff(time) {
+        IN_MESSAGES.with(|m| m.borrow_mut().remove_before(cutoff));
+    }
+
+    for (measurement, entries) in &measurements {
+        crate::store_entries(method, measurement, entries);
+    }
+    if summary.failed > 0 {
+        metrics::record_rejected_writes(method, summary.failed);
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decoder::{Decoder, JsonDecoder};
+    use super::*;
+    use crate::timedb::{Action, QueryLimits, Value};
+    use crate::{get_in_messages, get_out_messages, MessageQuery, TIME_DB};
+
+    fn message(topic: &str, payload: &str, timestamp: u64) -> GatewayMessage {
+        GatewayMessage {
+            topic: topic.to_string(),
+            payload: ByteBuf::from(payload.as_bytes()),
+            timestamp,
+        }
+    }
+
+    fn set_rules(rules: Vec<TopicRule>) {
+        SETTINGS.with(|s| s.borrow_mut().rules = rules);
+    }
+
+    #[test]
+    fn test_on_messages() {
+        set_rules(vec![
+            TopicRule {
+                topic: "sensors/+/temp".to_string(),
+                measurement: "temp".to_string(),
+                tags: vec!["sensor".to_string()],
+                field: None,
+                decoder: None,
+            },
+            TopicRule {
+                topic: "sensors/#".to_string(),
+                measurement: "sensors".to_string(),
+                tags: vec!["path".to_string()],
+                field: Some("state".to_string()),
+                decoder: None,
+            },
+        ]);
+
+        let summary = on_messages(
+            vec![
+                message("sensors/s1/temp", "21.5", 10),
+                message("sensors/s2/temp", "22", 20),
+                message("sensors/s1/door", "open", 30),
+                message("sensors/s1/temp", "", 40),
+                message("other", "1", 50),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            summary,
+            IngestSummary {
+                messages: 5,
+                routed: 3,
+                failed: 0
+            }
+        );
+
+        assert!(on_message("sensors/+/temp".to_string(), ByteBuf::from(*b"1"), 60).is_err());
+        SETTINGS.with(|s| s.borrow_mut().inbound_batch_size = 1);
+        assert!(on_messages(vec![message("a", "1", 0), message("b", "2", 0)], None).is_err());
+        assert_eq!(get_in_messages(MessageQuery::default()).unwrap().total, 5);
+
+        TIME_DB.with(|m| {
+            let db = m.borrow();
+            let temp = db
+                .find_measurement("temp")
+                .unwrap()
+                .apply(&[Action::Range(0, None)], &QueryLimits::default(), None)
+                .unwrap()
+                .entries;
+            assert_eq!(temp.len(), 2);
+            assert_eq!(temp[0].timestamp, 10);
+            assert_eq!(temp[0].fields.get("value"), Some(&Value::Float(21.5)));
+            assert_eq!(
+                temp[1].tags.get("sensor"),
+                Some(&Value::String("s2".to_string()))
+            );
+            assert_eq!(temp[1].fields.get("value"), Some(&Value::Int(22)));
+
+            let sensors = db
+                .find_measurement("sensors")
+                .unwrap()
+                .apply(&[Action::Range(0, None)], &QueryLimits::default(), None)
+                .unwrap()
+                .entries;
+            assert_eq!(sensors.len(), 1);
+            assert_eq!(
+                sensors[0].tags.get("path"),
+                Some(&Value::String("s1/door".to_string()))
+            );
+            assert_eq!(
+                sensors[0].fields.get("state"),
+                Some(&Value::String("open".to_string()))
+            );
+        });
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        set_rules(vec![TopicRule {
+            topic: "devices/+/json".to_string(),
+            measurement: "devices".to_string(),
+            tags: vec!["device".to_string()],
+            field: None,
+            decoder: Some(Decoder::Json(JsonDecoder::default())),
+        }]);
+
+        let summary = on_messages(
+            vec![
+                message("devices/d1/json", r#"{"temp": 20, "on": true}"#, 10),
+                message("devices/d2/json", "{not json", 20),
+                message("devices/d3/json", "[]", 30),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            summary,
+            IngestSummary {
+                messages: 3,
+                routed: 1,
+                failed: 1
+            }
+        );
+
+        let letters = get_dead_letters(0, 10);
+        assert_eq!(letters.total, 1);
+        assert_eq!(letters.data[0].topic, "devices/d2/json");
+        assert_eq!(letters.data[0].payload.as_slice(), b"{not json");
+        assert_eq!(letters.data[0].timestamp, 20);
+        assert!(letters.data[0].error.starts_with("invalid JSON"));
+
+        clear_dead_letters().unwrap();
+        assert_eq!(get_dead_letters(0, 10).total, 0);
+    }
+
+    #[test]
+    fn test_message_log() {
+        let now = crate::now();
+        let day = 24 * 3600 * 1_000_000_000;
+        for (topic, payload, time) in [
+            ("sensors/s1/temp", "1", now - 8 * day),
+            ("sensors/s2/temp", "2", now - 6 * day),
+            ("sensors/s1/humidity", "3", now - 2 * day),
+        ] {
+            ingest("on_message", vec![message(topic, payload, time)], time).unwrap();
+        }
+        on_message("sensors/s1/temp".to_string(), ByteBuf::from(*b"4"), now).unwrap();
+
+        // the message received more than the 7 days of retention ago is gone, indexes stay
+        // the same
+        let query = |query: MessageQuery| {
+            let page = get_in_messages(query).unwrap();
+            let indexes: Vec<u64> = page.data.iter().map(|message| message.index).collect();
+            (page.total, indexes)
+        };
+        assert_eq!(query(MessageQuery::default()), (3, vec![1, 2, 3]));
+        assert_eq!(
+            query(MessageQuery {
+                topic: Some("sensors/s1/#".to_string()),
+                ..MessageQuery::default()
+            }),
+            (2, vec![2, 3])
+        );
+        assert_eq!(
+            query(MessageQuery {
+                start: Some(now - 7 * day),
+                end: Some(now),
+                ..MessageQuery::default()
+            }),
+            (2, vec![1, 2])
+        );
+        assert_eq!(
+            query(MessageQuery {
+                skip: Some(1),
+                limit: Some(1),
+                ..MessageQuery::default()
+            }),
+            (3, vec![2])
+        );
+        assert_eq!(
+            query(MessageQuery {
+                after: Some(2),
+                ..MessageQuery::default()
+            }),
+            (1, vec![3])
+        );
+        assert!(get_in_messages(MessageQuery {
+            topic: Some("sensors/#/temp".to_string()),
+            ..MessageQuery::default()
+        })
+        .is_err());
+
+        IN_MESSAGES.with(|m| {
+            let store = m.borrow();
+            assert!(store.get_message(0).is_none());
+            assert_eq!(store.get_message(2).unwrap().topic, "sensors/s1/humidity");
+        });
+
+        // a backfilled message is kept by the time it arrived, not by its timestamp
+        on_message(
+            "sensors/s2/temp".to_string(),
+            ByteBuf::from(*b"5"),
+            now - 30 * day,
+        )
+        .unwrap();
+        assert_eq!(query(MessageQuery::default()), (4, vec![1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_replayed_batch() {
+        set_rules(vec![TopicRule {
+            topic: "sensors/#".to_string(),
+            measurement: "sensors".to_string(),
+            tags: vec![],
+            field: None,
+            decoder: None,
+        }]);
+
+        let batch = vec![message("sensors/a", "1", 0), message("sensors/b", "2", 0)];
+        let gateway = Principal::management_canister();
+        let now = crate::now();
+        let first = ingest_batch(gateway, batch.clone(), Some(7), now).unwrap();
+        assert_eq!(
+            ingest_batch(gateway, batch.clone(), Some(7), now),
+            Ok(first)
+        );
+        assert_eq!(get_in_messages(MessageQuery::default()).unwrap().total, 2);
+
+        // unit tests call anonymously, anonymous batches can't be numbered
+        assert!(on_messages(batch.clone(), Some(8)).is_err());
+        ingest_batch(gateway, batch.clone(), Some(8), now).unwrap();
+        on_messages(batch, None).unwrap();
+        assert_eq!(get_in_messages(MessageQuery::default()).unwrap().total, 6);
+    }
+
+    #[test]
+    fn test_message_logs_need_a_gateway() {
+        on_message("sensors/a".to_string(), ByteBuf::from("1"), 0).unwrap();
+
+        SETTINGS.with(|s| s.borrow_mut().owner = Principal::management_canister());
+        assert!(get_in_messages(MessageQuery::default()).is_err());
+        assert!(get_out_messages(MessageQuery::default()).is_err());
+
+        SETTINGS.with(|s| s.borrow_mut().owner = Principal::anonymous());
+        assert_eq!(get_in_messages(MessageQuery::default()).unwrap().total, 1);
+        assert!(get_out_messages(MessageQuery::default()).is_ok());
+    }
+}
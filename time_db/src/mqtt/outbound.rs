@@ -0,0 +1,388 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use ic_cdk_macros::{query, update};
+use serde_bytes::ByteBuf;
+
+use super::{gateways::ensure_gateway, retention_cutoff};
+use crate::{caller, ensure_owner, Message, OUT_MESSAGES, SETTINGS};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Messages not delivered within this time are dropped
+const MESSAGE_TTL: u64 = 24 * 3600 * NANOS_PER_SECOND;
+// A polled message is handed out again when the gateway doesn't acknowledge it in time
+const ACK_TIMEOUT: u64 = 60 * NANOS_PER_SECOND;
+const MAX_ATTEMPTS: u32 = 5;
+
+thread_local! {
+    static DELIVERIES: RefCell<BTreeMap<u64, Delivery>> = const { RefCell::new(BTreeMap::new()) };
+    static PUBLISHERS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Debug, PartialEq)]
+pub enum DeliveryState {
+    Pending,   // waiting for the gateway to poll it
+    InFlight,  // polled, waiting for the acknowledgement
+    Delivered, // acknowledged, or polled with QoS 0
+    Expired,   // not delivered before `expires_at`
+    Failed,    // not acknowledged after the last attempt
+}
+
+// Delivery of the message with the same index in `OUT_MESSAGES`
+#[derive(Clone, Debug)]
+struct Delivery {
+    qos: u8,
+    retain: bool,
+    state: DeliveryState,
+    attempts: u32,
+    last_attempt: u64,
+    expires_at: u64,
+}
+
+impl Delivery {
+    fn is_final(&self) -> bool {
+        matches!(
+            self.state,
+            DeliveryState::Delivered | DeliveryState::Expired | DeliveryState::Failed
+        )
+    }
+
+    // Moves a message that can't be delivered anymore to its final state
+    fn update(&mut self, now: u64) {
+        if self.is_final() {
+            return;
+        }
+
+        let timed_out =
+            self.state == DeliveryState::InFlight && now >= self.last_attempt + ACK_TIMEOUT;
+        if now >= self.expires_at {
+            self.state = DeliveryState::Expired;
+        } else if timed_out && self.attempts >= MAX_ATTEMPTS {
+            self.state = DeliveryState::Failed;
+        } else if timed_out {
+            self.state = DeliveryState::Pending;
+        }
+    }
+}
+
+/// Message to be published by the gateway, with the state of its delivery.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct OutboundMessage {
+    pub index: u64,
+    pub topic: String,
+    pub payload: ByteBuf,
+    pub qos: u8,
+    pub retain: bool,
+    pub timestamp: u64,
+    pub state: DeliveryState,
+    pub attempts: u32,
+    pub expires_at: u64,
+}
+
+/// Queues a message for the gateway to publish on `topic`. Returns the index of the message
+/// to follow its delivery with `get_outbound`. Only the owner and the publishers can do this.
+#[update]
+#[candid_method(update)]
+fn publish(topic: String, payload: ByteBuf, qos: u8, retain: bool) -> Result<u64, String> {
+    ensure_publisher()?;
+    enqueue(topic, payload, qos, retain, crate::now())
+}
+
+/// Hands out up to `limit` (at most the outbound batch size) messages from `since_index` on
+/// that are due for delivery. Messages with QoS 1 and 2 are handed out again when they aren't
+/// acknowledged within a minute. Only the owner and the authorized gateways can do this.
+#[update]
+#[candid_method(update)]
+fn poll_outbound(since_index: u64, limit: u64) -> Result<Vec<OutboundMessage>, String> {
+    ensure_gateway()?;
+    let batch_size = SETTINGS.with(|s| s.borrow().outbound_batch_size);
+    Ok(poll(since_index, limit.min(batch_size), crate::now()))
+}
+
+/// Marks polled messages as delivered, returns how many of them were in flight. Only the
+/// owner and the authorized gateways can do this.
+#[update]
+#[candid_method(update)]
+fn ack_outbound(indexes: Vec<u64>) -> Result<u64, String> {
+    ensure_gateway()?;
+    Ok(acknowledge(indexes, crate::now()))
+}
+
+fn acknowledge(indexes: Vec<u64>, now: u64) -> u64 {
+    DELIVERIES.with(|d| {
+        let mut deliveries = d.borrow_mut();
+        let mut acknowledged = 0;
+        for index in indexes {
+            if let Some(delivery) = deliveries.get_mut(&index) {
+                delivery.update(now);
+                // late acknowledgements of handed out messages still count
+                if delivery.state == DeliveryState::InFlight
+                    || (delivery.state == DeliveryState::Pending && delivery.attempts > 0)
+                {
+                    delivery.state = DeliveryState::Delivered;
+                    acknowledged += 1;
+                }
+            }
+        }
+        acknowledged
+    })
+}
+
+/// Returns an outbound message with the state of its delivery. Only the owner, the
+/// authorized gateways and the publishers can do this.
+#[query]
+#[candid_method(query)]
+fn get_outbound(index: u64) -> Result<Option<OutboundMessage>, String> {
+    ensure_gateway()
+        .or_else(|_| ensure_publisher())
+        .map_err(|_| {
+            "Only the owner, the gateways and the publishers can read outbound messages".to_string()
+        })?;
+
+    let now = crate::now();
+    let Some(mut delivery) = DELIVERIES.with(|d| d.borrow().get(&index).cloned()) else {
+        return Ok(None);
+    };
+    delivery.update(now);
+    Ok(OUT_MESSAGES.with(|m| {
+        m.borrow()
+            .get_message(index)
+            .map(|message| outbound_message(message, &delivery))
+    }))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_publishers() -> Vec<Principal> {
+    PUBLISHERS.with(|p| p.borrow().iter().cloned().collect())
+}
+
+/// Replaces the principals allowed to publish besides the owner.
+#[update]
+#[candid_method(update)]
+fn set_publishers(publishers: Vec<Principal>) -> Result<(), String> {
+    ensure_owner()?;
+    PUBLISHERS.with(|p| *p.borrow_mut() = publishers.into_iter().collect());
+    Ok(())
+}
+
+fn ensure_publisher() -> Result<(), String> {
+    let caller = caller();
+    let owner = SETTINGS.with(|s| s.borrow().owner);
+    if caller == owner || PUBLISHERS.with(|p| p.borrow().contains(&caller)) {
+        Ok(())
+    } else {
+        Err("Only the owner and the publishers of the canister can publish".to_string())
+    }
+}
+
+pub fn enqueue(
+    topic: String,
+    payload: ByteBuf,
+    qos: u8,
+    retain: bool,
+    now: u64,
+) -> Result<u64, String> {
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        return Err(format!("Invalid topic '{}'", topic));
+    }
+    if qos > 2 {
+        return Err(format!("Invalid QoS {}, expected 0, 1 or 2", qos));
+    }
+
+    let (index, first_index) = OUT_MESSAGES.with(|m| {
+        let mut store = m.borrow_mut();
+        let index = store.add_message(&Message {
+            index: 0,
+            topic,
+            message: payload,
+            timestamp: now,
+            received_at: now,
+        });
+        let first_index = retention_cutoff(now).map_or(0, |cutoff| store.remove_before(cutoff));
+        (index, first_index)
+    });
+    DELIVERIES.with(|d| {
+        let mut deliveries = d.borrow_mut();
+        // the deliveries of messages trimmed from the log go with them
+        deliveries.retain(|index, _| *index >= first_index);
+        deliveries.insert(
+            index,
+            Delivery {
+                qos,
+                retain,
+                state: DeliveryState::Pending,
+                attempts: 0,
+                last_attempt: 0,
+                expires_at: now.saturating_add(MESSAGE_TTL),
+            },
+        )
+    });
+
+    Ok(index)
+}
+
+fn poll(since_index: u64, limit: u64, now: u64) -> Vec<OutboundMessage> {
+    DELIVERIES.with(|d| {
+        let mut deliveries = d.borrow_mut();
+        let mut polled = vec![];
+        for (index, delivery) in deliveries.range_mut(since_index..) {
+            if polled.len() as u64 >= limit {
+                break;
+            }
+
+            delivery.update(now);
+            if delivery.state != DeliveryState::Pending {
+                continue;
+            }
+
+            delivery.attempts += 1;
+            delivery.last_attempt = now;
+            delivery.state = match delivery.qos {
+                0 => DeliveryState::Delivered,
+                _ => DeliveryState::InFlight,
+            };
+            polled.push((*index, delivery.clone()));
+        }
+
+        OUT_MESSAGES.with(|m| {
+            let store = m.borrow();
+            polled
+                .iter()
+                .filter_map(|(index, delivery)| {
+                    let message = store.get_message(*index)?;
+                    Some(outbound_message(message, delivery))
+                })
+                .collect()
+        })
+    })
+}
+
+fn outbound_message(message: &Message, delivery: &Delivery) -> OutboundMessage {
+    OutboundMessage {
+        index: message.index,
+        topic: message.topic.clone(),
+        payload: message.message.clone(),
+        qos: delivery.qos,
+        retain: delivery.retain,
+        timestamp: message.timestamp,
+        state: delivery.state,
+        attempts: delivery.attempts,
+        expires_at: delivery.expires_at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indexes(messages: &[OutboundMessage]) -> Vec<u64> {
+        messages.iter().map(|message| message.index).collect()
+    }
+
+    #[test]
+    fn test_delivery() {
+        let now = crate::now();
+        let payload = ByteBuf::from(*b"on");
+        let once = enqueue("cmd/a".to_string(), payload.clone(), 0, false, now).unwrap();
+        let acked = enqueue("cmd/b".to_string(), payload.clone(), 1, true, now).unwrap();
+        let retried = enqueue("cmd/c".to_string(), payload.clone(), 2, false, now).unwrap();
+        assert!(enqueue("cmd/#".to_string(), payload.clone(), 1, false, now).is_err());
+        assert!(enqueue("cmd/d".to_string(), payload, 3, false, now).is_err());
+
+        let polled = poll(0, 10, now);
+        assert_eq!(indexes(&polled), vec![once, acked, retried]);
+        assert_eq!(polled[0].state, DeliveryState::Delivered);
+        assert_eq!(polled[1].state, DeliveryState::InFlight);
+        assert!(polled[1].retain);
+        assert!(poll(0, 10, now + 1).is_empty());
+
+        assert_eq!(ack_outbound(vec![acked, once, 99]), Ok(1));
+        assert_eq!(
+            get_outbound(acked).unwrap().unwrap().state,
+            DeliveryState::Delivered
+        );
+
+        // handed out again once the acknowledgement timed out, until the attempts run out
+        let mut time = now;
+        for attempt in 2..=MAX_ATTEMPTS {
+            time += ACK_TIMEOUT;
+            let polled = poll(1, 10, time);
+            assert_eq!(indexes(&polled), vec![retried]);
+            assert_eq!(polled[0].attempts, attempt);
+        }
+        assert!(poll(0, 10, time + ACK_TIMEOUT).is_empty());
+        assert_eq!(
+            get_outbound(retried).unwrap().unwrap().state,
+            DeliveryState::Failed
+        );
+    }
+
+    #[test]
+    fn test_expiry_and_limit() {
+        let now = crate::now();
+        let polled = now - 2 * ACK_TIMEOUT;
+        let expired = enqueue(
+            "cmd/a".to_string(),
+            ByteBuf::new(),
+            1,
+            false,
+            now - MESSAGE_TTL,
+        );
+        let late = enqueue("cmd/b".to_string(), ByteBuf::new(), 1, false, polled);
+        let pending = enqueue("cmd/c".to_string(), ByteBuf::new(), 1, false, now);
+        let (expired, late, pending) = (expired.unwrap(), late.unwrap(), pending.unwrap());
+
+        assert_eq!(indexes(&poll(0, 1, polled)), vec![expired]);
+        assert_eq!(indexes(&poll(late, 1, polled)), vec![late]);
+        assert_eq!(
+            get_outbound(expired).unwrap().unwrap().state,
+            DeliveryState::Expired
+        );
+
+        // a late acknowledgement of a handed out message still counts
+        assert_eq!(ack_outbound(vec![expired, late, pending]), Ok(1));
+        assert_eq!(
+            get_outbound(late).unwrap().unwrap().state,
+            DeliveryState::Delivered
+        );
+        assert_eq!(
+            get_outbound(pending).unwrap().unwrap().state,
+            DeliveryState::Pending
+        );
+        assert!(get_outbound(99).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_publishers() {
+        assert!(publish("cmd/a".to_string(), ByteBuf::new(), 0, false).is_ok());
+
+        SETTINGS.with(|s| s.borrow_mut().owner = Principal::management_canister());
+        assert!(publish("cmd/a".to_string(), ByteBuf::new(), 0, false).is_err());
+        assert!(set_publishers(vec![Principal::anonymous()]).is_err());
+
+        PUBLISHERS.with(|p| p.borrow_mut().insert(Principal::anonymous()));
+        let index = publish("cmd/a".to_string(), ByteBuf::new(), 0, false).unwrap();
+        assert!(get_outbound(index).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_gateway_calls() {
+        let index = enqueue("cmd/a".to_string(), ByteBuf::new(), 1, false, crate::now()).unwrap();
+
+        SETTINGS.with(|s| s.borrow_mut().owner = Principal::management_canister());
+        assert!(poll_outbound(0, 10).is_err());
+        assert!(ack_outbound(vec![index]).is_err());
+        assert!(get_outbound(index).is_err());
+
+        SETTINGS.with(|s| s.borrow_mut().owner = Principal::anonymous());
+        assert_eq!(indexes(&poll_outbound(0, 10).unwrap()), vec![index]);
+        assert_eq!(ack_outbound(vec![index]), Ok(1));
+        assert_eq!(
+            get_outbound(index).unwrap().unwrap().state,
+            DeliveryState::Delivered
+        );
+    }
+}
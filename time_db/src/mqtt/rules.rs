@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use candid::{CandidType, Deserialize};
+
+use super::decoder::Decoder;
+use crate::timedb::Value;
+
+/// Routes the messages of topics matching `topic` into `measurement`.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub struct TopicRule {
+    pub topic: String, // MQTT topic filter, `+` matches one level and `#` all remaining ones
+    pub measurement: String,
+    pub tags: Vec<String>, // tag names for the levels matched by the wildcards, in order
+    pub field: Option<String>, // field holding the payload, `value` by default
+    pub decoder: Option<Decoder>, // `Raw` by default
+}
+
+impl TopicRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.measurement.is_empty() {
+            return Err(format!("Rule for '{}' has no measurement", self.topic));
+        }
+
+        validate_filter(&self.topic)?;
+        let wildcards = self
+            .topic
+            .split('/')
+            .filter(|level| *level == "+" || *level == "#")
+            .count();
+        if self.tags.len() > wildcards {
+            return Err(format!(
+                "Rule for '{}' names {} tags but has {} wildcards",
+                self.topic,
+                self.tags.len(),
+                wildcards
+            ));
+        }
+        if self.tags.iter().any(|tag| tag.is_empty()) {
+            return Err(format!("Rule for '{}' has an empty tag name", self.topic));
+        }
+
+        self.decoder()
+            .validate()
+            .map_err(|err| format!("Rule for '{}': {}", self.topic, err))
+    }
+
+    /// Levels matched by the wildcards when `topic` matches the filter of the rule.
+    pub fn captures<'a>(&self, topic: &'a str) -> Option<Vec<&'a str>> {
+        captures(&self.topic, topic)
+    }
+
+    /// Tags for the wildcard levels of a matching topic.
+    pub fn tags(&self, captures: &[&str]) -> HashMap<String, Value> {
+        self.tags
+            .iter()
+            .zip(captures)
+            .map(|(name, level)| (name.clone(), Value::String(level.to_string())))
+            .collect()
+    }
+
+    pub fn field(&self) -> &str {
+        self.field.as_deref().unwrap_or("value")
+    }
+
+    pub fn decoder(&self) -> &Decoder {
+        static RAW: Decoder = Decoder::Raw;
+        self.decoder.as_ref().unwrap_or(&RAW)
+    }
+}
+
+/// Checks the syntax of an MQTT topic filter: `+` and `#` take a whole level, `#` only the last.
+pub fn validate_filter(filter: &str) -> Result<(), String> {
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        let valid = match *level {
+            "+" => true,
+            "#" => i == levels.len() - 1,
+            level => !level.contains(['+', '#']),
+        };
+        if !valid || filter.is_empty() {
+            return Err(format!("Invalid topic filter '{}'", filter));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `topic` matches the topic filter.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    captures(filter, topic).is_some()
+}
+
+// Levels matched by the wildcards when `topic` matches the filter, `#` matches the remaining
+// levels joined with `/`
+fn captures<'a>(filter: &str, topic: &'a str) -> Option<Vec<&'a str>> {
+    // wildcards don't match the `$SYS/...` topics of the broker
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return None;
+    }
+
+    let mut captures = vec![];
+    let mut levels = topic.split('/');
+    let mut rest = topic;
+    for expected in filter.split('/') {
+        if expected == "#" {
+            captures.push(rest);
+            return Some(captures);
+        }
+
+        let level = levels.next()?;
+        rest = rest.get(level.len() + 1..).unwrap_or_default();
+        match expected {
+            "+" => captures.push(level),
+            expected if expected != level => return None,
+            _ => {}
+        }
+    }
+
+    match levels.next() {
+        Some(_) => None,
+        None => Some(captures),
+    }
+}
+
+/// First rule matching the topic, with the levels captured by its wildcards.
+pub fn route<'a, 'b>(
+    rules: &'a [TopicRule],
+    topic: &'b str,
+) -> Option<(&'a TopicRule, Vec<&'b str>)> {
+    rules
+        .iter()
+        .find_map(|rule| rule.captures(topic).map(|captures| (rule, captures)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt::decoder::JsonDecoder;
+
+    fn rule(topic: &str, tags: &[&str]) -> TopicRule {
+        TopicRule {
+            topic: topic.to_string(),
+            measurement: "m".to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            field: None,
+            decoder: None,
+        }
+    }
+
+    #[test]
+    fn test_captures() {
+        let temp = rule("sensors/+/temp", &["sensor"]);
+        assert_eq!(temp.captures("sensors/s1/temp"), Some(vec!["s1"]));
+        assert_eq!(temp.captures("sensors/s1/humidity"), None);
+        assert_eq!(temp.captures("sensors/s1/temp/raw"), None);
+        assert_eq!(temp.captures("sensors/temp"), None);
+
+        let all = rule("sites/+/#", &["site", "path"]);
+        assert_eq!(all.captures("sites/a/b/c"), Some(vec!["a", "b/c"]));
+        assert_eq!(all.captures("sites/a"), Some(vec!["a", ""]));
+        assert_eq!(rule("#", &[]).captures("$SYS/uptime"), None);
+        assert_eq!(
+            rule("$SYS/#", &[]).captures("$SYS/uptime"),
+            Some(vec!["uptime"])
+        );
+
+        let rules = [rule("sensors/s1/temp", &[]), temp.clone()];
+        assert_eq!(route(&rules, "sensors/s2/temp"), Some((&temp, vec!["s2"])));
+        assert_eq!(
+            temp.tags(&["s2"]),
+            HashMap::from([("sensor".to_string(), Value::String("s2".to_string()))])
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(rule("sensors/+/temp", &["sensor"]).validate().is_ok());
+        assert!(rule("sensors/#", &[]).validate().is_ok());
+        assert!(rule("sensors/#/temp", &[]).validate().is_err());
+        assert!(rule("sensors/s+/temp", &[]).validate().is_err());
+        assert!(rule("sensors/+/temp", &["a", "b"]).validate().is_err());
+        assert!(rule("", &[]).validate().is_err());
+
+        let mut json = rule("sensors/#", &[]);
+        json.decoder = Some(Decoder::Json(JsonDecoder {
+            records: Some("$..readings".to_string()),
+            ..JsonDecoder::default()
+        }));
+        assert!(json.validate().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::timedb::{Entry, Value};
+
+// Times below 2^28 seconds are relative to the time the pack was received
+const RELATIVE_TIME_LIMIT: f64 = (1 << 28) as f64;
+
+/// A record of a SenML pack (RFC 8428), base values apply to the records that follow.
+#[derive(Deserialize, Default)]
+struct Record {
+    bn: Option<String>,
+    bt: Option<f64>,
+    bu: Option<String>,
+    bv: Option<f64>,
+    bs: Option<f64>,
+    n: Option<String>,
+    u: Option<String>,
+    v: Option<f64>,
+    vs: Option<String>,
+    vb: Option<bool>,
+    vd: Option<String>,
+    s: Option<f64>,
+    t: Option<f64>,
+}
+
+/// Entries of a JSON SenML pack, one per record. The value goes to `field` (the sum to
+/// `sum`), the resolved name and the unit become the `name` and `unit` tags.
+pub fn decode(payload: &[u8], field: &str, now: u64) -> Result<Vec<Entry>, String> {
+    let records: Vec<Record> =
+        serde_json::from_slice(payload).map_err(|err| format!("invalid SenML: {}", err))?;
+
+    let mut base = Record::default();
+    records
+        .into_iter()
+        .enumerate()
+        .map(|(i, record)| {
+            base.bn = record.bn.clone().or(base.bn.take());
+            base.bt = record.bt.or(base.bt);
+            base.bu = record.bu.clone().or(base.bu.take());
+            base.bv = record.bv.or(base.bv);
+            base.bs = record.bs.or(base.bs);
+            entry(&base, record, field, now).map_err(|err| format!("record {}: {}", i, err))
+        })
+        .collect()
+}
+
+fn entry(base: &Record, record: Record, field: &str, now: u64) -> Result<Entry, String> {
+    let name = format!(
+        "{}{}",
+        base.bn.as_deref().unwrap_or_default(),
+        record.n.as_deref().unwrap_or_default()
+    );
+    if name.is_empty() {
+        return Err("no name".to_string());
+    }
+
+    let mut fields = HashMap::new();
+    let value = match (record.v, record.vs, record.vb, record.vd) {
+        (Some(v), ..) => Some(Value::from_f64(base.bv.unwrap_or_default() + v)),
+        (_, Some(vs), ..) => Some(Value::String(vs)),
+        (_, _, Some(vb), _) => Some(Value::Bool(vb)),
+        (.., Some(vd)) => Some(Value::String(vd)),
+        _ => None,
+    };
+    if let Some(value) = value {
+        fields.insert(field.to_string(), value);
+    }
+    if let Some(s) = record.s {
+        let sum = base.bs.unwrap_or_default() + s;
+        fields.insert("sum".to_string(), Value::from_f64(sum));
+    }
+    if fields.is_empty() {
+        return Err(format!("no value for '{}'", name));
+    }
+
+    let mut tags = HashMap::from([("name".to_string(), Value::String(name))]);
+    if let Some(unit) = record.u.or_else(|| base.bu.clone()) {
+        tags.insert("unit".to_string(), Value::String(unit));
+    }
+
+    let time = base.bt.unwrap_or_default() + record.t.unwrap_or_default();
+    let nanos = match time.is_finite() {
+        true => time.trunc() as i128 * 1_000_000_000 + (time.fract() * 1e9).round() as i128,
+        false => return Err(format!("invalid time {}", time)),
+    };
+    let timestamp = match time < RELATIVE_TIME_LIMIT {
+        true => now as i128 + nanos,
+        false => nanos,
+    };
+    let timestamp = u64::try_from(timestamp).map_err(|_| format!("invalid time {}", time))?;
+
+    Ok(Entry {
+        timestamp,
+        fields,
+        tags,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let pack = br#"[
+            {"bn": "urn:dev:ow:10e2073a01080063:", "bt": 1.320067464e9, "bu": "%RH", "v": 20},
+            {"u": "lon", "v": 24.30621},
+            {"n": "door", "vs": "open", "t": 60},
+            {"bn": "urn:dev:mac:0024befffe804ff1:", "bt": 0, "n": "energy", "s": 12.5, "t": -5}
+        ]"#;
+
+        let entries = decode(pack, "value", 1_000_000_000_000).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].timestamp, 1_320_067_464_000_000_000);
+        assert_eq!(entries[0].fields.get("value"), Some(&Value::Int(20)));
+        assert_eq!(
+            entries[0].tags,
+            HashMap::from([
+                (
+                    "name".to_string(),
+                    Value::String("urn:dev:ow:10e2073a01080063:".to_string())
+                ),
+                ("unit".to_string(), Value::String("%RH".to_string())),
+            ])
+        );
+        assert_eq!(
+            entries[1].tags.get("unit"),
+            Some(&Value::String("lon".to_string()))
+        );
+        assert_eq!(entries[2].timestamp, 1_320_067_524_000_000_000);
+        assert_eq!(
+            entries[2].tags.get("name"),
+            Some(&Value::String(
+                "urn:dev:ow:10e2073a01080063:door".to_string()
+            ))
+        );
+        assert_eq!(
+            entries[2].fields.get("value"),
+            Some(&Value::String("open".to_string()))
+        );
+
+        // relative to the time of arrival
+        assert_eq!(entries[3].timestamp, 995_000_000_000);
+        assert_eq!(entries[3].fields.get("sum"), Some(&Value::Float(12.5)));
+
+        assert_eq!(
+            decode(br#"[{"n": "a", "v": 1}, {"n": "b"}]"#, "value", 0)
+                .err()
+                .as_deref(),
+            Some("record 1: no value for 'b'")
+        );
+        let entries = decode(br#"[{"n": "a", "bv": 16777216, "v": 1}]"#, "value", 0).unwrap();
+        assert_eq!(
+            entries[0].fields.get("value"),
+            Some(&Value::Int(16_777_217))
+        );
+        assert!(decode(br#"[{"v": 1}]"#, "value", 0).is_err());
+        assert!(decode(br#"{"n": "a"}"#, "value", 0).is_err());
+    }
+}
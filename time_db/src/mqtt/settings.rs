@@ -0,0 +1,215 @@
+use candid::{candid_method, CandidType, Deserialize};
+use ic_cdk_macros::{query, update};
+
+use super::rules::{validate_filter, TopicRule};
+use crate::{ensure_owner, Settings, SETTINGS};
+
+/// Topic filter the gateway subscribes to on the broker.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub struct Subscription {
+    pub topic: String,
+    pub qos: u8,
+}
+
+/// Version of the settings, gateways reload the settings when it changes.
+#[query]
+#[candid_method(query)]
+fn get_settings_version() -> u64 {
+    SETTINGS.with(|s| s.borrow().version)
+}
+
+/// Subscribes the gateway to `topic`, or changes the QoS of an existing subscription.
+/// Returns the new settings version.
+#[update]
+#[candid_method(update)]
+fn subscribe(topic: String, qos: u8) -> Result<u64, String> {
+    validate_subscription(&topic, qos)?;
+    update_settings(|settings| {
+        match settings.subscriptions.iter_mut().find(|s| s.topic == topic) {
+            Some(subscription) => subscription.qos = qos,
+            None => settings.subscriptions.push(Subscription { topic, qos }),
+        }
+        Ok(())
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn unsubscribe(topic: String) -> Result<u64, String> {
+    update_settings(|settings| {
+        let count = settings.subscriptions.len();
+        settings.subscriptions.retain(|s| s.topic != topic);
+        match settings.subscriptions.len() < count {
+            true => Ok(()),
+            false => Err(format!("Not subscribed to '{}'", topic)),
+        }
+    })
+}
+
+/// Replaces all subscriptions of the gateway.
+#[update]
+#[candid_method(update)]
+fn set_subscriptions(subscriptions: Vec<Subscription>) -> Result<u64, String> {
+    for subscription in &subscriptions {
+        validate_subscription(&subscription.topic, subscription.qos)?;
+    }
+    update_settings(|settings| {
+        settings.subscriptions = subscriptions;
+        Ok(())
+    })
+}
+
+/// Sets the seconds between the polls of the gateway.
+#[update]
+#[candid_method(update)]
+fn set_interval(interval: u64) -> Result<u64, String> {
+    if interval == 0 {
+        return Err("The interval must be at least a second".to_string());
+    }
+    update_settings(|settings| {
+        settings.interval = interval;
+        Ok(())
+    })
+}
+
+/// Sets the most messages accepted by one `on_messages` call and handed out by one
+/// `poll_outbound` call.
+#[update]
+#[candid_method(update)]
+fn set_batch_sizes(inbound: u64, outbound: u64) -> Result<u64, String> {
+    if inbound == 0 || outbound == 0 {
+        return Err("Batch sizes must be at least 1".to_string());
+    }
+    update_settings(|settings| {
+        settings.inbound_batch_size = inbound;
+        settings.outbound_batch_size = outbound;
+        Ok(())
+    })
+}
+
+/// Sets the seconds messages are kept in the message logs, 0 keeps them forever.
+#[update]
+#[candid_method(update)]
+fn set_retention(retention: u64) -> Result<u64, String> {
+    update_settings(|settings| {
+        settings.retention = retention;
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_topic_rules() -> Vec<TopicRule> {
+    SETTINGS.with(|s| s.borrow().rules.clone())
+}
+
+/// Replaces the topic rules, messages are routed by the first rule matching their topic.
+#[update]
+#[candid_method(update)]
+fn set_topic_rules(rules: Vec<TopicRule>) -> Result<u64, String> {
+    for rule in &rules {
+        rule.validate()?;
+    }
+    update_settings(|settings| {
+        settings.rules = rules;
+        Ok(())
+    })
+}
+
+fn validate_subscription(topic: &str, qos: u8) -> Result<(), String> {
+    validate_filter(topic)?;
+    match qos {
+        0..=2 => Ok(()),
+        qos => Err(format!("Invalid QoS {}, expected 0, 1 or 2", qos)),
+    }
+}
+
+/// Time before which messages are removed from the message logs, if they expire at all.
+pub fn retention_cutoff(now: u64) -> Option<u64> {
+    match SETTINGS.with(|s| s.borrow().retention) {
+        0 => None,
+        retention => Some(now.saturating_sub(retention.saturating_mul(1_000_000_000))),
+    }
+}
+
+// Applies an owner's change and returns the new version
+fn update_settings(
+    change: impl FnOnce(&mut Settings) -> Result<(), String>,
+) -> Result<u64, String> {
+    ensure_owner()?;
+    SETTINGS.with(|s| {
+        let mut settings = s.borrow_mut();
+        change(&mut settings)?;
+        settings.version += 1;
+        Ok(settings.version)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    fn topics() -> Vec<(String, u8)> {
+        SETTINGS.with(|s| {
+            s.borrow()
+                .subscriptions
+                .iter()
+                .map(|s| (s.topic.clone(), s.qos))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_settings() {
+        assert_eq!(get_settings_version(), 0);
+        assert_eq!(subscribe("sensors/#".to_string(), 1), Ok(1));
+        assert_eq!(subscribe("alerts/+".to_string(), 0), Ok(2));
+        assert_eq!(subscribe("sensors/#".to_string(), 2), Ok(3));
+        assert_eq!(
+            topics(),
+            vec![("sensors/#".to_string(), 2), ("alerts/+".to_string(), 0)]
+        );
+
+        assert!(subscribe("sensors/#/temp".to_string(), 1).is_err());
+        assert!(subscribe("sensors/+".to_string(), 3).is_err());
+        assert!(unsubscribe("other".to_string()).is_err());
+        assert_eq!(unsubscribe("alerts/+".to_string()), Ok(4));
+        assert_eq!(topics(), vec![("sensors/#".to_string(), 2)]);
+
+        assert_eq!(set_interval(5), Ok(5));
+        assert!(set_interval(0).is_err());
+        assert_eq!(set_batch_sizes(50, 20), Ok(6));
+        assert!(set_batch_sizes(0, 20).is_err());
+        assert_eq!(set_subscriptions(vec![]), Ok(7));
+        assert_eq!(set_retention(60), Ok(8));
+        assert_eq!(retention_cutoff(100_000_000_000), Some(40_000_000_000));
+        assert_eq!(set_retention(0), Ok(9));
+        assert_eq!(retention_cutoff(100_000_000_000), None);
+        assert_eq!(get_settings_version(), 9);
+
+        let settings = SETTINGS.with(|s| s.borrow().clone());
+        assert_eq!(settings.interval, 5);
+        assert_eq!(settings.inbound_batch_size, 50);
+        assert_eq!(settings.outbound_batch_size, 20);
+        assert!(settings.subscriptions.is_empty());
+
+        SETTINGS.with(|s| s.borrow_mut().owner = Principal::management_canister());
+        assert!(set_interval(10).is_err());
+        assert_eq!(get_settings_version(), 9);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let rule = TopicRule {
+            topic: "sensors/#/temp".to_string(),
+            measurement: "temp".to_string(),
+            tags: vec![],
+            field: None,
+            decoder: None,
+        };
+        assert!(set_topic_rules(vec![rule]).is_err());
+        assert!(get_topic_rules().is_empty());
+        assert_eq!(get_settings_version(), 0);
+    }
+}
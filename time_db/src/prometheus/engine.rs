@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+
+use crate::timedb::AggregateFunction;
+
+use super::{
+    promql::{PromQl, RangeFunction},
+    remote::{LabelMatcher, TimeSeries, NAME_LABEL},
+    ApiError,
+};
+
+/// How far back an instant selector looks for the latest sample, as in Prometheus.
+pub const LOOKBACK: i64 = 5 * 60 * 1000;
+
+/// Most steps a range query may evaluate.
+pub const MAX_STEPS: i64 = 11_000;
+
+pub type Labels = Vec<(String, String)>; // sorted by name
+
+/// Series of a query result, with points as (milliseconds, value).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Series {
+    pub labels: Labels,
+    pub points: Vec<(i64, f64)>,
+}
+
+/// Reads the series matching the matchers with samples within `[start, end]` (milliseconds).
+/// With an aggregate function the samples of every series are aggregated into one by the
+/// query.
+pub type Fetch<'a> = dyn FnMut(&[LabelMatcher], i64, i64, Option<AggregateFunction>) -> Result<Vec<TimeSeries>, ApiError>
+    + 'a;
+
+/// Evaluates the expression at every step from `start` to `end` (inclusive), all in
+/// milliseconds. `avg_over_time` and `max_over_time` are aggregated by the queries, one
+/// per step, `rate` and sums are computed here from the samples read.
+pub fn evaluate(
+    expression: &PromQl,
+    start: i64,
+    end: i64,
+    step: i64,
+    fetch: &mut Fetch,
+) -> Result<Vec<Series>, ApiError> {
+    if step <= 0 {
+        return Err(ApiError::bad_data("the step has to be positive"));
+    }
+    if end < start {
+        return Err(ApiError::bad_data("the end is before the start"));
+    }
+    if (end - start) / step >= MAX_STEPS {
+        return Err(ApiError::bad_data(format!(
+            "exceeded maximum resolution of {} points per timeseries, try a larger step",
+            MAX_STEPS
+        )));
+    }
+
+    let steps: Vec<i64> = (0..=(end - start) / step)
+        .map(|i| start + i * step)
+        .collect();
+    let series = eval(expression, &steps, fetch)?;
+
+    Ok(series
+        .into_iter()
+        .filter(|series| !series.points.is_empty())
+        .collect())
+}
+
+/// Samples of a range selector within `(time - range, time]`, for instant queries.
+pub fn range_samples(
+    matchers: &[LabelMatcher],
+    range: i64,
+    time: i64,
+    fetch: &mut Fetch,
+) -> Result<Vec<Series>, ApiError> {
+    Ok(fetch(matchers, time - range + 1, time, None)?
+        .into_iter()
+        .map(|series| Series {
+            labels: labels(&series),
+            points: series
+                .samples
+                .iter()
+                .map(|sample| (sample.timestamp, sample.value))
+                .collect(),
+        })
+        .filter(|series| !series.points.is_empty())
+        .collect())
+}
+
+fn eval(expression: &PromQl, steps: &[i64], fetch: &mut Fetch) -> Result<Vec<Series>, ApiError> {
+    let (first, last) = (steps[0], steps[steps.len() - 1]);
+
+    match expression {
+        PromQl::Selector { range: Some(_), .. } => Err(ApiError::bad_data(
+            "a range selector can only be used as the argument of a function or in an instant query",
+        )),
+        PromQl::Selector {
+            matchers,
+            range: None,
+        } => {
+            let series = fetch(matchers, first - LOOKBACK + 1, last, None)?;
+            Ok(series
+                .iter()
+                .map(|series| Series {
+                    labels: labels(series),
+                    points: over_steps(series, steps, LOOKBACK, |_, window| {
+                        window.last().map(|(_, value)| *value)
+                    }),
+                })
+                .collect())
+        }
+        PromQl::Function {
+            function: RangeFunction::Rate,
+            matchers,
+            range,
+        } => {
+            let series = fetch(matchers, first - range + 1, last, None)?;
+            Ok(series
+                .iter()
+                .map(|series| Series {
+                    labels: without_name(labels(series)),
+                    points: over_steps(series, steps, *range, |step, window| {
+                        rate(window, step, *range)
+                    }),
+                })
+                .collect())
+        }
+        PromQl::Function {
+            function,
+            matchers,
+            range,
+        } => {
+            let aggregate = match function {
+                RangeFunction::MaxOverTime => AggregateFunction::Max,
+                _ => AggregateFunction::Mean,
+            };
+
+            let mut points: BTreeMap<Labels, Vec<(i64, f64)>> = BTreeMap::new();
+            for step in steps {
+                let series = fetch(matchers, step - range + 1, *step, Some(aggregate.clone()))?;
+                for series in series {
+                    if let Some(sample) = series.samples.first() {
+                        points
+                            .entry(without_name(labels(&series)))
+                            .or_default()
+                            .push((*step, sample.value));
+                    }
+                }
+            }
+
+            Ok(points
+                .into_iter()
+                .map(|(labels, points)| Series { labels, points })
+                .collect())
+        }
+        PromQl::Sum { by, expression } => {
+            let mut groups: BTreeMap<Labels, BTreeMap<i64, f64>> = BTreeMap::new();
+            for series in eval(expression, steps, fetch)? {
+                let labels = series
+                    .labels
+                    .into_iter()
+                    .filter(|(name, _)| by.contains(name))
+                    .collect();
+                let group = groups.entry(labels).or_default();
+                for (time, value) in series.points {
+                    *group.entry(time).or_default() += value;
+                }
+            }
+
+            Ok(groups
+                .into_iter()
+                .map(|(labels, points)| Series {
+                    labels,
+                    points: points.into_iter().collect(),
+                })
+                .collect())
+        }
+    }
+}
+
+// Applies `function` to the samples within `(step - range, step]` of every step
+fn over_steps(
+    series: &TimeSeries,
+    steps: &[i64],
+    range: i64,
+    function: impl Fn(i64, &[(i64, f64)]) -> Option<f64>,
+) -> Vec<(i64, f64)> {
+    let samples: Vec<(i64, f64)> = series
+        .samples
+        .iter()
+        .map(|sample| (sample.timestamp, sample.value))
+        .collect();
+
+    let (mut from, mut to) = (0, 0);
+    let mut points = vec![];
+    for step in steps {
+        while to < samples.len() && samples[to].0 <= *step {
+            to += 1;
+        }
+        while from < to && samples[from].0 <= step - range {
+            from += 1;
+        }
+        if let Some(value) = function(*step, &samples[from..to]) {
+            points.push((*step, value));
+        }
+    }
+
+    points
+}
+
+// Per second increase of a counter over the range ending at `step`, extrapolated to the
+// edges of the range like Prometheus does
+fn rate(samples: &[(i64, f64)], step: i64, range: i64) -> Option<f64> {
+    if samples.len() < 2 {
+        return None;
+    }
+    let (first, last) = (samples[0], samples[samples.len() - 1]);
+
+    // counter resets start again from zero
+    let mut increase = last.1 - first.1;
+    for pair in samples.windows(2) {
+        if pair[1].1 < pair[0].1 {
+            increase += pair[0].1;
+        }
+    }
+
+    let sampled = (last.0 - first.0) as f64 / 1000.0;
+    let average_interval = sampled / (samples.len() - 1) as f64;
+    let mut to_start = (first.0 - (step - range)) as f64 / 1000.0;
+    let to_end = (step - last.0) as f64 / 1000.0;
+    if increase > 0.0 && first.1 >= 0.0 {
+        // counters don't go below zero
+        to_start = to_start.min(sampled * first.1 / increase);
+    }
+
+    // edges further away than an interval are probably where the series starts or ends
+    let threshold = average_interval * 1.1;
+    let extrapolate = |to_edge: f64| match to_edge < threshold {
+        true => to_edge,
+        false => average_interval / 2.0,
+    };
+    let interval = sampled + extrapolate(to_start) + extrapolate(to_end);
+
+    Some(increase * interval / sampled / (range as f64 / 1000.0))
+}
+
+fn labels(series: &TimeSeries) -> Labels {
+    series
+        .labels
+        .iter()
+        .map(|label| (label.name.clone(), label.value.clone()))
+        .collect()
+}
+
+fn without_name(labels: Labels) -> Labels {
+    labels
+        .into_iter()
+        .filter(|(name, _)| name != NAME_LABEL)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prometheus::remote::{Label, Sample};
+
+    fn fetch_from(
+        data: Vec<TimeSeries>,
+    ) -> impl FnMut(
+        &[LabelMatcher],
+        i64,
+        i64,
+        Option<AggregateFunction>,
+    ) -> Result<Vec<TimeSeries>, ApiError> {
+        move |_, start, end, aggregate| {
+            Ok(data
+                .iter()
+                .map(|series| {
+                    let mut samples: Vec<Sample> = series
+                        .samples
+                        .iter()
+                        .filter(|sample| sample.timestamp >= start && sample.timestamp <= end)
+                        .cloned()
+                        .collect();
+                    if let (Some(aggregate), Some(first)) = (&aggregate, samples.first()) {
+                        let values = samples.iter().map(|sample| sample.value);
+                        let value = match aggregate {
+                            AggregateFunction::Max => values.reduce(f64::max).unwrap(),
+                            _ => values.sum::<f64>() / samples.len() as f64,
+                        };
+                        samples = vec![Sample {
+                            value,
+                            timestamp: first.timestamp,
+                        }];
+                    }
+                    TimeSeries {
+                        labels: series.labels.clone(),
+                        samples,
+                    }
+                })
+                .collect())
+        }
+    }
+
+    fn series(job: &str, samples: &[(i64, f64)]) -> TimeSeries {
+        TimeSeries {
+            labels: vec![
+                Label {
+                    name: NAME_LABEL.to_string(),
+                    value: "requests".to_string(),
+                },
+                Label {
+                    name: "job".to_string(),
+                    value: job.to_string(),
+                },
+            ],
+            samples: samples
+                .iter()
+                .map(|(timestamp, value)| Sample {
+                    value: *value,
+                    timestamp: *timestamp,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        // counters growing by 1 per second, sampled every 10 seconds, the second one resets
+        let data = vec![
+            series(
+                "a",
+                &(0..=12)
+                    .map(|i| (i * 10_000, i as f64 * 10.0))
+                    .collect::<Vec<_>>(),
+            ),
+            series(
+                "b",
+                &[
+                    (60_000, 50.0),
+                    (70_000, 60.0),
+                    (80_000, 5.0),
+                    (90_000, 15.0),
+                ],
+            ),
+        ];
+        let mut fetch = fetch_from(data);
+
+        let query: PromQl = "rate(requests[1m])".parse().unwrap();
+        let result = evaluate(&query, 60_000, 120_000, 30_000, &mut fetch).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].labels, vec![("job".to_string(), "a".to_string())]);
+        assert_eq!(
+            result[0].points,
+            vec![(60_000, 1.0), (90_000, 1.0), (120_000, 1.0)]
+        );
+        // 10 + 5 (after the reset) + 10 in 30s, extrapolated by half an interval at the start
+        assert_eq!(result[1].points[0], (90_000, 25.0 * 35.0 / 30.0 / 60.0));
+
+        let query: PromQl = "sum(max_over_time(requests[1m]))".parse().unwrap();
+        let result = evaluate(&query, 90_000, 90_000, 1, &mut fetch).unwrap();
+        assert_eq!(
+            result[0],
+            Series {
+                labels: vec![],
+                points: vec![(90_000, 150.0)]
+            }
+        );
+
+        let query: PromQl = "requests".parse().unwrap();
+        let result = evaluate(&query, 85_000, 95_000, 10_000, &mut fetch).unwrap();
+        assert_eq!(result[1].points, vec![(85_000, 5.0), (95_000, 15.0)]);
+
+        let query: PromQl = "requests[1m]".parse().unwrap();
+        assert!(evaluate(&query, 0, 60_000, 1_000, &mut fetch).is_err());
+        assert!(evaluate(&"requests".parse().unwrap(), 0, 60_000_000, 1, &mut fetch).is_err());
+    }
+}
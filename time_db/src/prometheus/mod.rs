@@ -0,0 +1,391 @@
+mod engine;
+mod promql;
+pub mod remote;
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    caller_query_limits,
+    http::{bad_request, is_write_authorized, respond, HttpError},
+    http_types::{HttpRequest, HttpResponse, HttpResponseBuilder},
+    metrics,
+    timedb::{
+        parse_rfc3339, Action, AggregateFunction, Budget, Duration, Entry, QueryError, QueryLimits,
+    },
+    TIME_DB,
+};
+
+use self::{
+    engine::Series,
+    promql::PromQl,
+    remote::{
+        Query, QueryResult, ReadRequest, ReadResponse, ResponseType, TimeSeries, WriteRequest,
+    },
+};
+
+const JSON: &str = "application/json; charset=utf-8";
+
+/// Failure of a call to the Prometheus API, answered as
+/// `{"status": "error", "errorType", "error"}`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: u16,
+    error_type: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn bad_data(message: impl ToString) -> Self {
+        Self {
+            status: 400,
+            error_type: "bad_data",
+            message: message.to_string(),
+        }
+    }
+
+    fn execution(message: impl ToString) -> Self {
+        Self {
+            status: 422,
+            error_type: "execution",
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<QueryError> for ApiError {
+    fn from(err: QueryError) -> Self {
+        match err {
+            QueryError::LimitExceeded { .. } => ApiError::execution(err),
+            QueryError::Invalid(message) => ApiError::bad_data(message),
+        }
+    }
+}
+
+impl From<ApiError> for HttpError {
+    fn from(err: ApiError) -> Self {
+        HttpError::new(err.status, err.message)
+    }
+}
+
+/// Stores the samples of a remote write request (`POST /api/v1/write`), see
+/// `remote::write_entries` for how they are mapped to entries. The request has to carry a
+/// write token.
+pub fn remote_write(req: &HttpRequest) -> Result<(), HttpError> {
+    ensure_post(req)?;
+    if !is_write_authorized(req) {
+        return Err(HttpError::new(401, "A valid write token is required"));
+    }
+
+    let request: WriteRequest = remote::decode(&req.body).map_err(bad_request)?;
+    let samples: usize = request
+        .timeseries
+        .iter()
+        .map(|series| series.samples.len())
+        .sum();
+
+    let measurements = remote::write_entries(request).map_err(|err| {
+        metrics::record_rejected_writes("remote_write", samples as u64);
+        bad_request(err)
+    })?;
+
+    for (name, entries) in &measurements {
+        crate::store_entries("remote_write", name, entries);
+    }
+
+    Ok(())
+}
+
+/// Answers a remote read request (`POST /api/v1/read`) with a snappy compressed
+/// `ReadResponse` holding one result per query.
+///
+/// Only the `SAMPLES` response type is supported.
+pub fn remote_read(req: &HttpRequest) -> Result<HttpResponse, HttpError> {
+    ensure_post(req)?;
+
+    let request: ReadRequest = remote::decode(&req.body).map_err(bad_request)?;
+    if !request.accepted_response_types.is_empty()
+        && !request
+            .accepted_response_types
+            .contains(&(ResponseType::Samples as i32))
+    {
+        return Err(HttpError::new(
+            400,
+            "Only the SAMPLES response type is supported",
+        ));
+    }
+
+    let limits = caller_query_limits();
+    let results = request
+        .queries
+        .iter()
+        .map(|query| select(query, &limits))
+        .collect::<Result<Vec<QueryResult>, ApiError>>()?;
+
+    let body = remote::encode(&ReadResponse { results }).map_err(|err| HttpError::new(500, err))?;
+
+    let mut response = HttpResponseBuilder::with_status(200);
+    response.header("Content-Type", "application/x-protobuf");
+    response.header("Content-Encoding", "snappy");
+    response.with_body_and_content_length(body);
+    Ok(response.build())
+}
+
+/// Answers `/api/v1/query`: evaluates `query` at `time` (now by default) and returns a
+/// vector, or a matrix for range selectors.
+pub fn instant_query(req: &HttpRequest) -> HttpResponse {
+    api_response(req, || {
+        let query = parse_query(req)?;
+        let time = match req.query_param("time") {
+            Some(time) => parse_time(&time)?,
+            None => (crate::now() / 1_000_000) as i64,
+        };
+
+        let limits = caller_query_limits();
+        let budget = Budget::new(&limits);
+        let mut fetch = |matchers: &[_], start, end, aggregate| {
+            read(
+                &Query::new(matchers, start, end),
+                aggregate,
+                &limits,
+                &budget,
+            )
+        };
+
+        Ok(match &query {
+            PromQl::Selector {
+                matchers,
+                range: Some(range),
+            } => matrix(engine::range_samples(matchers, *range, time, &mut fetch)?),
+            _ => {
+                let series = engine::evaluate(&query, time, time, 1, &mut fetch)?;
+                json!({
+                    "resultType": "vector",
+                    "result": series.iter().map(|series| {
+                        let (time, value) = series.points[0];
+                        json!({"metric": metric(series), "value": sample(time, value)})
+                    }).collect::<Vec<_>>(),
+                })
+            }
+        })
+    })
+}
+
+/// Answers `/api/v1/query_range`: evaluates `query` at every `step` from `start` to `end`
+/// and returns a matrix.
+pub fn range_query(req: &HttpRequest) -> HttpResponse {
+    api_response(req, || {
+        let query = parse_query(req)?;
+        let param = |name: &str| {
+            req.query_param(name)
+                .ok_or_else(|| ApiError::bad_data(format!("parameter '{}' is required", name)))
+        };
+        let start = parse_time(&param("start")?)?;
+        let end = parse_time(&param("end")?)?;
+        let step = parse_step(&param("step")?)?;
+
+        let limits = caller_query_limits();
+        let budget = Budget::new(&limits);
+        let mut fetch = |matchers: &[_], start, end, aggregate| {
+            read(
+                &Query::new(matchers, start, end),
+                aggregate,
+                &limits,
+                &budget,
+            )
+        };
+        Ok(matrix(engine::evaluate(
+            &query, start, end, step, &mut fetch,
+        )?))
+    })
+}
+
+impl Query {
+    fn new(matchers: &[remote::LabelMatcher], start: i64, end: i64) -> Self {
+        Self {
+            start_timestamp_ms: start,
+            end_timestamp_ms: end,
+            matchers: matchers.to_vec(),
+        }
+    }
+}
+
+// Reads the series selected by the query. Queries fail rather than return a truncated
+// result, unknown metrics have no series
+fn select(query: &Query, limits: &QueryLimits) -> Result<QueryResult, ApiError> {
+    let (measurement, actions) = remote::query_actions(query).map_err(ApiError::bad_data)?;
+
+    let result = TIME_DB.with(|m| {
+        let db = m.borrow();
+        db.find_measurement(&measurement)
+            .map(|measure| measure.apply(&actions, limits, None))
+            .transpose()
+    })?;
+
+    match result {
+        Some(result) if result.truncated => Err(ApiError::execution(
+            "The query exceeds the query limits, read a shorter time range",
+        )),
+        Some(result) => Ok(remote::query_result(&measurement, &result.entries)),
+        None => Ok(QueryResult { timeseries: vec![] }),
+    }
+}
+
+// Reads the series selected by the query, with their samples aggregated into one by an
+// `AggregateWindow` spanning the query when a function is given. The aggregated reads of
+// an evaluation, one per step, share its budget
+fn read(
+    query: &Query,
+    aggregate: Option<AggregateFunction>,
+    limits: &QueryLimits,
+    budget: &Budget,
+) -> Result<Vec<TimeSeries>, ApiError> {
+    let Some(function) = aggregate else {
+        return select(query, limits).map(|result| result.timeseries);
+    };
+
+    let (measurement, mut actions) = remote::query_actions(query).map_err(ApiError::bad_data)?;
+    let window = query.end_timestamp_ms - query.start_timestamp_ms + 1;
+    actions.push(Action::AggregateWindow(format!("{}ms", window), function));
+
+    let series = TIME_DB.with(|m| {
+        let db = m.borrow();
+        db.find_measurement(&measurement)
+            .map(|measure| measure.apply_by_series(&actions, budget))
+            .transpose()
+    })?;
+
+    // windows have no tags, they get those of their series
+    let entries: Vec<Entry> = series
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|(tags, entries)| {
+            entries.into_iter().map(move |entry| Entry {
+                tags: tags.clone(),
+                ..entry
+            })
+        })
+        .collect();
+    Ok(remote::query_result(&measurement, &entries).timeseries)
+}
+
+fn api_response(
+    req: &HttpRequest,
+    evaluate: impl FnOnce() -> Result<serde_json::Value, ApiError>,
+) -> HttpResponse {
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Reply {
+        status: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<serde_json::Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error_type: Option<&'static str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    }
+
+    let (status, reply) = match evaluate() {
+        Ok(data) => (
+            200,
+            Reply {
+                status: "success",
+                data: Some(data),
+                error_type: None,
+                error: None,
+            },
+        ),
+        Err(err) => (
+            err.status,
+            Reply {
+                status: "error",
+                data: None,
+                error_type: Some(err.error_type),
+                error: Some(err.message),
+            },
+        ),
+    };
+
+    match serde_json::to_vec(&reply) {
+        Ok(body) => respond(req, status, JSON, body),
+        Err(err) => HttpResponseBuilder::server_error(err).build(),
+    }
+}
+
+fn parse_query(req: &HttpRequest) -> Result<PromQl, ApiError> {
+    let query = req
+        .query_param("query")
+        .ok_or_else(|| ApiError::bad_data("parameter 'query' is required"))?;
+    PromQl::from_str(&query).map_err(|err| ApiError::bad_data(format!("invalid query: {}", err)))
+}
+
+// Times are Unix timestamps in seconds, possibly with decimals, or RFC 3339
+fn parse_time(time: &str) -> Result<i64, ApiError> {
+    if let Ok(seconds) = time.parse::<f64>() {
+        if seconds.is_finite() {
+            return Ok((seconds * 1000.0).round() as i64);
+        }
+    }
+    parse_rfc3339(time)
+        .map(|nanos| (nanos / 1_000_000) as i64)
+        .map_err(|_| ApiError::bad_data(format!("invalid time '{}'", time)))
+}
+
+// Steps are seconds, possibly with decimals, or durations like `1m`
+fn parse_step(step: &str) -> Result<i64, ApiError> {
+    let millis = match step.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() => Some((seconds * 1000.0).round() as i64),
+        _ => Duration::parse(step)
+            .ok()
+            .and_then(|step| step.fixed_nanos())
+            .map(|nanos| (nanos / 1_000_000) as i64),
+    };
+
+    millis
+        .filter(|millis| *millis > 0)
+        .ok_or_else(|| ApiError::bad_data(format!("invalid step '{}'", step)))
+}
+
+fn matrix(series: Vec<Series>) -> serde_json::Value {
+    json!({
+        "resultType": "matrix",
+        "result": series.iter().map(|series| json!({
+            "metric": metric(series),
+            "values": series
+                .points
+                .iter()
+                .map(|(time, value)| sample(*time, *value))
+                .collect::<Vec<_>>(),
+        })).collect::<Vec<_>>(),
+    })
+}
+
+fn metric(series: &Series) -> BTreeMap<&str, &str> {
+    series
+        .labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+// Samples are `[seconds, "value"]`
+fn sample(time: i64, value: f64) -> serde_json::Value {
+    let value = match value {
+        value if value == f64::INFINITY => "+Inf".to_string(),
+        value if value == f64::NEG_INFINITY => "-Inf".to_string(),
+        value => value.to_string(),
+    };
+    json!([time as f64 / 1000.0, value])
+}
+
+fn ensure_post(req: &HttpRequest) -> Result<(), HttpError> {
+    match req.method.as_str() {
+        "POST" => Ok(()),
+        method => Err(HttpError::new(
+            405,
+            format!("Method {} is not allowed, use POST", method),
+        )),
+    }
+}
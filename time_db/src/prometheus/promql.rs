@@ -0,0 +1,425 @@
+use std::{fmt, str::FromStr};
+
+use crate::timedb::Duration;
+
+use super::remote::{LabelMatcher, MatchType, NAME_LABEL};
+
+/// The subset of PromQL the canister evaluates.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PromQl {
+    /// `metric{label="value"}`, or with a range `metric{label="value"}[5m]`
+    Selector {
+        matchers: Vec<LabelMatcher>,
+        range: Option<i64>, // milliseconds
+    },
+    /// `rate(metric[5m])`, `avg_over_time(metric[5m])` or `max_over_time(metric[5m])`
+    Function {
+        function: RangeFunction,
+        matchers: Vec<LabelMatcher>,
+        range: i64,
+    },
+    /// `sum by (label) (expression)`, or `sum(expression)` without grouping
+    Sum {
+        by: Vec<String>,
+        expression: Box<PromQl>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RangeFunction {
+    Rate,
+    AvgOverTime,
+    MaxOverTime,
+}
+
+/// Error in a PromQL expression, `position` is the byte offset where parsing stopped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PromQlError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PromQlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+/// Parses expressions like `sum by (job) (rate(http_requests_total{code!="500"}[5m]))`.
+///
+/// Selectors take `=` and `!=` matchers on labels. Regular expression matchers, binary
+/// operators and functions other than `rate`, `avg_over_time` and `max_over_time` are
+/// not supported.
+impl FromStr for PromQl {
+    type Err = PromQlError;
+
+    fn from_str(query: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { query, next: 0 };
+
+        let expression = parser.expression()?;
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => Ok(expression),
+            Some(c) => Err(parser.error(format!("unexpected '{}'", c))),
+        }
+    }
+}
+
+struct Parser<'a> {
+    query: &'a str,
+    next: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn expression(&mut self) -> Result<PromQl, PromQlError> {
+        self.skip_whitespace();
+        let start = self.next;
+        let name = self.identifier();
+
+        match name {
+            "sum" => self.sum(),
+            "rate" | "avg_over_time" | "max_over_time" if self.lookahead('(') => {
+                let function = match name {
+                    "rate" => RangeFunction::Rate,
+                    "avg_over_time" => RangeFunction::AvgOverTime,
+                    _ => RangeFunction::MaxOverTime,
+                };
+                self.expect('(')?;
+                let (matchers, range) = self.selector()?;
+                let range = range.ok_or_else(|| {
+                    self.error(format!(
+                        "{} expects a range selector, e.g. metric[5m]",
+                        name
+                    ))
+                })?;
+                self.expect(')')?;
+                Ok(PromQl::Function {
+                    function,
+                    matchers,
+                    range,
+                })
+            }
+            _ if self.lookahead('(') => Err(PromQlError {
+                position: start,
+                message: format!("function or aggregation '{}' is not supported", name),
+            }),
+            _ => {
+                self.next = start;
+                let (matchers, range) = self.selector()?;
+                Ok(PromQl::Selector { matchers, range })
+            }
+        }
+    }
+
+    // `sum` was read, grouping can come before or after the expression
+    fn sum(&mut self) -> Result<PromQl, PromQlError> {
+        let mut by = self.grouping()?;
+        self.expect('(')?;
+        let expression = self.expression()?;
+        self.expect(')')?;
+        if by.is_none() {
+            by = self.grouping()?;
+        }
+
+        Ok(PromQl::Sum {
+            by: by.unwrap_or_default(),
+            expression: Box::new(expression),
+        })
+    }
+
+    fn grouping(&mut self) -> Result<Option<Vec<String>>, PromQlError> {
+        self.skip_whitespace();
+        let start = self.next;
+        match self.identifier() {
+            "by" => {}
+            "without" => {
+                return Err(PromQlError {
+                    position: start,
+                    message: "'without' is not supported, use 'by'".to_string(),
+                })
+            }
+            _ => {
+                self.next = start;
+                return Ok(None);
+            }
+        }
+
+        self.expect('(')?;
+        let mut labels = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.accept(')') {
+                return Ok(Some(labels));
+            }
+            if !labels.is_empty() {
+                self.expect(',')?;
+                self.skip_whitespace();
+            }
+            match self.identifier() {
+                "" => return Err(self.error("expected a label name".to_string())),
+                label => labels.push(label.to_string()),
+            }
+        }
+    }
+
+    fn selector(&mut self) -> Result<(Vec<LabelMatcher>, Option<i64>), PromQlError> {
+        self.skip_whitespace();
+        let mut matchers = vec![];
+
+        let name = self.identifier();
+        if !name.is_empty() {
+            matchers.push(LabelMatcher {
+                r#type: MatchType::Eq as i32,
+                name: NAME_LABEL.to_string(),
+                value: name.to_string(),
+            });
+        }
+
+        self.skip_whitespace();
+        if self.accept('{') {
+            loop {
+                self.skip_whitespace();
+                if self.accept('}') {
+                    break;
+                }
+                if matchers.len() > usize::from(!name.is_empty()) {
+                    self.expect(',')?;
+                    self.skip_whitespace();
+                    if self.accept('}') {
+                        break; // trailing comma
+                    }
+                }
+                matchers.push(self.matcher()?);
+            }
+        } else if name.is_empty() {
+            return Err(self.error("expected a metric name or a selector".to_string()));
+        }
+
+        self.skip_whitespace();
+        let range = if self.accept('[') {
+            let start = self.next;
+            let end = self.query[start..]
+                .find(']')
+                .map(|len| start + len)
+                .ok_or_else(|| self.error("unterminated range".to_string()))?;
+            self.next = end + 1;
+
+            let range = Duration::parse(&self.query[start..end])
+                .ok()
+                .and_then(|range| range.fixed_nanos())
+                .map(|nanos| (nanos / 1_000_000) as i64)
+                .filter(|millis| *millis > 0)
+                .ok_or_else(|| PromQlError {
+                    position: start,
+                    message: format!("invalid range '{}'", &self.query[start..end]),
+                })?;
+            Some(range)
+        } else {
+            None
+        };
+
+        Ok((matchers, range))
+    }
+
+    fn matcher(&mut self) -> Result<LabelMatcher, PromQlError> {
+        let name = self.identifier().to_string();
+        if name.is_empty() {
+            return Err(self.error("expected a label name".to_string()));
+        }
+
+        self.skip_whitespace();
+        let start = self.next;
+        let match_type = if self.accept_str("=~") {
+            MatchType::Re
+        } else if self.accept_str("!~") {
+            MatchType::Nre
+        } else if self.accept_str("!=") {
+            MatchType::Neq
+        } else if self.accept('=') {
+            MatchType::Eq
+        } else {
+            return Err(self.error("expected =, !=, =~ or !~".to_string()));
+        };
+        if matches!(match_type, MatchType::Re | MatchType::Nre) {
+            return Err(PromQlError {
+                position: start,
+                message: "regular expression matchers are not supported".to_string(),
+            });
+        }
+
+        self.skip_whitespace();
+        Ok(LabelMatcher {
+            r#type: match_type as i32,
+            name,
+            value: self.string()?,
+        })
+    }
+
+    fn string(&mut self) -> Result<String, PromQlError> {
+        let start = self.next;
+        let quote = match self.peek() {
+            Some(quote @ ('"' | '\'' | '`')) => quote,
+            _ => return Err(self.error("expected a quoted string".to_string())),
+        };
+        self.next += 1;
+
+        let mut value = String::new();
+        let mut chars = self.query[self.next..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' if quote != '`' => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, escaped)) => value.push(escaped),
+                    None => break,
+                },
+                c if c == quote => {
+                    self.next += i + 1;
+                    return Ok(value);
+                }
+                c => value.push(c),
+            }
+        }
+
+        Err(PromQlError {
+            position: start,
+            message: "unterminated string".to_string(),
+        })
+    }
+
+    // Metric and label names, metric names may contain colons
+    fn identifier(&mut self) -> &'a str {
+        let start = self.next;
+        let len = self.query[start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == ':'))
+            .unwrap_or(self.query.len() - start);
+        let identifier = &self.query[start..start + len];
+        if identifier.starts_with(|c: char| c.is_ascii_digit()) {
+            return "";
+        }
+        self.next += len;
+        identifier
+    }
+
+    fn lookahead(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        self.peek() == Some(c)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), PromQlError> {
+        self.skip_whitespace();
+        match self.accept(c) {
+            true => Ok(()),
+            false => Err(self.error(format!("expected '{}'", c))),
+        }
+    }
+
+    fn accept(&mut self, c: char) -> bool {
+        match self.peek() {
+            Some(next) if next == c => {
+                self.next += c.len_utf8();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn accept_str(&mut self, s: &str) -> bool {
+        match self.query[self.next..].starts_with(s) {
+            true => {
+                self.next += s.len();
+                true
+            }
+            false => false,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.query[self.next..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.query[self.next..];
+        self.next += rest.len() - rest.trim_start().len();
+    }
+
+    fn error(&self, message: String) -> PromQlError {
+        let found = match self.peek() {
+            Some(c) => format!("'{}'", c),
+            None => "end of query".to_string(),
+        };
+        PromQlError {
+            position: self.next,
+            message: format!("{}, found {}", message, found),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(match_type: MatchType, name: &str, value: &str) -> LabelMatcher {
+        LabelMatcher {
+            r#type: match_type as i32,
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_promql() {
+        assert_eq!(
+            "sum by (job, instance) (rate(http_requests_total{code!=\"500\", method='GET'}[1m30s]))"
+                .parse(),
+            Ok(PromQl::Sum {
+                by: vec!["job".to_string(), "instance".to_string()],
+                expression: Box::new(PromQl::Function {
+                    function: RangeFunction::Rate,
+                    matchers: vec![
+                        matcher(MatchType::Eq, "__name__", "http_requests_total"),
+                        matcher(MatchType::Neq, "code", "500"),
+                        matcher(MatchType::Eq, "method", "GET"),
+                    ],
+                    range: 90_000,
+                }),
+            })
+        );
+
+        assert_eq!(
+            "sum(max_over_time({__name__=\"up\"}[5m])) by (job)".parse(),
+            Ok(PromQl::Sum {
+                by: vec!["job".to_string()],
+                expression: Box::new(PromQl::Function {
+                    function: RangeFunction::MaxOverTime,
+                    matchers: vec![matcher(MatchType::Eq, "__name__", "up")],
+                    range: 300_000,
+                }),
+            })
+        );
+
+        assert_eq!(
+            "node:cpu[1h]".parse(),
+            Ok(PromQl::Selector {
+                matchers: vec![matcher(MatchType::Eq, "__name__", "node:cpu")],
+                range: Some(3_600_000),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_promql_errors() {
+        for (query, position) in [
+            ("up{job=~\"edge.*\"}", 6),
+            ("rate(up)", 7),
+            ("histogram_quantile(0.9, up)", 0),
+            ("up + 1", 3),
+            ("up[1mo]", 3),
+            ("sum without (job) (up)", 4),
+            ("up{job=\"edge}", 7),
+        ] {
+            let error = query.parse::<PromQl>().unwrap_err();
+            assert_eq!(error.position, position, "{}: {}", query, error);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, HashMap};
+
+use prost::Message;
+
+use crate::timedb::{Action, Entry, Expression, Value};
+
+// Messages of the remote write and read protocols, from `prometheus/prompb` (fields that
+// aren't used here, like exemplars, histograms and read hints, are skipped when decoding)
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64, // milliseconds
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReadRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub queries: Vec<Query>,
+    #[prost(enumeration = "ResponseType", repeated, tag = "2")]
+    pub accepted_response_types: Vec<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ResponseType {
+    Samples = 0,
+    StreamedXorChunks = 1,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Query {
+    #[prost(int64, tag = "1")]
+    pub start_timestamp_ms: i64,
+    #[prost(int64, tag = "2")]
+    pub end_timestamp_ms: i64,
+    #[prost(message, repeated, tag = "3")]
+    pub matchers: Vec<LabelMatcher>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelMatcher {
+    #[prost(enumeration = "MatchType", tag = "1")]
+    pub r#type: i32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum MatchType {
+    Eq = 0,
+    Neq = 1,
+    Re = 2,
+    Nre = 3,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReadResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub results: Vec<QueryResult>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct QueryResult {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+/// Label holding the metric name, stored as the measurement.
+pub const NAME_LABEL: &str = "__name__";
+
+/// Field holding the value of a sample.
+pub const VALUE_FIELD: &str = "value";
+
+// Prometheus marks series that disappeared with this NaN
+const STALE_NAN: u64 = 0x7ff0000000000002;
+
+const NANOS_PER_MILLI: u64 = 1_000_000;
+
+/// Decodes a snappy compressed (block format) protobuf message.
+pub fn decode<M: Message + Default>(body: &[u8]) -> Result<M, String> {
+    let decompressed = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|err| format!("Invalid snappy data: {}", err))?;
+    M::decode(&decompressed[..]).map_err(|err| format!("Invalid protobuf message: {}", err))
+}
+
+pub fn encode<M: Message>(message: &M) -> Result<Vec<u8>, String> {
+    snap::raw::Encoder::new()
+        .compress_vec(&message.encode_to_vec())
+        .map_err(|err| err.to_string())
+}
+
+/// Turns the samples of a write request into entries, grouped by measurement.
+///
+/// `__name__` gives the measurement, the other labels become string tags and the sample
+/// a `value` field, an integer when the sample is a whole number and a float otherwise. The whole request is refused when a series has no name or a
+/// sample lies before the Unix epoch, staleness markers are skipped.
+pub fn write_entries(request: WriteRequest) -> Result<BTreeMap<String, Vec<Entry>>, String> {
+    let mut measurements: BTreeMap<String, Vec<Entry>> = BTreeMap::new();
+
+    for series in request.timeseries {
+        let mut name = None;
+        let mut tags = HashMap::new();
+        for label in series.labels {
+            if label.name == NAME_LABEL {
+                name = Some(label.value);
+            } else {
+                tags.insert(label.name, Value::String(label.value));
+            }
+        }
+        let name = name
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| format!("Series without a '{}' label", NAME_LABEL))?;
+
+        let entries = measurements.entry(name).or_default();
+        for sample in series.samples {
+            if sample.value.to_bits() == STALE_NAN {
+                continue;
+            }
+            let timestamp = u64::try_from(sample.timestamp)
+                .map_err(|_| format!("Sample with a negative timestamp {}", sample.timestamp))?;
+
+            entries.push(Entry {
+                timestamp: timestamp.saturating_mul(NANOS_PER_MILLI),
+                fields: HashMap::from([(VALUE_FIELD.to_string(), Value::from_f64(sample.value))]),
+                tags: tags.clone(),
+            });
+        }
+    }
+
+    Ok(measurements)
+}
+
+/// Translates a read query into the measurement to read and the actions to run on it.
+///
+/// The query needs an equality matcher on `__name__`, other matchers become filters on
+/// tags. As in Prometheus a missing label is the same as an empty one. Regular expression
+/// matchers are not supported.
+pub fn query_actions(query: &Query) -> Result<(String, Vec<Action>), String> {
+    let mut measurement = None;
+    let mut actions = vec![Action::Range(
+        millis_to_nanos(query.start_timestamp_ms),
+        Some(millis_to_nanos(query.end_timestamp_ms).saturating_add(NANOS_PER_MILLI - 1)),
+    )];
+
+    for matcher in &query.matchers {
+        let match_type = MatchType::try_from(matcher.r#type)
+            .map_err(|_| format!("Unknown matcher type {}", matcher.r#type))?;
+        let name = matcher.name.clone();
+        let value = Value::String(matcher.value.clone());
+        // strings compare lexicographically, every non empty string is greater than ""
+        let is_set = || Expression::Gt(name.clone(), Value::String(String::new()));
+
+        let expression = match (match_type, matcher.value.is_empty()) {
+            (MatchType::Eq, _) if name == NAME_LABEL => {
+                measurement = Some(matcher.value.clone());
+                continue;
+            }
+            (MatchType::Eq, false) => Expression::Eq(name, value),
+            (MatchType::Eq, true) => Expression::Not(Box::new(is_set())),
+            (MatchType::Neq, false) => Expression::Not(Box::new(Expression::Eq(name, value))),
+            (MatchType::Neq, true) => is_set(),
+            (MatchType::Re, _) | (MatchType::Nre, _) => {
+                return Err(format!(
+                    "Regular expression matcher on '{}' is not supported",
+                    matcher.name
+                ))
+            }
+        };
+        actions.push(Action::Filter(expression));
+    }
+
+    match measurement {
+        Some(measurement) if !measurement.is_empty() => Ok((measurement, actions)),
+        _ => Err(format!(
+            "Queries need an equality matcher on '{}'",
+            NAME_LABEL
+        )),
+    }
+}
+
+/// Groups entries into series by their tags, with the measurement as `__name__`. Entries
+/// without a numeric `value` field are left out.
+pub fn query_result(measurement: &str, entries: &[Entry]) -> QueryResult {
+    let mut series: BTreeMap<Vec<(String, String)>, Vec<Sample>> = BTreeMap::new();
+
+    for entry in entries {
+        let value = match entry.fields.get(VALUE_FIELD) {
+            Some(Value::Float(value)) => *value as f64,
+            Some(Value::Int(value)) => *value as f64,
+            Some(Value::UInt(value)) => *value as f64,
+            _ => continue,
+        };
+
+        let mut labels: Vec<(String, String)> = entry
+            .tags
+            .iter()
+            .filter_map(|(name, value)| match value {
+                Value::String(value) => Some((name.clone(), value.clone())),
+                Value::Int(value) => Some((name.clone(), value.to_string())),
+                Value::UInt(value) => Some((name.clone(), value.to_string())),
+                Value::Float(value) => Some((name.clone(), value.to_string())),
+                Value::Bool(value) => Some((name.clone(), value.to_string())),
+                Value::None => None,
+            })
+            .chain(std::iter::once((
+                NAME_LABEL.to_string(),
+                measurement.to_string(),
+            )))
+            .collect();
+        labels.sort();
+
+        series.entry(labels).or_default().push(Sample {
+            value,
+            timestamp: (entry.timestamp / NANOS_PER_MILLI) as i64,
+        });
+    }
+
+    QueryResult {
+        timeseries: series
+            .into_iter()
+            .map(|(labels, samples)| TimeSeries {
+                labels: labels
+                    .into_iter()
+                    .map(|(name, value)| Label { name, value })
+                    .collect(),
+                samples,
+            })
+            .collect(),
+    }
+}
+
+fn millis_to_nanos(millis: i64) -> u64 {
+    u64::try_from(millis)
+        .unwrap_or(0)
+        .saturating_mul(NANOS_PER_MILLI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(labels: &[(&str, &str)], samples: &[(i64, f64)]) -> TimeSeries {
+        TimeSeries {
+            labels: labels
+                .iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+            samples: samples
+                .iter()
+                .map(|(timestamp, value)| Sample {
+                    value: *value,
+                    timestamp: *timestamp,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let request = WriteRequest {
+            timeseries: vec![
+                series(
+                    &[("__name__", "up"), ("job", "edge")],
+                    &[(1000, 1.0), (2000, f64::from_bits(STALE_NAN))],
+                ),
+                series(&[("__name__", "up"), ("job", "core")], &[(1500, 0.0)]),
+            ],
+        };
+        let request: WriteRequest = decode(&encode(&request).unwrap()).unwrap();
+
+        let measurements = write_entries(request).unwrap();
+        let entries = &measurements["up"];
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].timestamp, 1_000_000_000);
+        assert_eq!(
+            entries[1].tags.get("job"),
+            Some(&Value::String("core".to_string()))
+        );
+
+        let result = query_result("up", entries);
+        assert_eq!(result.timeseries.len(), 2);
+        assert_eq!(
+            result.timeseries[1],
+            series(&[("__name__", "up"), ("job", "edge")], &[(1000, 1.0)])
+        );
+
+        let nameless = WriteRequest {
+            timeseries: vec![series(&[("job", "edge")], &[(1000, 1.0)])],
+        };
+        assert!(write_entries(nameless).is_err());
+    }
+
+    #[test]
+    fn test_write_and_read_keep_precision() {
+        let samples = [(1000, 16_777_217.0), (2000, 1.5), (3000, -1e12)];
+        let request = WriteRequest {
+            timeseries: vec![series(&[("__name__", "requests")], &samples)],
+        };
+
+        let measurements = write_entries(request).unwrap();
+        let entries = &measurements["requests"];
+        assert_eq!(
+            entries[0].fields.get("value"),
+            Some(&Value::Int(16_777_217))
+        );
+        assert_eq!(entries[1].fields.get("value"), Some(&Value::Float(1.5)));
+
+        let result = query_result("requests", entries);
+        assert_eq!(
+            result.timeseries,
+            vec![series(&[("__name__", "requests")], &samples)]
+        );
+    }
+
+    #[test]
+    fn test_query_actions() {
+        let matcher = |match_type: MatchType, name: &str, value: &str| LabelMatcher {
+            r#type: match_type as i32,
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let mut query = Query {
+            start_timestamp_ms: 1,
+            end_timestamp_ms: 2,
+            matchers: vec![
+                matcher(MatchType::Eq, "__name__", "up"),
+                matcher(MatchType::Neq, "job", "edge"),
+                matcher(MatchType::Eq, "instance", ""),
+            ],
+        };
+
+        let (measurement, actions) = query_actions(&query).unwrap();
+        assert_eq!(measurement, "up");
+        assert_eq!(
+            format!("{:?}", actions),
+            format!(
+                "{:?}",
+                vec![
+                    Action::Range(1_000_000, Some(2_999_999)),
+                    Action::Filter(Expression::Not(Box::new(Expression::Eq(
+                        "job".to_string(),
+                        Value::String("edge".to_string())
+                    )))),
+                    Action::Filter(Expression::Not(Box::new(Expression::Gt(
+                        "instance".to_string(),
+                        Value::String(String::new())
+                    )))),
+                ]
+            )
+        );
+
+        query.matchers.push(matcher(MatchType::Re, "job", "e.*"));
+        assert!(query_actions(&query).is_err());
+        query.matchers.remove(0);
+        query.matchers.pop();
+        assert!(query_actions(&query).is_err());
+    }
+}
@@ -5,58 +5,22 @@ use serde::Deserialize;
 
 use super::{
     aggregate::AggregateFunction,
+    duration::Duration,
     entry::{Entry, Value},
     expression::Expression,
-    index::Indexes,
     query::QueryResponse,
+    registry::DEVICES,
 };
 
-#[derive(Clone, CandidType, Deserialize)]
+#[derive(Clone, CandidType, Deserialize, Debug)]
 pub enum Action {
     Range(u64, Option<u64>), //start and optional end of range in timestamp
     Filter(Expression),      //filter the results using expression
-    AggregateWindow(String, AggregateFunction), //Aggregate window specification, function to use
+    AggregateWindow(String, AggregateFunction), //Aggregate window duration (e.g. "1h30m", "1mo"), function to use
+    Enrich(String), //adds the attributes of the registered device whose id is the value of this tag as tags
 }
 
 impl Action {
-    pub fn init(&self, indexes: &Indexes) -> Result<QueryResponse, Box<dyn Error>> {
-        let mut query_response = QueryResponse::new();
-
-        match self {
-            Action::Range(start, end) => {
-                let range = indexes
-                    .main_index
-                    .range(start..=&end.unwrap_or(u64::MAX))
-                    .map(|(_, entry)| entry.clone())
-                    .collect();
-
-                query_response.items = range;
-            }
-
-            Action::Filter(expression) => {
-                let entries = indexes.values();
-                query_response.items = entries;
-                expression.filter(&mut query_response);
-
-                let mut filtered = Vec::new();
-                for entry in query_response.items {
-                    if expression.evaluate(&entry) {
-                        filtered.push(entry);
-                    }
-                }
-
-                query_response.items = filtered;
-            }
-            Action::AggregateWindow(aggregate_function, window_size_str) => {
-                let entries = indexes.values();
-                query_response.items =
-                    Action::aggregate_entries(&entries, &aggregate_function, window_size_str)?;
-            }
-        };
-
-        Ok(query_response)
-    }
-
     pub fn evaluate(
         &self,
         query_response: &QueryResponse,
@@ -87,9 +51,20 @@ impl Action {
 
                 output.items = filtered;
             }
-            Action::AggregateWindow(aggregate_function, window_size_str) => {
+            Action::AggregateWindow(window_size_str, aggregate_function) => {
                 output.items =
-                    Action::aggregate_entries(&output.items, &aggregate_function, window_size_str)?;
+                    Action::aggregate_entries(&output.items, window_size_str, aggregate_function)?;
+            }
+            Action::Enrich(tag) => {
+                DEVICES.with(|d| {
+                    let devices = d.borrow();
+                    for entry in output.items.iter_mut() {
+                        let tags = devices.enrichment(tag, &entry.tags);
+                        if !tags.is_empty() {
+                            Rc::make_mut(entry).tags.extend(tags);
+                        }
+                    }
+                });
             }
         };
 
@@ -97,95 +72,82 @@ impl Action {
     }
 
     pub fn aggregate_entries(
-        entries: &Vec<Rc<Entry>>,
+        entries: &[Rc<Entry>],
         window_size_str: &str,
         aggregate_function: &AggregateFunction,
     ) -> Result<Vec<Rc<Entry>>, Box<dyn Error>> {
-        let window_size = match Action::parse_window_size(window_size_str) {
-            Some(size) => size,
-            None => return Ok(Vec::new()), // Return empty if the window size is invalid
-        };
+        let window_size = Duration::parse(window_size_str)
+            .map_err(|err| format!("Invalid aggregate window '{}': {}", window_size_str, err))?;
 
-        let mut sorted_entries = entries.clone();
+        let mut sorted_entries = entries.to_vec();
         sorted_entries.sort_by_key(|entry| entry.timestamp);
 
         let mut windowed_results: Vec<Rc<Entry>> = Vec::new();
         let mut window_fields: HashMap<String, Vec<Value>> = HashMap::new(); //Contains a list of fields with their values for every entry in current window
-        let mut current_window_start = None;
+        let mut current_window: Option<(u64, u64)> = None; //Start and end of the current window
 
         for entry in sorted_entries {
             let timestamp = entry.timestamp;
-            current_window_start = current_window_start.or(Some(timestamp));
-            let window_end = current_window_start.unwrap() + window_size;
-
-            if timestamp >= window_end {
-                let mut aggregated_fields = HashMap::new();
-
-                for (field, values) in window_fields.iter() {
-                    let aggregated_value = match aggregate_function {
-                        AggregateFunction::Mean => AggregateFunction::mean(values)?,
-                        AggregateFunction::Max => AggregateFunction::max(values)?,
-                        AggregateFunction::Min => AggregateFunction::min(values)?,
-                        AggregateFunction::Sum => AggregateFunction::sum(values)?,
-                    };
-                    aggregated_fields.insert(field.clone(), aggregated_value);
-                }
 
-                windowed_results.push(Rc::new(Entry {
-                    timestamp: current_window_start.unwrap(),
-                    fields: aggregated_fields,
-                    tags: HashMap::new(),
-                }));
+            match current_window {
+                Some((start, end)) if timestamp >= end => {
+                    windowed_results.push(Rc::new(Action::aggregate_window(
+                        start,
+                        &window_fields,
+                        aggregate_function,
+                    )?));
+                    window_fields.clear();
+                    current_window = None;
+                }
+                _ => {}
+            }
 
-                current_window_start = Some(timestamp);
-                window_fields.clear();
+            if current_window.is_none() {
+                let end = window_size.add_to(timestamp).ok_or_else(|| {
+                    format!("Aggregate window '{}' overflows timestamp", window_size)
+                })?;
+                current_window = Some((timestamp, end));
             }
 
             //Fill window_fields with all the field names that are there
             for (field, value) in &entry.fields {
                 window_fields
                     .entry(field.clone())
-                    .or_insert_with(Vec::new)
+                    .or_default()
                     .push(value.clone());
             }
         }
 
         // Process the last window
-        if !window_fields.is_empty() {
-            let mut aggregated_fields = HashMap::new();
-
-            for (field, values) in window_fields.iter() {
-                let aggregated_value = match aggregate_function {
-                    AggregateFunction::Mean => AggregateFunction::mean(values)?,
-                    AggregateFunction::Max => AggregateFunction::max(values)?,
-                    AggregateFunction::Min => AggregateFunction::min(values)?,
-                    AggregateFunction::Sum => AggregateFunction::sum(values)?,
-                };
-                aggregated_fields.insert(field.clone(), aggregated_value);
+        if let Some((start, _)) = current_window {
+            if !window_fields.is_empty() {
+                windowed_results.push(Rc::new(Action::aggregate_window(
+                    start,
+                    &window_fields,
+                    aggregate_function,
+                )?));
             }
-
-            windowed_results.push(Rc::new(Entry {
-                timestamp: current_window_start.unwrap(),
-                fields: aggregated_fields,
-                tags: HashMap::new(),
-            }));
         }
 
         Ok(windowed_results)
     }
 
-    fn parse_window_size(window_size_str: &str) -> Option<u64> {
-        let len = window_size_str.len();
-        let (num_str, unit) = window_size_str.split_at(len - 1);
-        let num = num_str.parse::<u64>().ok()?;
-
-        match unit {
-            "s" => Some(num),                // seconds
-            "m" => Some(num * 60),           // minutes
-            "h" => Some(num * 60 * 60),      // hours
-            "d" => Some(num * 60 * 60 * 24), // days
-            _ => None,
+    pub(crate) fn aggregate_window(
+        start: u64,
+        window_fields: &HashMap<String, Vec<Value>>,
+        aggregate_function: &AggregateFunction,
+    ) -> Result<Entry, Box<dyn Error>> {
+        let mut aggregated_fields = HashMap::new();
+
+        for (field, values) in window_fields.iter() {
+            aggregated_fields.insert(field.clone(), aggregate_function.apply(values)?);
         }
+
+        Ok(Entry {
+            timestamp: start,
+            fields: aggregated_fields,
+            tags: HashMap::new(),
+        })
     }
 }
 
@@ -195,26 +157,70 @@ mod tests {
 
     use super::*;
 
+    fn query_response(entries: Vec<Entry>) -> QueryResponse {
+        let mut query_response = QueryResponse::new();
+        query_response.items = entries.into_iter().map(Rc::new).collect();
+        query_response
+    }
+
     #[test]
     fn test_range_action() {
-        let entries = create_test_entries();
-
-        let mut indexes = Indexes::new();
-        for entry in entries {
-            indexes.insert(entry.timestamp, entry);
-        }
+        let query_response = query_response(create_test_entries());
 
         let start = 1625230000;
         let range = 3*30*24*60*60;
         let end = start + range;
 
         let action = Action::Range(start, Some(end));
-        let query_response = action.init(&indexes).unwrap();
+        let query_response = action.evaluate(&query_response).unwrap();
 
         for entry in query_response.items {
             assert!(entry.timestamp >= start && entry.timestamp <= end);
         }
     }
 
-    // Additional test cases for Filter, AggregateWindow, etc...
-}
\ No newline at end of file
+    #[test]
+    fn test_aggregate_window_action() {
+        let hour = 60 * 60 * 1_000_000_000;
+        let entries = [10, 20, 30, 40]
+            .iter()
+            .enumerate()
+            .map(|(i, temperature)| Entry {
+                timestamp: i as u64 * hour / 2,
+                fields: HashMap::from([("temperature".to_string(), Value::Int(*temperature))]),
+                tags: HashMap::new(),
+            })
+            .collect();
+
+        let action = Action::AggregateWindow("1h".to_string(), AggregateFunction::Sum);
+        let query_response = action.evaluate(&query_response(entries)).unwrap();
+
+        assert_eq!(query_response.items.len(), 2);
+        assert_eq!(query_response.items[0].timestamp, 0);
+        assert_eq!(
+            query_response.items[0].fields["temperature"],
+            Value::Float(30.0)
+        );
+        assert_eq!(query_response.items[1].timestamp, hour);
+        assert_eq!(
+            query_response.items[1].fields["temperature"],
+            Value::Float(70.0)
+        );
+    }
+
+    #[test]
+    fn test_aggregate_window_invalid_spec() {
+        let query_response = QueryResponse::new();
+
+        for spec in ["", "10", "5x"] {
+            let action = Action::AggregateWindow(spec.to_string(), AggregateFunction::Mean);
+            assert!(
+                action.evaluate(&query_response).is_err(),
+                "spec '{}' should be rejected",
+                spec
+            );
+        }
+    }
+
+    // Additional test cases for Filter, etc...
+}
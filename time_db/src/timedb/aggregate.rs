@@ -3,10 +3,11 @@ use serde::Deserialize;
 
 use std::boxed::Box;
 use std::error::Error;
+use std::str::FromStr;
 
 use super::entry::Value;
 
-#[derive(Clone, CandidType, Deserialize)]
+#[derive(Clone, CandidType, Deserialize, Debug)]
 pub enum AggregateFunction {
     Mean,
     Max,
@@ -14,8 +15,34 @@ pub enum AggregateFunction {
     Sum,
 }
 
+impl FromStr for AggregateFunction {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "mean" => Ok(AggregateFunction::Mean),
+            "max" => Ok(AggregateFunction::Max),
+            "min" => Ok(AggregateFunction::Min),
+            "sum" => Ok(AggregateFunction::Sum),
+            _ => Err(format!(
+                "Unknown aggregate function '{}' (expected one of mean, max, min, sum)",
+                name
+            )),
+        }
+    }
+}
+
 impl AggregateFunction {
-    pub fn mean(values: &Vec<Value>) -> Result<Value, Box<dyn Error>> {
+    pub fn apply(&self, values: &[Value]) -> Result<Value, Box<dyn Error>> {
+        match self {
+            AggregateFunction::Mean => AggregateFunction::mean(values),
+            AggregateFunction::Max => AggregateFunction::max(values),
+            AggregateFunction::Min => AggregateFunction::min(values),
+            AggregateFunction::Sum => AggregateFunction::sum(values),
+        }
+    }
+
+    pub fn mean(values: &[Value]) -> Result<Value, Box<dyn Error>> {
         if values.is_empty() {
             return Ok(Value::None);
         }
@@ -0,0 +1,493 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    mem::size_of,
+};
+
+use super::entry::Value;
+
+/// Sealed block of points of one series, stored column by column.
+///
+/// Timestamps are delta-of-delta encoded, floats use Gorilla XOR compression, integers
+/// are stored as zig-zag varint deltas and strings through a per column dictionary.
+/// Chunks are immutable, a write into a sealed time range re-encodes the chunk.
+pub struct Chunk {
+    pub min_timestamp: u64,
+    pub max_timestamp: u64,
+    pub len: usize,
+    timestamps: Vec<u8>,
+    columns: Vec<Column>,
+}
+
+struct Column {
+    name: String,
+    present: Vec<u8>, // bitmap of points that have the field, empty when all of them do
+    data: ColumnData,
+}
+
+enum ColumnData {
+    Float(Vec<u8>),
+    Int(Vec<u8>),
+    UInt(Vec<u8>),
+    Bool(Vec<u8>),
+    String(Vec<String>, Vec<u8>), // dictionary, varint indexes into it
+    None,
+    Mixed(Vec<Value>), // values of different types, kept as they are
+}
+
+impl Chunk {
+    /// Encodes points ordered by timestamp, `rows` must not be empty.
+    pub fn encode(rows: &[(u64, &HashMap<String, Value>)]) -> Chunk {
+        let mut timestamps = Vec::new();
+        let mut previous = 0u64;
+        let mut previous_delta = 0i128;
+        for (i, (timestamp, _)) in rows.iter().enumerate() {
+            if i == 0 {
+                write_varint(&mut timestamps, *timestamp as u128);
+            } else {
+                let delta = (timestamp - previous) as i128;
+                write_varint(&mut timestamps, zigzag(delta - previous_delta));
+                previous_delta = delta;
+            }
+            previous = *timestamp;
+        }
+
+        let names: BTreeSet<&String> = rows.iter().flat_map(|(_, fields)| fields.keys()).collect();
+        let columns = names
+            .into_iter()
+            .map(|name| {
+                let values: Vec<Option<&Value>> =
+                    rows.iter().map(|(_, fields)| fields.get(name)).collect();
+                Column::encode(name, &values)
+            })
+            .collect();
+
+        Chunk {
+            min_timestamp: rows[0].0,
+            max_timestamp: previous,
+            len: rows.len(),
+            timestamps,
+            columns,
+        }
+    }
+
+    pub fn decode(&self) -> Vec<(u64, HashMap<String, Value>)> {
+        let mut rows = Vec::with_capacity(self.len);
+        let mut pos = 0;
+        let mut previous = 0u64;
+        let mut previous_delta = 0i128;
+        for i in 0..self.len {
+            let timestamp = if i == 0 {
+                read_varint(&self.timestamps, &mut pos) as u64
+            } else {
+                previous_delta += unzigzag(read_varint(&self.timestamps, &mut pos));
+                previous + previous_delta as u64
+            };
+            previous = timestamp;
+            rows.push((timestamp, HashMap::with_capacity(self.columns.len())));
+        }
+
+        for column in &self.columns {
+            column.decode(&mut rows);
+        }
+
+        rows
+    }
+
+    /// Bytes used by the chunk.
+    pub fn size(&self) -> usize {
+        size_of::<Chunk>()
+            + self.timestamps.len()
+            + self
+                .columns
+                .iter()
+                .map(|column| column.size())
+                .sum::<usize>()
+    }
+}
+
+impl Column {
+    fn encode(name: &str, values: &[Option<&Value>]) -> Column {
+        let present = if values.iter().all(|value| value.is_some()) {
+            vec![]
+        } else {
+            bitmap(values.iter().map(|value| value.is_some()))
+        };
+        let values: Vec<&Value> = values.iter().flatten().copied().collect();
+
+        let same_type = values
+            .windows(2)
+            .all(|pair| std::mem::discriminant(pair[0]) == std::mem::discriminant(pair[1]));
+
+        let data = match values.first() {
+            Some(first) if same_type => match first {
+                Value::Float(_) => {
+                    ColumnData::Float(encode_floats(values.iter().map(|value| match value {
+                        Value::Float(value) => *value,
+                        _ => unreachable!(),
+                    })))
+                }
+                Value::Int(_) => {
+                    let mut buf = Vec::new();
+                    let mut previous = 0i128;
+                    for value in &values {
+                        if let Value::Int(value) = value {
+                            write_varint(&mut buf, zigzag(value.wrapping_sub(previous)));
+                            previous = *value;
+                        }
+                    }
+                    ColumnData::Int(buf)
+                }
+                Value::UInt(_) => {
+                    let mut buf = Vec::new();
+                    let mut previous = 0u128;
+                    for value in &values {
+                        if let Value::UInt(value) = value {
+                            write_varint(&mut buf, zigzag(value.wrapping_sub(previous) as i128));
+                            previous = *value;
+                        }
+                    }
+                    ColumnData::UInt(buf)
+                }
+                Value::Bool(_) => ColumnData::Bool(bitmap(
+                    values
+                        .iter()
+                        .map(|value| matches!(value, Value::Bool(true))),
+                )),
+                Value::String(_) => {
+                    let mut dictionary: Vec<String> = Vec::new();
+                    let mut lookup: HashMap<&str, usize> = HashMap::new();
+                    let mut buf = Vec::new();
+                    for value in &values {
+                        if let Value::String(value) = value {
+                            let index = *lookup.entry(value).or_insert_with(|| {
+                                dictionary.push(value.clone());
+                                dictionary.len() - 1
+                            });
+                            write_varint(&mut buf, index as u128);
+                        }
+                    }
+                    ColumnData::String(dictionary, buf)
+                }
+                Value::None => ColumnData::None,
+            },
+            _ => ColumnData::Mixed(values.into_iter().cloned().collect()),
+        };
+
+        Column {
+            name: name.to_string(),
+            present,
+            data,
+        }
+    }
+
+    fn decode(&self, rows: &mut [(u64, HashMap<String, Value>)]) {
+        let rows = rows
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| self.present.is_empty() || bit(&self.present, *i))
+            .map(|(_, (_, fields))| fields);
+
+        let mut pos = 0;
+        match &self.data {
+            ColumnData::Float(buf) => {
+                let mut reader = FloatDecoder::new(buf);
+                for fields in rows {
+                    fields.insert(self.name.clone(), Value::Float(reader.next()));
+                }
+            }
+            ColumnData::Int(buf) => {
+                let mut previous = 0i128;
+                for fields in rows {
+                    previous = previous.wrapping_add(unzigzag(read_varint(buf, &mut pos)));
+                    fields.insert(self.name.clone(), Value::Int(previous));
+                }
+            }
+            ColumnData::UInt(buf) => {
+                let mut previous = 0u128;
+                for fields in rows {
+                    previous = previous.wrapping_add(unzigzag(read_varint(buf, &mut pos)) as u128);
+                    fields.insert(self.name.clone(), Value::UInt(previous));
+                }
+            }
+            ColumnData::Bool(buf) => {
+                for (i, fields) in rows.enumerate() {
+                    fields.insert(self.name.clone(), Value::Bool(bit(buf, i)));
+                }
+            }
+            ColumnData::String(dictionary, buf) => {
+                for fields in rows {
+                    let index = read_varint(buf, &mut pos) as usize;
+                    fields.insert(self.name.clone(), Value::String(dictionary[index].clone()));
+                }
+            }
+            ColumnData::None => {
+                for fields in rows {
+                    fields.insert(self.name.clone(), Value::None);
+                }
+            }
+            ColumnData::Mixed(values) => {
+                for (fields, value) in rows.zip(values.iter()) {
+                    fields.insert(self.name.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        let data = match &self.data {
+            ColumnData::Float(buf)
+            | ColumnData::Int(buf)
+            | ColumnData::UInt(buf)
+            | ColumnData::Bool(buf) => buf.len(),
+            ColumnData::String(dictionary, buf) => {
+                buf.len()
+                    + dictionary
+                        .iter()
+                        .map(|value| size_of::<String>() + value.len())
+                        .sum::<usize>()
+            }
+            ColumnData::None => 0,
+            ColumnData::Mixed(values) => values
+                .iter()
+                .map(|value| size_of::<Value>() + value.size_estimate())
+                .sum(),
+        };
+
+        size_of::<Column>() + self.name.len() + self.present.len() + data
+    }
+}
+
+fn zigzag(value: i128) -> u128 {
+    ((value << 1) ^ (value >> 127)) as u128
+}
+
+fn unzigzag(value: u128) -> i128 {
+    (value >> 1) as i128 ^ -((value & 1) as i128)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u128) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> u128 {
+    let mut value = 0u128;
+    let mut shift = 0;
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u128) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+fn bitmap(bits: impl Iterator<Item = bool>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (i, set) in bits.enumerate() {
+        if i % 8 == 0 {
+            buf.push(0);
+        }
+        if set {
+            buf[i / 8] |= 1 << (i % 8);
+        }
+    }
+    buf
+}
+
+fn bit(buf: &[u8], i: usize) -> bool {
+    buf[i / 8] & (1 << (i % 8)) != 0
+}
+
+// Gorilla XOR compression adapted to 32 bit floats: a value equal to the previous one takes
+// a single bit, otherwise only the bits that differ are written, framed by the number of
+// leading zeros and the length of the meaningful bits.
+fn encode_floats(values: impl Iterator<Item = f32>) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    let mut previous = 0u32;
+    let mut window: Option<(u32, u32)> = None; // leading and trailing zeros of the last written block
+
+    for (i, value) in values.enumerate() {
+        let bits = value.to_bits();
+        let xor = bits ^ previous;
+        previous = bits;
+
+        if i == 0 {
+            writer.write(bits as u64, 32);
+            continue;
+        }
+
+        if xor == 0 {
+            writer.write(0, 1);
+            continue;
+        }
+
+        writer.write(1, 1);
+        let leading = xor.leading_zeros();
+        let trailing = xor.trailing_zeros();
+
+        match window {
+            Some((window_leading, window_trailing))
+                if leading >= window_leading && trailing >= window_trailing =>
+            {
+                writer.write(0, 1);
+                writer.write(
+                    (xor >> window_trailing) as u64,
+                    32 - window_leading - window_trailing,
+                );
+            }
+            _ => {
+                let len = 32 - leading - trailing;
+                writer.write(1, 1);
+                writer.write(leading as u64, 5);
+                writer.write((len - 1) as u64, 5);
+                writer.write((xor >> trailing) as u64, len);
+                window = Some((leading, trailing));
+            }
+        }
+    }
+
+    writer.buf
+}
+
+struct FloatDecoder<'a> {
+    reader: BitReader<'a>,
+    previous: Option<u32>,
+    window: (u32, u32),
+}
+
+impl<'a> FloatDecoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self {
+            reader: BitReader { buf, pos: 0 },
+            previous: None,
+            window: (0, 0),
+        }
+    }
+
+    fn next(&mut self) -> f32 {
+        let bits = match self.previous {
+            None => self.reader.read(32) as u32,
+            Some(previous) => {
+                if self.reader.read(1) == 0 {
+                    previous
+                } else {
+                    if self.reader.read(1) == 1 {
+                        let leading = self.reader.read(5) as u32;
+                        let len = self.reader.read(5) as u32 + 1;
+                        self.window = (leading, 32 - leading - len);
+                    }
+                    let (leading, trailing) = self.window;
+                    let xor = (self.reader.read(32 - leading - trailing) as u32) << trailing;
+                    previous ^ xor
+                }
+            }
+        };
+
+        self.previous = Some(bits);
+        f32::from_bits(bits)
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    buf: Vec<u8>,
+    len: usize, // bits written
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            if self.len & 7 == 0 {
+                self.buf.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                self.buf[self.len / 8] |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    buf: &'a [u8],
+    pos: usize, // bits read
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self, bits: u32) -> u64 {
+        let mut value = 0u64;
+        for _ in 0..bits {
+            let bit = (self.buf[self.pos / 8] >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.pos += 1;
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_roundtrip() {
+        let rows: Vec<(u64, HashMap<String, Value>)> = (0..500u64)
+            .map(|i| {
+                let mut fields = HashMap::from([
+                    (
+                        "temperature".to_string(),
+                        Value::Float(20.0 + (i % 7) as f32 * 0.25),
+                    ),
+                    ("count".to_string(), Value::Int(i as i128 * 3 - 700)),
+                    ("total".to_string(), Value::UInt(i as u128 * 1000)),
+                    ("open".to_string(), Value::Bool(i % 3 == 0)),
+                    (
+                        "state".to_string(),
+                        Value::String(format!("state_{}", i % 4)),
+                    ),
+                ]);
+                if i % 5 == 0 {
+                    fields.insert("mixed".to_string(), Value::Int(i as i128));
+                } else if i % 5 == 1 {
+                    fields.insert("mixed".to_string(), Value::Float(i as f32));
+                }
+                // irregular intervals exercise the delta-of-delta encoding
+                (
+                    1_700_000_000_000_000_000 + i * 1_000_000_000 + (i % 3) * 17,
+                    fields,
+                )
+            })
+            .collect();
+
+        let borrowed: Vec<(u64, &HashMap<String, Value>)> = rows
+            .iter()
+            .map(|(timestamp, fields)| (*timestamp, fields))
+            .collect();
+        let chunk = Chunk::encode(&borrowed);
+
+        assert_eq!(chunk.len, 500);
+        assert_eq!(chunk.min_timestamp, rows[0].0);
+        assert_eq!(chunk.max_timestamp, rows[499].0);
+        assert_eq!(chunk.decode(), rows);
+    }
+
+    #[test]
+    fn test_float_compression() {
+        let values = [21.5f32, 21.5, 21.5, 21.75, 22.0, 21.75, -3.1, f32::MAX, 0.0];
+        let encoded = encode_floats(values.iter().copied());
+
+        let mut decoder = FloatDecoder::new(&encoded);
+        for value in values {
+            assert_eq!(decoder.next(), value);
+        }
+
+        // repeated values take a single bit each
+        assert!(encode_floats([21.5f32; 1000].into_iter()).len() < 4 + 1000 / 8 + 1);
+    }
+}
@@ -0,0 +1,440 @@
+use std::{error::Error, fmt, str::FromStr};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Length of an aggregate window, parsed from specs like `15m`, `1h30m`, `250ms` or `1mo`.
+///
+/// Timestamps stored in TimeDb are nanoseconds since the Unix epoch, so fixed units are
+/// kept as nanoseconds. Calendar months (and years) have no fixed length and are kept
+/// apart, they are only resolved when applied to a concrete timestamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Duration {
+    months: u32,
+    nanos: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DurationError {
+    Empty,
+    MissingNumber(String), // unit that is not preceded by a number, e.g. `h`
+    MissingUnit(String),   // number that is not followed by a unit, e.g. `10`
+    UnknownUnit(String),
+    Zero,
+    Overflow,
+}
+
+impl fmt::Display for DurationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DurationError::Empty => write!(f, "duration is empty"),
+            DurationError::MissingNumber(unit) => {
+                write!(f, "unit '{}' is not preceded by a number", unit)
+            }
+            DurationError::MissingUnit(number) => write!(
+                f,
+                "number '{}' has no unit (expected one of ns, us, ms, s, m, h, d, w, mo, y)",
+                number
+            ),
+            DurationError::UnknownUnit(unit) => write!(
+                f,
+                "unknown unit '{}' (expected one of ns, us, ms, s, m, h, d, w, mo, y)",
+                unit
+            ),
+            DurationError::Zero => write!(f, "duration must be greater than zero"),
+            DurationError::Overflow => write!(f, "duration is too large"),
+        }
+    }
+}
+
+impl Error for DurationError {}
+
+impl Duration {
+    pub fn from_nanos(nanos: u64) -> Self {
+        Self { months: 0, nanos }
+    }
+
+    pub fn from_months(months: u32) -> Self {
+        Self { months, nanos: 0 }
+    }
+
+    /// Parses a sequence of `<number><unit>` pairs, e.g. `1h30m` or `1mo2w`.
+    pub fn parse(spec: &str) -> Result<Self, DurationError> {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            return Err(DurationError::Empty);
+        }
+
+        let mut duration = Duration::default();
+        let mut rest = spec;
+
+        while !rest.is_empty() {
+            let number_len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let (number, tail) = rest.split_at(number_len);
+
+            let unit_len = tail
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(tail.len());
+            let (unit, tail) = tail.split_at(unit_len);
+
+            if number.is_empty() {
+                return Err(DurationError::MissingNumber(unit.to_string()));
+            }
+            if unit.is_empty() {
+                return Err(DurationError::MissingUnit(number.to_string()));
+            }
+
+            let value = number.parse::<u64>().map_err(|_| DurationError::Overflow)?;
+            duration = duration.checked_add(Duration::unit(value, unit)?)?;
+            rest = tail;
+        }
+
+        if duration.is_zero() {
+            return Err(DurationError::Zero);
+        }
+
+        Ok(duration)
+    }
+
+    fn unit(value: u64, unit: &str) -> Result<Duration, DurationError> {
+        let nanos_per_unit = match unit {
+            "ns" => 1,
+            "us" | "µs" => 1_000,
+            "ms" => 1_000_000,
+            "s" => NANOS_PER_SECOND,
+            "m" => 60 * NANOS_PER_SECOND,
+            "h" => 60 * 60 * NANOS_PER_SECOND,
+            "d" => SECONDS_PER_DAY * NANOS_PER_SECOND,
+            "w" => 7 * SECONDS_PER_DAY * NANOS_PER_SECOND,
+            "mo" | "y" => {
+                let months = if unit == "y" {
+                    value.checked_mul(12)
+                } else {
+                    Some(value)
+                };
+                return months
+                    .and_then(|months| u32::try_from(months).ok())
+                    .map(Duration::from_months)
+                    .ok_or(DurationError::Overflow);
+            }
+            _ => return Err(DurationError::UnknownUnit(unit.to_string())),
+        };
+
+        value
+            .checked_mul(nanos_per_unit)
+            .map(Duration::from_nanos)
+            .ok_or(DurationError::Overflow)
+    }
+
+    fn checked_add(self, other: Duration) -> Result<Duration, DurationError> {
+        Ok(Duration {
+            months: self
+                .months
+                .checked_add(other.months)
+                .ok_or(DurationError::Overflow)?,
+            nanos: self
+                .nanos
+                .checked_add(other.nanos)
+                .ok_or(DurationError::Overflow)?,
+        })
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.months == 0 && self.nanos == 0
+    }
+
+    /// Length in nanoseconds, `None` for durations with calendar months.
+    pub fn fixed_nanos(&self) -> Option<u64> {
+        match self.months {
+            0 => Some(self.nanos),
+            _ => None,
+        }
+    }
+
+    /// Adds the duration to a nanosecond timestamp. Months are added on the calendar,
+    /// clamping the day to the length of the target month (Jan 31 + 1mo = Feb 28/29).
+    /// Returns `None` if the result does not fit into a timestamp.
+    pub fn add_to(&self, timestamp: u64) -> Option<u64> {
+        let mut result = timestamp;
+
+        if self.months > 0 {
+            let seconds = result / NANOS_PER_SECOND;
+            let subsecond = result % NANOS_PER_SECOND;
+            let days = (seconds / SECONDS_PER_DAY) as i64;
+            let time_of_day = seconds % SECONDS_PER_DAY;
+
+            let (year, month, day) = civil_from_days(days);
+            let month_index = month as i64 - 1 + self.months as i64;
+            let year = year + month_index.div_euclid(12);
+            let month = (month_index.rem_euclid(12) + 1) as u32;
+            let day = day.min(days_in_month(year, month));
+
+            let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+            result = days
+                .checked_mul(SECONDS_PER_DAY)?
+                .checked_add(time_of_day)?
+                .checked_mul(NANOS_PER_SECOND)?
+                .checked_add(subsecond)?;
+        }
+
+        result.checked_add(self.nanos)
+    }
+}
+
+impl FromStr for Duration {
+    type Err = DurationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Duration::parse(s)
+    }
+}
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0s");
+        }
+
+        if self.months > 0 {
+            write!(f, "{}mo", self.months)?;
+        }
+
+        let mut nanos = self.nanos;
+        for (unit, size) in [
+            ("d", SECONDS_PER_DAY * NANOS_PER_SECOND),
+            ("h", 60 * 60 * NANOS_PER_SECOND),
+            ("m", 60 * NANOS_PER_SECOND),
+            ("s", NANOS_PER_SECOND),
+            ("ms", 1_000_000),
+            ("us", 1_000),
+            ("ns", 1),
+        ] {
+            if nanos >= size {
+                write!(f, "{}{}", nanos / size, unit)?;
+                nanos %= size;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Formats a timestamp in nanoseconds as RFC 3339 in UTC, with as many fractional digits as needed,
+/// e.g. `2021-07-02T12:46:40.5Z`.
+pub fn format_rfc3339(timestamp: u64) -> String {
+    let seconds = timestamp / NANOS_PER_SECOND;
+    let nanos = timestamp % NANOS_PER_SECOND;
+    let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+    let time = seconds % SECONDS_PER_DAY;
+
+    let mut formatted = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    );
+    if nanos > 0 {
+        formatted.push_str(format!(".{:09}", nanos).trim_end_matches('0'));
+    }
+    formatted.push('Z');
+
+    formatted
+}
+
+/// Parses an RFC 3339 timestamp, e.g. `2021-07-02T12:46:40.5Z` or `2021-07-02T14:46:40+02:00`,
+/// into nanoseconds. Digits beyond nanoseconds are ignored, times before 1970 are refused.
+pub fn parse_rfc3339(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid RFC 3339 timestamp '{}'", value);
+    let number = |start: usize, len: usize| -> Result<u32, String> {
+        value
+            .get(start..start + len)
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(invalid)
+    };
+
+    let bytes = value.as_bytes();
+    let separators = bytes.len() >= 20
+        && bytes[4] == b'-'
+        && bytes[7] == b'-'
+        && matches!(bytes[10], b'T' | b't' | b' ')
+        && bytes[13] == b':'
+        && bytes[16] == b':';
+    if !separators {
+        return Err(invalid());
+    }
+
+    let (year, month, day) = (number(0, 4)? as i64, number(5, 2)?, number(8, 2)?);
+    let (hour, minute, second) = (number(11, 2)?, number(14, 2)?, number(17, 2)?);
+    if !(1..=12).contains(&month)
+        || day == 0
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(invalid());
+    }
+
+    let mut rest = &value[19..];
+    let mut nanos = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 {
+            return Err(invalid());
+        }
+        nanos = format!("{:0<9}", &fraction[..digits.min(9)])
+            .parse::<u64>()
+            .map_err(|_| invalid())?;
+        rest = &fraction[digits..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+            let minutes = i64::from(number(value.len() - 5, 2)? * 60 + number(value.len() - 2, 2)?);
+            match rest.as_bytes()[0] {
+                b'+' => minutes * 60,
+                b'-' => -minutes * 60,
+                _ => return Err(invalid()),
+            }
+        }
+        _ => return Err(invalid()),
+    };
+
+    let seconds = days_from_civil(year, month, day) * SECONDS_PER_DAY as i64
+        + i64::from(hour * 3600 + minute * 60 + second)
+        - offset;
+    u64::try_from(seconds)
+        .ok()
+        .and_then(|seconds| seconds.checked_mul(NANOS_PER_SECOND))
+        .and_then(|seconds| seconds.checked_add(nanos))
+        .ok_or_else(|| format!("timestamp '{}' is before 1970", value))
+}
+
+// Days since 1970-01-01 to (year, month, day), see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            format_rfc3339(1_625_230_000 * NANOS_PER_SECOND + 500_000_000),
+            "2021-07-02T12:46:40.5Z"
+        );
+        assert_eq!(format_rfc3339(951_782_400 * NANOS_PER_SECOND + 7), "2000-02-29T00:00:00.000000007Z");
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Ok(0));
+        assert_eq!(
+            parse_rfc3339("2021-07-02T14:46:40.5+02:00"),
+            Ok(1_625_230_000 * NANOS_PER_SECOND + 500_000_000)
+        );
+        assert_eq!(
+            parse_rfc3339("2000-02-29t00:00:00.0000000071z"),
+            Ok(951_782_400 * NANOS_PER_SECOND + 7)
+        );
+        for invalid in ["2021-02-29T00:00:00Z", "2021-07-02T12:46:40", "2021-07-02T12:46:40.Z", "1969-12-31T23:59:59Z"] {
+            assert!(parse_rfc3339(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_compound_duration() {
+        let duration = Duration::parse("1h30m").unwrap();
+        assert_eq!(duration.add_to(0), Some(90 * 60 * NANOS_PER_SECOND));
+
+        assert_eq!(
+            Duration::parse("250ms").unwrap().add_to(0),
+            Some(250_000_000)
+        );
+        assert_eq!(Duration::parse("10us").unwrap().add_to(0), Some(10_000));
+        assert_eq!(Duration::parse("5ns").unwrap().add_to(0), Some(5));
+        assert_eq!(
+            Duration::parse("2w").unwrap().add_to(0),
+            Some(14 * SECONDS_PER_DAY * NANOS_PER_SECOND)
+        );
+        assert_eq!(Duration::parse("1y").unwrap(), Duration::from_months(12));
+        assert_eq!(Duration::parse("1h30m").unwrap().to_string(), "1h30m");
+    }
+
+    #[test]
+    fn test_parse_invalid_duration() {
+        assert_eq!(Duration::parse(""), Err(DurationError::Empty));
+        assert_eq!(
+            Duration::parse("10"),
+            Err(DurationError::MissingUnit("10".to_string()))
+        );
+        assert_eq!(
+            Duration::parse("h"),
+            Err(DurationError::MissingNumber("h".to_string()))
+        );
+        assert_eq!(
+            Duration::parse("5x"),
+            Err(DurationError::UnknownUnit("x".to_string()))
+        );
+        assert_eq!(Duration::parse("0s"), Err(DurationError::Zero));
+        assert_eq!(
+            Duration::parse("99999999999999999999s"),
+            Err(DurationError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_add_calendar_months() {
+        // 2024-01-31T12:00:00Z
+        let timestamp = 1_706_702_400 * NANOS_PER_SECOND;
+        // 2024-02-29T12:00:00Z, the day is clamped to the end of February in a leap year
+        let expected = 1_709_208_000 * NANOS_PER_SECOND;
+
+        assert_eq!(Duration::from_months(1).add_to(timestamp), Some(expected));
+        // 2025-01-31T12:00:00Z
+        assert_eq!(
+            Duration::parse("1y").unwrap().add_to(timestamp),
+            Some(1_738_324_800 * NANOS_PER_SECOND)
+        );
+    }
+}
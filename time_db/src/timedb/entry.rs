@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, mem::size_of};
 
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
@@ -19,19 +19,87 @@ pub struct Entry {
     pub fields: HashMap<String, Value>,
     pub tags: HashMap<String, Value>,
 }
+impl Value {
+    /// Value of a number read as a 64 bit float. Whole numbers are kept as integers since
+    /// `Float` only holds 24 bits of precision, others are narrowed to a float.
+    pub fn from_f64(value: f64) -> Value {
+        if value.fract() == 0.0 && value.abs() < i128::MAX as f64 {
+            Value::Int(value as i128)
+        } else {
+            Value::Float(value as f32)
+        }
+    }
+
+    /// Approximate number of bytes the value takes in a reply.
+    pub fn size_estimate(&self) -> usize {
+        match self {
+            Value::String(value) => value.len() + 4,
+            Value::Int(_) | Value::UInt(_) => 16,
+            Value::Float(_) => 4,
+            Value::Bool(_) => 1,
+            Value::None => 1,
+        }
+    }
+}
+
 impl Entry {
+    /// Approximate number of bytes the entry takes in a reply.
+    pub fn size_estimate(&self) -> usize {
+        let values = |values: &HashMap<String, Value>| -> usize {
+            values
+                .iter()
+                .map(|(name, value)| name.len() + 4 + value.size_estimate())
+                .sum()
+        };
+
+        8 + values(&self.fields) + values(&self.tags)
+    }
+
+    /// Approximate number of bytes the entry takes in memory when stored as is.
+    pub fn memory_size(&self) -> usize {
+        Entry::memory_size_of(&self.fields, &self.tags)
+    }
+
+    pub fn memory_size_of(fields: &HashMap<String, Value>, tags: &HashMap<String, Value>) -> usize {
+        size_of::<Entry>() + Entry::map_memory_size(fields) + Entry::map_memory_size(tags)
+    }
+
+    /// Approximate number of bytes used by the contents of a field or tag map.
+    pub fn map_memory_size(values: &HashMap<String, Value>) -> usize {
+        values
+            .iter()
+            .map(|(name, value)| {
+                let heap = match value {
+                    Value::String(value) => value.len(),
+                    _ => 0,
+                };
+                size_of::<(String, Value)>() + 1 + name.len() + heap // one control byte per bucket
+            })
+            .sum()
+    }
+
     pub fn get_value(&self, field: &str) -> Option<&Value> {
-        let mut res = None;
+        Entry::lookup(&self.fields, &self.tags, field)
+    }
 
-        if self.fields.contains_key(field) {
-            res = self.fields.get(field)
-        }
+    /// Value of a field or tag, a tag takes precedence over a field with the same name.
+    pub fn lookup<'a>(
+        fields: &'a HashMap<String, Value>,
+        tags: &'a HashMap<String, Value>,
+        field: &str,
+    ) -> Option<&'a Value> {
+        tags.get(field).or_else(|| fields.get(field))
+    }
+}
 
-        if self.tags.contains_key(field) {
-            res = self.tags.get(field)
-        }
+/// Point whose fields and tags can be looked up by name, e.g. by an expression.
+pub trait Record {
+    fn get_value(&self, field: &str) -> Option<&Value>;
+}
 
-        res
+impl Record for Entry {
+    fn get_value(&self, field: &str) -> Option<&Value> {
+        Entry::get_value(self, field)
     }
 }
 
@@ -65,5 +133,14 @@ mod tests {
         assert_eq!(entry.get_value("temperature"), Some(&Value::Int(25)));
     }
 
+    #[test]
+    fn test_whole_floats_keep_their_precision() {
+        assert_eq!(Value::from_f64(16777217.0), Value::Int(16777217));
+        assert_eq!(Value::from_f64(-3.0), Value::Int(-3));
+        assert_eq!(Value::from_f64(1.5), Value::Float(1.5));
+        assert!(matches!(Value::from_f64(f64::NAN), Value::Float(value) if value.is_nan()));
+        assert_eq!(Value::from_f64(f64::INFINITY), Value::Float(f32::INFINITY));
+    }
+
     // Additional test cases here...
 }
\ No newline at end of file
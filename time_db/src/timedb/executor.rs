@@ -0,0 +1,269 @@
+use std::{borrow::Cow, collections::HashMap, error::Error, rc::Rc};
+
+use super::{
+    aggregate::AggregateFunction,
+    duration::Duration,
+    entry::{Entry, Value},
+    expression::Expression,
+    limits::Budget,
+    planner::QueryPlan,
+    registry::DEVICES,
+    series::Point,
+    Action,
+};
+
+type PointResult<'a> = Result<Point<'a>, Box<dyn Error>>;
+type PointIter<'a> = Box<dyn Iterator<Item = PointResult<'a>> + 'a>;
+
+/// Executes a plan as a chain of iterators pulling from the points of the index scan.
+///
+/// The scan yields points that borrow the fields of the head of a series, or own the fields
+/// decoded from a chunk, and share the tags of their series. They are moved through `Range`
+/// and `Filter` steps without being copied, only the points that make it to the output are
+/// turned into entries, with the projected fields and tags.
+///
+/// The scan and the output stop as soon as the budget runs out, what was produced up to
+/// that point is returned and the budget holds the cursor to resume from.
+pub fn execute<'a>(
+    plan: &'a QueryPlan,
+    scan: impl Iterator<Item = Point<'a>> + 'a,
+    budget: &'a Budget,
+) -> Result<Vec<Entry>, Box<dyn Error>> {
+    let projection = Projection::new(&plan.steps);
+
+    let mut points: PointIter = Box::new(
+        scan.take_while(|point| budget.scan(point.timestamp))
+            .map(Ok),
+    );
+
+    for step in plan.steps.iter() {
+        points = match step {
+            Action::Range(start, end) => {
+                let (start, end) = (*start, end.unwrap_or(u64::MAX));
+                Box::new(points.filter(move |point| match point {
+                    Ok(point) => point.timestamp >= start && point.timestamp <= end,
+                    Err(_) => true,
+                }))
+            }
+            Action::Filter(expression) => Box::new(points.filter(move |point| match point {
+                Ok(point) => expression.matches(point),
+                Err(_) => true,
+            })),
+            Action::AggregateWindow(window, function) => {
+                let window = Duration::parse(window)
+                    .map_err(|err| format!("Invalid aggregate window '{}': {}", window, err))?;
+                Box::new(AggregateWindow::new(points, window, function, budget))
+            }
+            Action::Enrich(tag) => Box::new(points.map(move |point| {
+                let mut point = point?;
+                let tags = DEVICES.with(|d| d.borrow().enrichment(tag, &point.tags));
+                if !tags.is_empty() {
+                    Rc::make_mut(&mut point.tags).extend(tags);
+                }
+                Ok(point)
+            })),
+        };
+    }
+
+    let mut output = vec![];
+    for point in points {
+        let entry = projection.apply(point?);
+        if !budget.output(&entry) {
+            break;
+        }
+        output.push(entry);
+    }
+
+    Ok(output)
+}
+
+// Fields and tags kept in the output, empty means keep all
+struct Projection<'a> {
+    fields: Vec<&'a String>,
+    tags: Vec<&'a String>,
+}
+
+impl<'a> Projection<'a> {
+    fn new(steps: &'a [Action]) -> Self {
+        let mut projection = Projection {
+            fields: vec![],
+            tags: vec![],
+        };
+
+        for step in steps {
+            match step {
+                Action::Filter(Expression::FieldFilter(fields)) => {
+                    projection.fields.extend(fields.iter())
+                }
+                Action::Filter(Expression::TagFilter(tags)) => projection.tags.extend(tags.iter()),
+                _ => {}
+            }
+        }
+
+        projection
+    }
+
+    fn apply(&self, point: Point) -> Entry {
+        let fields = match point.fields {
+            Cow::Borrowed(fields) => Projection::select(fields, &self.fields),
+            Cow::Owned(mut fields) => {
+                if !self.fields.is_empty() {
+                    fields.retain(|k, _| self.fields.contains(&k));
+                }
+                fields
+            }
+        };
+        let tags = match self.tags.is_empty() {
+            true => Rc::unwrap_or_clone(point.tags),
+            false => Projection::select(&point.tags, &self.tags),
+        };
+
+        Entry {
+            timestamp: point.timestamp,
+            fields,
+            tags,
+        }
+    }
+
+    fn select(values: &HashMap<String, Value>, keep: &[&String]) -> HashMap<String, Value> {
+        if keep.is_empty() {
+            return values.clone();
+        }
+
+        values
+            .iter()
+            .filter(|(k, _)| keep.contains(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+}
+
+// Streaming aggregation, relies on the input being ordered by timestamp as produced by the scan
+struct AggregateWindow<'a> {
+    input: PointIter<'a>,
+    window: Duration,
+    function: &'a AggregateFunction,
+    budget: &'a Budget,
+    current: Option<(u64, u64)>, //Start and end of the current window
+    fields: HashMap<String, Vec<Value>>,
+}
+
+impl<'a> AggregateWindow<'a> {
+    fn new(
+        input: PointIter<'a>,
+        window: Duration,
+        function: &'a AggregateFunction,
+        budget: &'a Budget,
+    ) -> Self {
+        Self {
+            input,
+            window,
+            function,
+            budget,
+            current: None,
+            fields: HashMap::new(),
+        }
+    }
+
+    fn flush(&mut self, start: u64) -> Option<PointResult<'a>> {
+        if self.fields.is_empty() {
+            return None;
+        }
+
+        let fields = std::mem::take(&mut self.fields);
+        Some(Action::aggregate_window(start, &fields, self.function).map(Point::from))
+    }
+}
+
+impl<'a> Iterator for AggregateWindow<'a> {
+    type Item = PointResult<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let point = match self.input.next() {
+                Some(Ok(point)) => point,
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    let (start, end) = self.current.take()?;
+
+                    // The scan was cut short within the window, it has to be recomputed
+                    if self.budget.cursor().is_some_and(|cursor| cursor < end) {
+                        self.budget.rewind(start);
+                        return None;
+                    }
+
+                    return self.flush(start);
+                }
+            };
+
+            let mut finished = None;
+            if let Some((start, end)) = self.current {
+                if point.timestamp >= end {
+                    finished = self.flush(start);
+                    self.current = None;
+                }
+            }
+
+            if self.current.is_none() {
+                match self.window.add_to(point.timestamp) {
+                    Some(end) => {
+                        self.budget.window(point.timestamp, end);
+                        self.current = Some((point.timestamp, end));
+                    }
+                    None => {
+                        return Some(Err(format!(
+                            "Aggregate window '{}' overflows timestamp",
+                            self.window
+                        )
+                        .into()))
+                    }
+                }
+            }
+
+            for (field, value) in point.fields.iter() {
+                self.fields
+                    .entry(field.clone())
+                    .or_default()
+                    .push(value.clone());
+            }
+
+            if finished.is_some() {
+                return finished;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timedb::{
+        index::Indexes, limits::QueryLimits, planner::Scan, test_helper::create_test_entries,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_execute_projects_output() {
+        let mut indexes = Indexes::new();
+        for entry in create_test_entries() {
+            indexes.insert(entry.timestamp, entry);
+        }
+
+        let plan = QueryPlan {
+            scan: Scan::new(),
+            steps: vec![
+                Action::Filter(Expression::FieldFilter(vec!["humidity".to_string()])),
+                Action::Filter(Expression::TagFilter(vec!["sensor_id".to_string()])),
+            ],
+        };
+
+        let budget = Budget::new(&QueryLimits::default());
+        let entries = execute(&plan, indexes.scan_iter(&plan.scan), &budget).unwrap();
+
+        assert_eq!(entries.len(), 1000);
+        for entry in entries {
+            assert!(entry.fields.contains_key("humidity") && entry.fields.len() == 1);
+            assert!(entry.tags.contains_key("sensor_id") && entry.tags.len() == 1);
+        }
+    }
+}
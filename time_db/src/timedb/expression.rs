@@ -1,14 +1,14 @@
-use std::{collections::HashSet, rc::Rc};
+use std::collections::HashSet;
 
 use candid::CandidType;
 use serde::Deserialize;
 
 use super::{
-    entry::{Entry, Value},
+    entry::{Entry, Record, Value},
     query::QueryResponse,
 };
 
-#[derive(Clone, CandidType, Deserialize)]
+#[derive(Clone, CandidType, Deserialize, Debug)]
 pub enum Expression {
     Eq(String, Value), // Field or tag name, Equal
     Gt(String, Value), // Field or tag name, Greater than
@@ -25,7 +25,12 @@ pub enum Expression {
 }
 
 impl Expression {
-    pub fn evaluate(&self, entry: &Rc<Entry>) -> bool {
+    pub fn evaluate(&self, entry: &Entry) -> bool {
+        self.matches(entry)
+    }
+
+    /// Same as `evaluate`, for points read from the indexes as well as entries.
+    pub fn matches(&self, entry: &impl Record) -> bool {
         match self {
         Expression::Eq(field, expected_value) => entry.get_value(field).map_or(false, |v| v == expected_value),
             Expression::Gt(field, expected_value) => {
@@ -59,9 +64,20 @@ impl Expression {
 
             Expression::TagFilter(_) => true,
             Expression::FieldFilter(_) => true,
-            Expression::And(left, right) => left.evaluate(entry) && right.evaluate(entry),
-            Expression::Or(left, right) => left.evaluate(entry) || right.evaluate(entry),
-            Expression::Not(val) => !val.evaluate(entry),
+            Expression::And(left, right) => left.matches(entry) && right.matches(entry),
+            Expression::Or(left, right) => left.matches(entry) || right.matches(entry),
+            Expression::Not(val) => !val.matches(entry),
+        }
+    }
+
+    /// Collects the operands of nested `And` expressions, e.g. `a && (b && c)` gives `[a, b, c]`.
+    pub fn conjuncts<'a>(&'a self, conjuncts: &mut Vec<&'a Expression>) {
+        match self {
+            Expression::And(left, right) => {
+                left.conjuncts(conjuncts);
+                right.conjuncts(conjuncts);
+            }
+            _ => conjuncts.push(self),
         }
     }
 
@@ -71,7 +87,7 @@ impl Expression {
                 query.tags = Expression::merge_unique(&query.tags, keep_tags)
             }
             Expression::FieldFilter(keep_fields) => {
-                query.fields = Expression::merge_unique(&query.tags, keep_fields)
+                query.fields = Expression::merge_unique(&query.fields, keep_fields)
             }
             _ => {}
         }
@@ -93,6 +109,7 @@ impl Expression {
             (Value::Float(v1), Value::Int(v2)) => *v1 > (*v2 as f32),
             (Value::Float(v1), Value::Float(v2)) => *v1 > *v2,
             (Value::UInt(v1), Value::UInt(v2)) => v1 > v2,
+            (Value::String(v1), Value::String(v2)) => v1 > v2,
             // Other type comparisons as needed
             _ => false, // Return false for non-comparable types or unhandled combinations
         }
@@ -105,6 +122,7 @@ impl Expression {
             (Value::Float(v1), Value::Int(v2)) => *v1 >= (*v2 as f32),
             (Value::Float(v1), Value::Float(v2)) => *v1 >= *v2,
             (Value::UInt(v1), Value::UInt(v2)) => v1 >= v2,
+            (Value::String(v1), Value::String(v2)) => v1 >= v2,
             _ => false,
         }
     }
@@ -116,6 +134,7 @@ impl Expression {
             (Value::Float(v1), Value::Int(v2)) => *v1 < (*v2 as f32),
             (Value::Float(v1), Value::Float(v2)) => *v1 < *v2,
             (Value::UInt(v1), Value::UInt(v2)) => v1 < v2,
+            (Value::String(v1), Value::String(v2)) => v1 < v2,
             _ => false,
         }
     }
@@ -127,6 +146,7 @@ impl Expression {
             (Value::Float(v1), Value::Int(v2)) => *v1 <= (*v2 as f32),
             (Value::Float(v1), Value::Float(v2)) => *v1 <= *v2,
             (Value::UInt(v1), Value::UInt(v2)) => v1 <= v2,
+            (Value::String(v1), Value::String(v2)) => v1 <= v2,
             _ => false,
         }
     }
@@ -0,0 +1,307 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use super::{entry::Value, expression::Expression};
+
+/// Error in a filter expression, `position` is the byte offset where parsing stopped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl Error for FilterError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Literal(Value),
+    Op(&'static str),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+/// Parses filters written like `sensor_id == "sensor_1" and (temperature > 20.5 or not humidity <= 40)`.
+///
+/// Comparisons are `==` (or `=`), `!=`, `>`, `>=`, `<` and `<=` between a field or tag name
+/// and a literal: a quoted string, an integer, a float, `true`, `false` or `null`. They are
+/// combined with `and`/`&&`, `or`/`||` and `not`/`!`, `and` binding tighter than `or`.
+impl FromStr for Expression {
+    type Err = FilterError;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(filter)?;
+        let mut parser = Parser {
+            tokens,
+            next: 0,
+            end: filter.len(),
+        };
+
+        let expression = parser.or()?;
+        match parser.tokens.get(parser.next) {
+            None => Ok(expression),
+            Some((position, token)) => Err(FilterError {
+                position: *position,
+                message: format!("unexpected {:?}", token),
+            }),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    end: usize, // length of the input, reported when it ends too early
+}
+
+impl Parser {
+    fn or(&mut self) -> Result<Expression, FilterError> {
+        let mut left = self.and()?;
+        while self.accept(&Token::Or) {
+            left = Expression::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expression, FilterError> {
+        let mut left = self.unary()?;
+        while self.accept(&Token::And) {
+            left = Expression::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, FilterError> {
+        if self.accept(&Token::Not) {
+            return Ok(Expression::Not(Box::new(self.unary()?)));
+        }
+
+        if self.accept(&Token::Open) {
+            let expression = self.or()?;
+            return match self.take() {
+                Some((_, Token::Close)) => Ok(expression),
+                token => Err(self.error(token, "expected ')'")),
+            };
+        }
+
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expression, FilterError> {
+        let name = match self.take() {
+            Some((_, Token::Name(name))) => name,
+            token => return Err(self.error(token, "expected a field or tag name")),
+        };
+
+        let op = match self.take() {
+            Some((_, Token::Op(op))) => op,
+            token => return Err(self.error(token, "expected a comparison operator")),
+        };
+
+        let value = match self.take() {
+            Some((_, Token::Literal(value))) => value,
+            token => return Err(self.error(token, "expected a value")),
+        };
+
+        Ok(match op {
+            "==" => Expression::Eq(name, value),
+            "!=" => Expression::Not(Box::new(Expression::Eq(name, value))),
+            ">" => Expression::Gt(name, value),
+            ">=" => Expression::Ge(name, value),
+            "<" => Expression::Lt(name, value),
+            _ => Expression::Le(name, value),
+        })
+    }
+
+    fn accept(&mut self, token: &Token) -> bool {
+        match self.tokens.get(self.next) {
+            Some((_, next)) if next == token => {
+                self.next += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn take(&mut self) -> Option<(usize, Token)> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token
+    }
+
+    fn error(&self, token: Option<(usize, Token)>, message: &str) -> FilterError {
+        match token {
+            Some((position, token)) => FilterError {
+                position,
+                message: format!("{}, found {:?}", message, token),
+            },
+            None => FilterError {
+                position: self.end,
+                message: format!("{}, found end of filter", message),
+            },
+        }
+    }
+}
+
+fn tokenize(filter: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = vec![];
+    let mut chars = filter.char_indices().peekable();
+
+    while let Some((position, c)) = chars.next() {
+        let error = |message: String| FilterError { position, message };
+
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '"' | '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => value.push(escaped),
+                            None => return Err(error("unterminated string".to_string())),
+                        },
+                        Some((_, quote)) if quote == c => break,
+                        Some((_, other)) => value.push(other),
+                        None => return Err(error("unterminated string".to_string())),
+                    }
+                }
+                Token::Literal(Value::String(value))
+            }
+            '=' | '!' | '<' | '>' | '&' | '|' => {
+                let next = chars.peek().map(|(_, next)| *next);
+                let (token, double) = match (c, next) {
+                    ('=', Some('=')) => (Token::Op("=="), true),
+                    ('=', _) => (Token::Op("=="), false),
+                    ('!', Some('=')) => (Token::Op("!="), true),
+                    ('!', _) => (Token::Not, false),
+                    ('<', Some('=')) => (Token::Op("<="), true),
+                    ('<', _) => (Token::Op("<"), false),
+                    ('>', Some('=')) => (Token::Op(">="), true),
+                    ('>', _) => (Token::Op(">"), false),
+                    ('&', Some('&')) => (Token::And, true),
+                    ('|', Some('|')) => (Token::Or, true),
+                    _ => return Err(error(format!("unexpected '{}'", c))),
+                };
+                if double {
+                    chars.next();
+                }
+                token
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let mut number = c.to_string();
+                while let Some((_, next)) = chars.peek() {
+                    if next.is_ascii_alphanumeric() || *next == '.' || *next == '_' {
+                        number.push(*next);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Literal(
+                    number_value(&number)
+                        .ok_or_else(|| error(format!("invalid number '{}'", number)))?,
+                )
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some((_, next)) = chars.peek() {
+                    if next.is_alphanumeric() || *next == '_' || *next == '.' || *next == '-' {
+                        word.push(*next);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                match word.to_lowercase().as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" | "none" => Token::Literal(Value::None),
+                    _ => Token::Name(word),
+                }
+            }
+            c => return Err(error(format!("unexpected '{}'", c))),
+        };
+
+        tokens.push((position, token));
+    }
+
+    Ok(tokens)
+}
+
+// Integers become `Int` unless they only fit into `UInt`, anything else with a dot or exponent is a `Float`
+fn number_value(number: &str) -> Option<Value> {
+    if let Ok(value) = number.parse::<i128>() {
+        return Some(Value::Int(value));
+    }
+    if let Ok(value) = number.parse::<u128>() {
+        return Some(Value::UInt(value));
+    }
+    number.parse::<f32>().ok().map(Value::Float)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        let expression: Expression =
+            "sensor_id == \"sensor_1\" and (temperature > 20.5 || not humidity <= -3) and on != true"
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            format!("{:?}", expression),
+            format!(
+                "{:?}",
+                Expression::And(
+                    Box::new(Expression::And(
+                        Box::new(Expression::Eq(
+                            "sensor_id".to_string(),
+                            Value::String("sensor_1".to_string())
+                        )),
+                        Box::new(Expression::Or(
+                            Box::new(Expression::Gt(
+                                "temperature".to_string(),
+                                Value::Float(20.5)
+                            )),
+                            Box::new(Expression::Not(Box::new(Expression::Le(
+                                "humidity".to_string(),
+                                Value::Int(-3)
+                            )))),
+                        )),
+                    )),
+                    Box::new(Expression::Not(Box::new(Expression::Eq(
+                        "on".to_string(),
+                        Value::Bool(true)
+                    )))),
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_filter_errors() {
+        let error = "temperature >".parse::<Expression>().unwrap_err();
+        assert_eq!(error.position, 13);
+
+        let error = "(temperature > 1".parse::<Expression>().unwrap_err();
+        assert!(error.message.starts_with("expected ')'"));
+
+        assert!("name == 'unterminated".parse::<Expression>().is_err());
+        assert!("a == 1 b == 2".parse::<Expression>().is_err());
+    }
+}
@@ -1,46 +1,205 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{HashMap, HashSet},
     rc::Rc,
 };
 
-use super::Entry;
+use super::{
+    planner::Scan,
+    series::{MergeIter, Point, Series, SeriesIter, SeriesStats},
+    tags::{Interner, TagSet, TagValue},
+    Entry,
+};
 
 pub struct Indexes {
-    pub main_index: BTreeMap<u64, Rc<Entry>>,
-    pub subindexes: HashMap<String, BTreeMap<String, Vec<Rc<Entry>>>>,
+    series: Vec<Series>,
+    series_keys: HashMap<Rc<TagSet>, usize>, //tag set -> series id
+    pub subindexes: HashMap<Rc<str>, HashMap<Rc<str>, Vec<usize>>>, //tag name -> tag value -> series ids
+    interner: Interner, //tag names and values shared by the tag sets and the tag index
+    field_keys: HashSet<String>, //names of all fields stored, a tag sharing a name with a field can not be answered from the index
 }
 
 impl Indexes {
     // Function to create a new instance
     pub fn new() -> Self {
         Self {
-            main_index: BTreeMap::new(),
+            series: vec![],
+            series_keys: HashMap::new(),
             subindexes: HashMap::new(),
+            interner: Interner::default(),
+            field_keys: HashSet::new(),
+        }
+    }
+
+    /// Adds the entry to the series of its tag set, replacing a point of that series with
+    /// the same timestamp.
+    pub(crate) fn insert(&mut self, timestamp: u64, entry: Entry) {
+        for field in entry.fields.keys() {
+            if !self.field_keys.contains(field) {
+                self.field_keys.insert(field.clone());
+            }
+        }
+
+        let tags = TagSet::new(&entry.tags, &mut self.interner);
+        let id = match self.series_keys.get(&tags) {
+            Some(id) => *id,
+            None => {
+                let id = self.series.len();
+                for (tag, value) in tags.string_tags() {
+                    self.subindexes
+                        .entry(tag.clone())
+                        .or_default()
+                        .entry(value.clone())
+                        .or_default()
+                        .push(id);
+                }
+
+                let tags = Rc::new(tags);
+                self.series.push(Series::new(tags.clone()));
+                self.series_keys.insert(tags, id);
+                id
+            }
+        };
+
+        self.series[id].insert(timestamp, entry.fields);
+    }
+
+    /// All entries ordered by timestamp.
+    pub(crate) fn values(&self) -> Vec<Entry> {
+        self.scan_iter(&Scan::new())
+            .map(|point| point.into_entry())
+            .collect()
+    }
+
+    /// Whether an equality predicate on `name` can be answered from the tag index.
+    pub(crate) fn is_tag_indexed(&self, name: &str) -> bool {
+        self.subindexes.contains_key(name) && !self.field_keys.contains(name)
+    }
+
+    /// Returns entries within the time bounds of the scan that match all of its tag predicates.
+    #[cfg(any(test, feature = "benchmark"))]
+    pub(crate) fn scan(&self, scan: &Scan) -> Vec<Rc<Entry>> {
+        self.scan_iter(scan)
+            .map(|point| Rc::new(point.into_entry()))
+            .collect()
+    }
+
+    /// Lazy version of `scan`, points are yielded in timestamp order.
+    /// The points of all series selected by the scan are merged by timestamp.
+    pub(crate) fn scan_iter<'a>(&'a self, scan: &Scan) -> Box<dyn Iterator<Item = Point<'a>> + 'a> {
+        Box::new(MergeIter::new(self.scan_series(scan)))
+    }
+
+    /// Points of every series selected by the scan, one iterator per series.
+    /// Series are selected through the smallest matching tag index.
+    pub(crate) fn scan_series<'a>(&'a self, scan: &Scan) -> Vec<SeriesIter<'a>> {
+        if scan.start > scan.end {
+            return vec![];
+        }
+
+        let mut smallest: Option<&Vec<usize>> = None;
+        for (tag, value) in &scan.tags {
+            match self
+                .subindexes
+                .get(tag.as_str())
+                .and_then(|values| values.get(value.as_str()))
+            {
+                Some(ids) => match smallest {
+                    Some(current) if current.len() <= ids.len() => {}
+                    _ => smallest = Some(ids),
+                },
+                None => return vec![],
+            }
         }
+
+        let candidates: Vec<&Series> = match smallest {
+            Some(ids) => ids.iter().map(|id| &self.series[*id]).collect(),
+            None => self.series.iter().collect(),
+        };
+
+        candidates
+            .into_iter()
+            .filter(|series| {
+                scan.tags
+                    .iter()
+                    .all(|(tag, value)| match series.tags.get(tag) {
+                        Some(TagValue::String(tag_value)) => **tag_value == **value,
+                        _ => false,
+                    })
+            })
+            .map(|series| series.scan(scan.start, scan.end))
+            .collect()
     }
 
-    // Function to add a new subindex
-    #[allow(dead_code)]
-    fn add_subindex(&mut self, name: &str) {
-        self.subindexes.insert(name.to_string(), BTreeMap::new());
+    pub(crate) fn stats(&self) -> SeriesStats {
+        let mut total = SeriesStats {
+            stored_bytes: self.interner.memory_size(),
+            ..SeriesStats::default()
+        };
+
+        for stats in self.series.iter().map(|series| series.stats()) {
+            total.points += stats.points;
+            total.sealed_chunks += stats.sealed_chunks;
+            total.head_points += stats.head_points;
+            total.raw_bytes += stats.raw_bytes;
+            total.stored_bytes += stats.stored_bytes;
+        }
+
+        total
     }
 
-    // Function to add an object to a subindex
-    #[allow(dead_code)]
-    fn add_to_subindex(&mut self, subindex_name: &str, key: String, object: Rc<Entry>) {
-        let subindex = self
-            .subindexes
-            .entry(subindex_name.to_string())
-            .or_default();
-        subindex.entry(key).or_default().push(object);
+    pub(crate) fn series_count(&self) -> usize {
+        self.series.len()
     }
 
-    pub(crate) fn insert(&mut self, timestamp: u64, entry: Entry) -> Option<Rc<Entry>> {
-        self.main_index.insert(timestamp, Rc::new(entry))
-        //todo: population of subindexes based on tags from Entry
+    pub(crate) fn interned_strings(&self) -> usize {
+        self.interner.len()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timedb::entry::Value;
+
+    use super::*;
+
+    fn entry(timestamp: u64, sensor: &str, value: i128) -> Entry {
+        Entry {
+            timestamp,
+            fields: HashMap::from([("value".to_string(), Value::Int(value))]),
+            tags: HashMap::from([
+                ("sensor_id".to_string(), Value::String(sensor.to_string())),
+                ("location".to_string(), Value::String("hall".to_string())),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_scan_merges_series() {
+        let mut indexes = Indexes::new();
+        indexes.insert(3, entry(3, "s1", 1));
+        indexes.insert(1, entry(1, "s2", 2));
+        indexes.insert(2, entry(2, "s1", 3));
+        indexes.insert(3, entry(3, "s1", 4)); // overwrites the point of s1 only
+        indexes.insert(3, entry(3, "s2", 5));
+
+        assert_eq!(indexes.series_count(), 2);
+
+        let values: Vec<(u64, Value)> = indexes
+            .values()
+            .into_iter()
+            .map(|entry| (entry.timestamp, entry.fields["value"].clone()))
+            .collect();
+        assert_eq!(values.len(), 4);
+        assert_eq!(values[0], (1, Value::Int(2)));
+        assert_eq!(values[1], (2, Value::Int(3)));
+        assert!(values[2..].contains(&(3, Value::Int(4))));
+        assert!(values[2..].contains(&(3, Value::Int(5))));
 
-    pub(crate) fn values(&self) -> Vec<Rc<Entry>> {
-        self.main_index.values().cloned().collect()
+        let mut scan = Scan::new();
+        scan.tags.push(("sensor_id".to_string(), "s1".to_string()));
+        scan.start = 3;
+        let entries = indexes.scan(&scan);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].fields["value"], Value::Int(4));
     }
 }
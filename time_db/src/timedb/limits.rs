@@ -0,0 +1,270 @@
+use std::{cell::Cell, error::Error, fmt};
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use super::Entry;
+
+// Reading the instruction counter is a system call, it is only done every this many points (a power of two)
+const INSTRUCTION_CHECK_INTERVAL: u64 = 64;
+
+/// What a query does when it runs into one of its limits.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub enum LimitBehaviour {
+    Abort,    // fail with `QueryError::LimitExceeded`
+    Truncate, // return what was computed so far, flagged as truncated
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub struct QueryLimits {
+    pub max_instructions: u64,
+    pub max_scanned_points: u64,
+    pub max_returned_bytes: u64,
+    pub on_limit: LimitBehaviour,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            max_instructions: 4_000_000_000, // queries trap at 5B instructions
+            max_scanned_points: 1_000_000,
+            max_returned_bytes: 2_000_000, // replies are limited to 2MiB
+            on_limit: LimitBehaviour::Abort,
+        }
+    }
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Debug, PartialEq)]
+pub enum Limit {
+    Instructions,
+    ScannedPoints,
+    ReturnedBytes,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub enum QueryError {
+    LimitExceeded { limit: Limit, cursor: u64 }, // run the query again from `cursor` to continue
+    Invalid(String),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::LimitExceeded { limit, cursor } => write!(
+                f,
+                "Query exceeded its {:?} limit, resume from cursor {}",
+                limit, cursor
+            ),
+            QueryError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for QueryError {}
+
+impl From<Box<dyn Error>> for QueryError {
+    fn from(err: Box<dyn Error>) -> Self {
+        QueryError::Invalid(err.to_string())
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct QueryResult {
+    pub entries: Vec<Entry>,
+    pub truncated: bool,
+    pub cursor: Option<u64>, // where to resume a truncated query
+}
+
+impl QueryResult {
+    /// Keeps at most `max_entries` entries, the cursor then points at the first one dropped.
+    /// Like for limits, entries at the cursor are all returned when the query is resumed. When
+    /// all entries kept would be at the cursor they are all kept instead, over `max_entries`,
+    /// so that every page makes progress.
+    pub fn truncate(&mut self, max_entries: usize) {
+        if self.entries.len() <= max_entries {
+            return;
+        }
+
+        let mut cursor = self.entries[max_entries].timestamp;
+        let mut keep = self
+            .entries
+            .partition_point(|entry| entry.timestamp < cursor);
+        if keep == 0 {
+            keep = self
+                .entries
+                .partition_point(|entry| entry.timestamp <= cursor);
+            match self.entries.get(keep) {
+                Some(entry) => cursor = entry.timestamp,
+                None => return,
+            }
+        }
+
+        self.entries.truncate(keep);
+        self.truncated = true;
+        self.cursor = Some(cursor);
+    }
+}
+
+/// Keeps track of the resources used by a running query.
+///
+/// The cursor is the timestamp from which the query has to be run again to get the rest
+/// of the result. Points before it are either in the output or were skipped by the query.
+///
+/// A truncated query that hits a limit on the first timestamp it scanned would return
+/// nothing and be resumed from the same cursor forever. It completes the points of that
+/// timestamp instead, over its limits, and is resumed after them. The same goes for the
+/// first aggregate window, when it starts at the first timestamp all of its points are
+/// completed.
+pub struct Budget {
+    limits: QueryLimits,
+    start_instructions: u64,
+    scanned: Cell<u64>,
+    returned_bytes: Cell<u64>,
+    exceeded: Cell<Option<(Limit, u64)>>,
+    first: Cell<Option<u64>>,     // timestamp of the first point scanned
+    first_end: Cell<Option<u64>>, // end of the first aggregate window
+}
+
+impl Budget {
+    pub fn new(limits: &QueryLimits) -> Self {
+        Self {
+            limits: limits.clone(),
+            start_instructions: instruction_counter(),
+            scanned: Cell::new(0),
+            returned_bytes: Cell::new(0),
+            exceeded: Cell::new(None),
+            first: Cell::new(None),
+            first_end: Cell::new(None),
+        }
+    }
+
+    /// Accounts for a point read from the indexes, returns false once a limit is hit.
+    pub fn scan(&self, timestamp: u64) -> bool {
+        if self.exceeded.get().is_some() {
+            return self.within(timestamp);
+        }
+        if self.first.get().is_none() {
+            self.first.set(Some(timestamp));
+        }
+
+        let scanned = self.scanned.get() + 1;
+        self.scanned.set(scanned);
+
+        if scanned > self.limits.max_scanned_points {
+            self.exceed(Limit::ScannedPoints, timestamp);
+        } else if scanned & (INSTRUCTION_CHECK_INTERVAL - 1) == 0
+            && instruction_counter() - self.start_instructions > self.limits.max_instructions
+        {
+            self.exceed(Limit::Instructions, timestamp);
+        }
+
+        self.within(timestamp)
+    }
+
+    /// Accounts for an entry added to the output, returns false once a limit is hit.
+    pub fn output(&self, entry: &Entry) -> bool {
+        if self.exceeded.get().is_some() {
+            return self.within(entry.timestamp);
+        }
+
+        let returned_bytes = self.returned_bytes.get() + entry.size_estimate() as u64;
+        self.returned_bytes.set(returned_bytes);
+
+        if returned_bytes > self.limits.max_returned_bytes {
+            self.exceed(Limit::ReturnedBytes, entry.timestamp);
+        }
+
+        self.within(entry.timestamp)
+    }
+
+    /// Tells that the points from `start` to `end` (exclusive) are aggregated into one result.
+    /// When they start at the first timestamp a truncated query completes all of them.
+    pub fn window(&self, start: u64, end: u64) {
+        if self.limits.on_limit != LimitBehaviour::Truncate || self.first.get() != Some(start) {
+            return;
+        }
+
+        self.first_end.set(Some(
+            self.first_end.get().map_or(end, |first| first.max(end)),
+        ));
+        // the limit was hit on the first timestamp, before the window was known
+        if let Some((limit, cursor)) = self.exceeded.get() {
+            self.exceeded.set(Some((limit, cursor.max(end))));
+        }
+    }
+
+    /// Moves the cursor back, used by steps that drop partially processed data
+    pub fn rewind(&self, timestamp: u64) {
+        if let Some((limit, cursor)) = self.exceeded.get() {
+            self.exceeded.set(Some((limit, cursor.min(timestamp))));
+        }
+    }
+
+    /// Where to resume once a limit was hit, whatever `on_limit` says.
+    pub fn cursor(&self) -> Option<u64> {
+        self.exceeded.get().map(|(_, cursor)| cursor)
+    }
+
+    /// Fails once a limit was hit whatever `on_limit` says, for results that can't be cut
+    /// short at a cursor.
+    pub fn check(&self) -> Result<(), QueryError> {
+        match self.exceeded.get() {
+            Some((limit, cursor)) => Err(QueryError::LimitExceeded { limit, cursor }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn finish(&self, entries: Vec<Entry>) -> Result<QueryResult, QueryError> {
+        match self.exceeded.get() {
+            None => Ok(QueryResult {
+                entries,
+                truncated: false,
+                cursor: None,
+            }),
+            Some((limit, cursor)) => match self.limits.on_limit {
+                LimitBehaviour::Abort => Err(QueryError::LimitExceeded { limit, cursor }),
+                LimitBehaviour::Truncate => Ok(QueryResult {
+                    // Other series can have points at the cursor, they are all returned on resume
+                    entries: entries
+                        .into_iter()
+                        .take_while(|entry| entry.timestamp < cursor)
+                        .collect(),
+                    truncated: true,
+                    cursor: Some(cursor),
+                }),
+            },
+        }
+    }
+
+    fn exceed(&self, limit: Limit, timestamp: u64) {
+        let mut cursor = timestamp;
+        if self.limits.on_limit == LimitBehaviour::Truncate {
+            if self.first.get() == Some(timestamp) {
+                cursor = timestamp.saturating_add(1);
+            }
+            if let Some(end) = self.first_end.get().filter(|end| timestamp < *end) {
+                cursor = cursor.max(end);
+            }
+        }
+        self.exceeded.set(Some((limit, cursor)));
+    }
+
+    // Whether the point at `timestamp` is still processed, the points before the cursor are
+    // completed once a limit was hit
+    fn within(&self, timestamp: u64) -> bool {
+        self.exceeded
+            .get()
+            .is_none_or(|(_, cursor)| timestamp < cursor)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn instruction_counter() -> u64 {
+    ic_cdk::api::performance_counter(0)
+}
+
+// The performance counter only exists inside a canister
+#[cfg(not(target_arch = "wasm32"))]
+fn instruction_counter() -> u64 {
+    0
+}
@@ -1,14 +1,46 @@
 use super::{
     entry::{Entry, Value},
+    executor,
     index::Indexes,
-    query::QueryResponse,
+    limits::{Budget, LimitBehaviour, QueryError, QueryLimits, QueryResult},
+    planner::{QueryPlan, Scan},
+    transform::{TransformRule, TransformRules, VERSION_FIELD},
     Action,
 };
-use std::{collections::HashMap, error::Error, rc::Rc};
+use candid::CandidType;
+use serde::Deserialize;
+use std::{collections::HashMap, error::Error};
+
+// Tags of a series with the entries a query returned for it
+type SeriesEntries = (HashMap<String, Value>, Vec<Entry>);
+
+/// Memory used by a measurement. Raw bytes are what the points would take as individual
+/// entries, stored bytes what they take in chunks, the head, the tag sets and the tag dictionary.
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct MeasurementStats {
+    pub points: u64,
+    pub series: u64,
+    pub interned_strings: u64,
+    pub sealed_chunks: u64,
+    pub head_points: u64,
+    pub raw_bytes: u64,
+    pub stored_bytes: u64,
+    pub raw_bytes_per_point: f64,
+    pub stored_bytes_per_point: f64,
+}
+
+/// Outcome of a call to `reprocess`. When the limits cut the time range short, the rest is
+/// reprocessed by calling again from `cursor`.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub struct ReprocessResult {
+    pub changed: u64,
+    pub cursor: Option<u64>,
+}
 
 pub struct Measurement {
     pub name: String,
     indexes: Indexes,
+    transforms: TransformRules,
 }
 
 impl Measurement {
@@ -17,46 +49,199 @@ impl Measurement {
             name: name.to_string(),
 
             indexes: Indexes::new(),
+            transforms: TransformRules::default(),
         }
     }
 
+    /// Adds a point, after transforming its fields with the transform rules.
     pub fn add_entry(
         &mut self,
         timestamp: u64,
         fields: &HashMap<String, Value>,
         tags: &HashMap<String, Value>,
     ) {
+        let mut fields = fields.clone();
+        self.transforms.apply(&mut fields, tags, &[]);
+
         let entry = Entry {
             timestamp: timestamp,
-            fields: fields.clone(),
+            fields,
             tags: tags.clone(),
         };
 
         self.indexes.insert(timestamp, entry);
     }
 
-    pub fn list_entries(&self) -> Vec<Rc<Entry>> {
+    pub fn transform_rules(&self) -> &TransformRules {
+        &self.transforms
+    }
+
+    /// Replaces the transform rules applied to new points, returns the new version.
+    pub fn set_transform_rules(&mut self, rules: Vec<TransformRule>) -> Result<u64, String> {
+        for rule in &rules {
+            rule.validate()?;
+        }
+        self.transforms.rules = rules;
+        self.transforms.version += 1;
+        Ok(self.transforms.version)
+    }
+
+    /// Transforms the points within `start..=end` again with the current rules, starting
+    /// from their raw values. Points transformed with the current version are skipped, and
+    /// fields whose raw value wasn't kept are left as they are. Returns the number of points
+    /// that changed, and the cursor to continue from when the scan ran into the limits.
+    pub fn reprocess(&mut self, start: u64, end: u64, limits: &QueryLimits) -> ReprocessResult {
+        let scan = Scan {
+            start,
+            end,
+            tags: vec![],
+        };
+        // Always stops at a cursor, the points before it are done and stay done
+        let budget = Budget::new(&QueryLimits {
+            on_limit: LimitBehaviour::Truncate,
+            ..limits.clone()
+        });
+        let version = Value::UInt(self.transforms.version as u128);
+        let mut points: Vec<Entry> = self
+            .indexes
+            .scan_iter(&scan)
+            .take_while(|point| budget.scan(point.timestamp))
+            .filter(|point| point.fields.get(VERSION_FIELD) != Some(&version))
+            .map(|point| point.into_entry())
+            .collect();
+
+        // Other series can have points at the cursor, they are all reprocessed on resume
+        let cursor = budget.cursor();
+        if let Some(cursor) = cursor {
+            points.retain(|point| point.timestamp < cursor);
+        }
+
+        let mut changed = 0;
+        for mut entry in points {
+            let (mut fields, lost) = TransformRules::restore(&entry.fields);
+            self.transforms.apply(&mut fields, &entry.tags, &lost);
+            if fields != entry.fields {
+                entry.fields = fields;
+                self.indexes.insert(entry.timestamp, entry);
+                changed += 1;
+            }
+        }
+        ReprocessResult { changed, cursor }
+    }
+
+    pub fn list_entries(&self) -> Vec<Entry> {
         self.indexes.values()
     }
 
-    pub fn apply(&self, actions: &Vec<Action>) -> Result<Option<QueryResponse>, Box<dyn Error>> {
-        if actions.len() > 0 {
-            let mut query_response = actions[0].init(&self.indexes)?;
+    pub fn stats(&self) -> MeasurementStats {
+        let stats = self.indexes.stats();
+        let per_point = |bytes: usize| match stats.points {
+            0 => 0.0,
+            points => bytes as f64 / points as f64,
+        };
+
+        MeasurementStats {
+            points: stats.points as u64,
+            series: self.indexes.series_count() as u64,
+            interned_strings: self.indexes.interned_strings() as u64,
+            sealed_chunks: stats.sealed_chunks as u64,
+            head_points: stats.head_points as u64,
+            raw_bytes: stats.raw_bytes as u64,
+            stored_bytes: stats.stored_bytes as u64,
+            raw_bytes_per_point: per_point(stats.raw_bytes),
+            stored_bytes_per_point: per_point(stats.stored_bytes),
+        }
+    }
+
+    /// Runs the actions within the given limits. A query that was cut short by its limits
+    /// is continued by running it again with the returned cursor.
+    pub fn apply(
+        &self,
+        actions: &[Action],
+        limits: &QueryLimits,
+        cursor: Option<u64>,
+    ) -> Result<QueryResult, QueryError> {
+        if actions.is_empty() {
+            return Err(QueryError::Invalid("Query contains no actions".to_string()));
+        }
+
+        let mut plan = QueryPlan::new(actions, &self.indexes)?;
+        if let Some(cursor) = cursor {
+            plan.scan.start = plan.scan.start.max(cursor);
+        }
 
-            for action in actions.iter().skip(1) {
-                query_response = action.evaluate(&query_response)?;
+        let budget = Budget::new(limits);
+        let entries = executor::execute(&plan, self.indexes.scan_iter(&plan.scan), &budget)?;
+
+        budget.finish(entries)
+    }
+
+    /// Runs the actions on each series they select on its own, e.g. to aggregate the points
+    /// of every series separately. Returns the tags of the series with their entries, series
+    /// without entries are left out. The series share the budget, which callers running several
+    /// queries for one request pass to all of them. The query fails once it is exceeded.
+    pub fn apply_by_series(
+        &self,
+        actions: &[Action],
+        budget: &Budget,
+    ) -> Result<Vec<SeriesEntries>, QueryError> {
+        if actions.is_empty() {
+            return Err(QueryError::Invalid("Query contains no actions".to_string()));
+        }
+
+        let plan = QueryPlan::new(actions, &self.indexes)?;
+        let mut result = vec![];
+        for series in self.indexes.scan_series(&plan.scan) {
+            let tags = series.tags().as_ref().clone();
+            let entries = executor::execute(&plan, series, budget)?;
+            budget.check()?;
+            if !entries.is_empty() {
+                result.push((tags, entries));
             }
+        }
 
-            Ok(Some(query_response))
-        } else {
-            Ok(None)
+        Ok(result)
+    }
+
+    /// Executes the plan by materializing the result of every step, the way queries were run
+    /// before the iterator based executor. Tests and the benchmark compare both executors with it.
+    #[cfg(any(test, feature = "benchmark"))]
+    pub fn apply_eager(
+        &self,
+        actions: &[Action],
+    ) -> Result<Option<super::query::QueryResponse>, Box<dyn Error>> {
+        if actions.is_empty() {
+            return Ok(None);
+        }
+
+        let plan = QueryPlan::new(actions, &self.indexes)?;
+
+        let mut query_response = super::query::QueryResponse::new();
+        query_response.items = self.indexes.scan(&plan.scan);
+
+        for action in plan.steps.iter() {
+            query_response = action.evaluate(&query_response)?;
         }
+
+        Ok(Some(query_response))
+    }
+
+    /// Describes how `apply` would execute the actions, one plan step per line.
+    pub fn explain(&self, actions: &[Action]) -> Result<String, Box<dyn Error>> {
+        Ok(QueryPlan::new(actions, &self.indexes)?.to_string())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::timedb::{test_helper::create_test_entries, expression::Expression};
+    use crate::timedb::{
+        aggregate::AggregateFunction,
+        expression::Expression,
+        limits::{Limit, LimitBehaviour},
+        test_helper::create_test_entries,
+        transform::Transform,
+        Device, DEVICES,
+    };
 
     use super::*;
 
@@ -98,6 +283,21 @@ mod tests {
         assert_eq!(entries.len(), 1000/* expected number of entries */);
     }
 
+    #[test]
+    fn test_stats() {
+        let mut measurement = Measurement::new("test_measurement");
+        for entry in create_test_entries() {
+            measurement.add_entry(entry.timestamp, &entry.fields, &entry.tags);
+        }
+
+        let stats = measurement.stats();
+        assert_eq!(stats.points, 1000);
+        assert!(stats.series <= 9);
+        // tag names, the location and at most 9 sensor ids
+        assert!(stats.interned_strings <= 12);
+        assert!(stats.stored_bytes_per_point < stats.raw_bytes_per_point);
+    }
+
     #[test]
     fn test_apply_actions() {
         let mut measurement = Measurement::new("test_measurement");
@@ -125,11 +325,13 @@ mod tests {
         //  |> range(from, to)
         //  |> filer((x) => x.sensor_id = 'sensor_6')
 
-        if let Ok(Some(query_response)) = measurement.apply(&actions) {
+        if let Ok(QueryResult { entries, .. }) =
+            measurement.apply(&actions, &QueryLimits::default(), None)
+        {
             // Assertions based on expected outcomes of applying the actions
-            print!("Items {} \n", query_response.items.len());
+            print!("Items {} \n", entries.len());
 
-            for entry in query_response.items {
+            for entry in entries {
                 assert!(entry.timestamp >= start && entry.timestamp <= end);
             
                 let sensor_val = entry.get_value("sensor_id");
@@ -145,4 +347,410 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_apply_actions_in_any_order() {
+        let mut measurement = Measurement::new("test_measurement");
+        for entry in create_test_entries() {
+            measurement.add_entry(entry.timestamp, &entry.fields, &entry.tags);
+        }
+
+        let range = Action::Range(1625230000, Some(1625230000 + 3 * 30 * 24 * 60 * 60));
+        let sensor = Action::Filter(Expression::Eq(
+            "sensor_id".to_string(),
+            Value::String("sensor_6".to_string()),
+        ));
+
+        let limits = QueryLimits::default();
+        let range_first = measurement
+            .apply(&[range.clone(), sensor.clone()], &limits, None)
+            .unwrap()
+            .entries;
+        let filter_first = measurement
+            .apply(&[sensor.clone(), range], &limits, None)
+            .unwrap()
+            .entries;
+
+        let timestamps = |entries: &Vec<Entry>| -> Vec<u64> {
+            entries.iter().map(|entry| entry.timestamp).collect()
+        };
+        assert_eq!(timestamps(&range_first), timestamps(&filter_first));
+
+        let explain = measurement.explain(&[sensor]).unwrap();
+        assert!(explain.starts_with("IndexScan tag sensor_id=\"sensor_6\""));
+    }
+
+    #[test]
+    fn test_apply_enrich() {
+        let mut measurement = Measurement::new("test_measurement");
+        for entry in create_test_entries() {
+            measurement.add_entry(entry.timestamp, &entry.fields, &entry.tags);
+        }
+        // a point carrying the attribute itself makes `building` an indexed tag
+        let fields = HashMap::from([("humidity".to_string(), Value::Int(40))]);
+        let tags = HashMap::from([
+            (
+                "sensor_id".to_string(),
+                Value::String("sensor_1".to_string()),
+            ),
+            ("building".to_string(), Value::String("B".to_string())),
+        ]);
+        measurement.add_entry(1, &fields, &tags);
+
+        let in_building_b = Device {
+            name: "Boiler room".to_string(),
+            attributes: vec![("building".to_string(), "B".to_string())],
+            ..Default::default()
+        };
+        DEVICES.with(|d| {
+            let mut devices = d.borrow_mut();
+            devices
+                .set("sensor_6".to_string(), in_building_b.clone())
+                .unwrap();
+            devices.set("sensor_7".to_string(), in_building_b).unwrap();
+        });
+
+        let actions = vec![
+            Action::Enrich("sensor_id".to_string()),
+            Action::Filter(Expression::Eq(
+                "building".to_string(),
+                Value::String("B".to_string()),
+            )),
+        ];
+        let entries = measurement
+            .apply(&actions, &QueryLimits::default(), None)
+            .unwrap()
+            .entries;
+
+        let expected = measurement
+            .list_entries()
+            .iter()
+            .filter(|entry| {
+                entry.tags.contains_key("building")
+                    || ["sensor_6", "sensor_7"]
+                        .map(|id| Value::String(id.to_string()))
+                        .contains(&entry.tags["sensor_id"])
+            })
+            .count();
+        assert_eq!(entries.len(), expected);
+        // the point of sensor_1 comes first, it has the attribute but no registered device
+        assert!(!entries[0].tags.contains_key("device"));
+        assert!(entries[1..]
+            .iter()
+            .all(|entry| entry.tags["device"] == Value::String("Boiler room".to_string())));
+
+        let eager = measurement.apply_eager(&actions).unwrap().unwrap().eval();
+        assert_eq!(eager.len(), expected);
+
+        let explain = measurement.explain(&actions).unwrap();
+        assert!(explain.starts_with("IndexScan main"));
+        assert!(explain.contains("Enrich sensor_id\nFilter"));
+    }
+
+    #[test]
+    fn test_transform_and_reprocess() {
+        let mut measurement = Measurement::new("test_measurement");
+        let tags = HashMap::from([(
+            "sensor_id".to_string(),
+            Value::String("sensor_6".to_string()),
+        )]);
+        let temperature =
+            |value: i128| HashMap::from([("temperature".to_string(), Value::Int(value))]);
+        measurement.add_entry(1, &temperature(20), &tags);
+
+        let offset = |offset: f64| TransformRule {
+            field: "temperature".to_string(),
+            tags: vec![("sensor_id".to_string(), "sensor_6".to_string())],
+            steps: vec![Transform::Linear { scale: 1.0, offset }],
+            keep_raw: true,
+        };
+        assert_eq!(measurement.set_transform_rules(vec![offset(-0.5)]), Ok(1));
+        measurement.add_entry(2, &temperature(21), &tags);
+
+        let entries = measurement.list_entries();
+        assert_eq!(entries[0].fields, temperature(20));
+        assert_eq!(
+            entries[1].fields.get("temperature"),
+            Some(&Value::Float(20.5))
+        );
+        assert_eq!(
+            entries[1].fields.get("temperature_raw"),
+            Some(&Value::Int(21))
+        );
+        assert_eq!(entries[1].fields.get(VERSION_FIELD), Some(&Value::UInt(1)));
+
+        // the calibration changed, both points are transformed from their raw value
+        assert_eq!(measurement.set_transform_rules(vec![offset(1.0)]), Ok(2));
+        let limits = QueryLimits::default();
+        assert_eq!(measurement.reprocess(0, u64::MAX, &limits).changed, 2);
+        assert_eq!(measurement.reprocess(0, u64::MAX, &limits).changed, 0);
+        let entries = measurement.list_entries();
+        assert_eq!(
+            entries[0].fields.get("temperature"),
+            Some(&Value::Float(21.0))
+        );
+        assert_eq!(
+            entries[1].fields.get("temperature"),
+            Some(&Value::Float(22.0))
+        );
+        assert_eq!(entries[1].fields.get(VERSION_FIELD), Some(&Value::UInt(2)));
+
+        // without rules the raw values come back
+        assert_eq!(measurement.set_transform_rules(vec![]), Ok(3));
+        assert_eq!(measurement.reprocess(2, 2, &limits).changed, 1);
+        assert_eq!(measurement.list_entries()[1].fields, temperature(21));
+
+        let invalid = TransformRule {
+            field: "temperature_raw".to_string(),
+            ..offset(0.0)
+        };
+        assert!(measurement.set_transform_rules(vec![invalid]).is_err());
+        assert_eq!(measurement.transform_rules().version, 3);
+    }
+
+    #[test]
+    fn test_reprocess_with_new_rule() {
+        let mut measurement = Measurement::new("test_measurement");
+        let rule = |field: &str, scale: f64, keep_raw: bool| TransformRule {
+            field: field.to_string(),
+            tags: vec![],
+            steps: vec![Transform::Linear { scale, offset: 0.0 }],
+            keep_raw,
+        };
+        let fields = HashMap::from([
+            ("temperature".to_string(), Value::Int(20)),
+            ("humidity".to_string(), Value::Int(50)),
+        ]);
+        measurement
+            .set_transform_rules(vec![rule("temperature", 2.0, true)])
+            .unwrap();
+        measurement.add_entry(1, &fields, &HashMap::new());
+
+        // humidity wasn't transformed, it is taken as raw
+        let limits = QueryLimits::default();
+        measurement
+            .set_transform_rules(vec![
+                rule("temperature", 2.0, true),
+                rule("humidity", 0.01, false),
+            ])
+            .unwrap();
+        assert_eq!(measurement.reprocess(0, u64::MAX, &limits).changed, 1);
+        let entry = &measurement.list_entries()[0];
+        assert_eq!(entry.fields.get("temperature"), Some(&Value::Float(40.0)));
+        assert_eq!(entry.fields.get("humidity"), Some(&Value::Float(0.5)));
+
+        // now it was transformed without its raw value, it is left as it is
+        measurement
+            .set_transform_rules(vec![rule("humidity", 0.1, false)])
+            .unwrap();
+        assert_eq!(measurement.reprocess(0, u64::MAX, &limits).changed, 1);
+        let entry = &measurement.list_entries()[0];
+        assert_eq!(entry.fields.get("temperature"), Some(&Value::Int(20)));
+        assert_eq!(entry.fields.get("humidity"), Some(&Value::Float(0.5)));
+    }
+
+    #[test]
+    fn test_reprocess_limits() {
+        let mut measurement = Measurement::new("test_measurement");
+        let fields = HashMap::from([("temperature".to_string(), Value::Int(20))]);
+        for (timestamp, sensor) in [(1, "a"), (1, "b"), (2, "a"), (3, "a"), (3, "b")] {
+            let tags = HashMap::from([("sensor".to_string(), Value::String(sensor.to_string()))]);
+            measurement.add_entry(timestamp, &fields, &tags);
+        }
+        measurement
+            .set_transform_rules(vec![TransformRule {
+                field: "temperature".to_string(),
+                tags: vec![],
+                steps: vec![Transform::Linear {
+                    scale: 1.0,
+                    offset: 1.0,
+                }],
+                keep_raw: true,
+            }])
+            .unwrap();
+
+        // the points of the first timestamp are all done, even over the limits, the last
+        // call only tells that nothing is left
+        let limits = QueryLimits {
+            max_scanned_points: 1,
+            ..QueryLimits::default()
+        };
+        let mut start = 0;
+        let mut calls = vec![];
+        loop {
+            let result = measurement.reprocess(start, u64::MAX, &limits);
+            calls.push(result.clone());
+            match result.cursor {
+                Some(cursor) => start = cursor,
+                None => break,
+            }
+        }
+        let changed: Vec<u64> = calls.iter().map(|result| result.changed).collect();
+        assert_eq!(changed, vec![2, 1, 2, 0]);
+        assert!(measurement
+            .list_entries()
+            .iter()
+            .all(|entry| entry.fields.get("temperature") == Some(&Value::Float(21.0))));
+    }
+
+    #[test]
+    fn test_apply_matches_eager_execution() {
+        let mut measurement = Measurement::new("test_measurement");
+        for entry in create_test_entries() {
+            measurement.add_entry(entry.timestamp, &entry.fields, &entry.tags);
+        }
+
+        let actions = vec![
+            Action::Filter(Expression::Gt("temperature".to_string(), Value::Int(20))),
+            Action::Filter(Expression::FieldFilter(vec!["temperature".to_string()])),
+            // test entries are spaced 31536 apart, this puts about three in every window
+            Action::AggregateWindow("100us".to_string(), AggregateFunction::Max),
+            Action::Range(1625230000 + 24 * 60 * 60, None),
+        ];
+
+        let lazy = measurement
+            .apply(&actions, &QueryLimits::default(), None)
+            .unwrap()
+            .entries;
+        let eager = measurement.apply_eager(&actions).unwrap().unwrap().eval();
+
+        assert!(!lazy.is_empty());
+        assert_eq!(lazy.len(), eager.len());
+        for (lazy, eager) in lazy.iter().zip(eager.iter()) {
+            assert_eq!(lazy.timestamp, eager.timestamp);
+            assert_eq!(lazy.fields, eager.fields);
+        }
+    }
+
+    #[test]
+    fn test_apply_limits() {
+        let mut measurement = Measurement::new("test_measurement");
+        for entry in create_test_entries() {
+            measurement.add_entry(entry.timestamp, &entry.fields, &entry.tags);
+        }
+
+        let actions = vec![Action::AggregateWindow(
+            "100us".to_string(),
+            AggregateFunction::Mean,
+        )];
+        let unlimited = measurement
+            .apply(&actions, &QueryLimits::default(), None)
+            .unwrap()
+            .entries;
+
+        let mut limits = QueryLimits {
+            max_scanned_points: 100,
+            ..QueryLimits::default()
+        };
+
+        match measurement.apply(&actions, &limits, None) {
+            Err(QueryError::LimitExceeded { limit, .. }) => assert_eq!(limit, Limit::ScannedPoints),
+            _ => panic!("Query should exceed the scanned points limit"),
+        }
+
+        // Resuming truncated queries from their cursor gives the same windows as one big query
+        limits.on_limit = LimitBehaviour::Truncate;
+        let mut cursor = None;
+        let mut resumed = vec![];
+        loop {
+            let result = measurement.apply(&actions, &limits, cursor).unwrap();
+            resumed.extend(result.entries);
+            if !result.truncated {
+                break;
+            }
+            cursor = result.cursor;
+        }
+
+        let timestamps = |entries: &Vec<Entry>| -> Vec<u64> {
+            entries.iter().map(|entry| entry.timestamp).collect()
+        };
+        assert_eq!(timestamps(&resumed), timestamps(&unlimited));
+    }
+
+    #[test]
+    fn test_apply_limits_on_shared_timestamps() {
+        let mut measurement = Measurement::new("test_measurement");
+        for timestamp in [10, 20] {
+            for sensor in ["s1", "s2", "s3"] {
+                measurement.add_entry(
+                    timestamp,
+                    &HashMap::from([("temperature".to_string(), Value::Int(20))]),
+                    &HashMap::from([("sensor_id".to_string(), Value::String(sensor.to_string()))]),
+                );
+            }
+        }
+
+        // Pages end within the points of a timestamp, resuming still gets past them
+        let actions = vec![Action::Range(0, None)];
+        for limits in [
+            QueryLimits {
+                max_scanned_points: 2,
+                on_limit: LimitBehaviour::Truncate,
+                ..QueryLimits::default()
+            },
+            QueryLimits {
+                max_returned_bytes: 1,
+                on_limit: LimitBehaviour::Truncate,
+                ..QueryLimits::default()
+            },
+        ] {
+            let mut cursor = None;
+            let mut pages = vec![];
+            loop {
+                let result = measurement.apply(&actions, &limits, cursor).unwrap();
+                pages.push(result.entries.len());
+                if !result.truncated {
+                    break;
+                }
+                assert!(result.cursor > cursor);
+                cursor = result.cursor;
+            }
+            assert_eq!(pages, vec![3, 3, 0]);
+        }
+    }
+
+    #[test]
+    fn test_apply_limits_on_large_windows() {
+        let mut measurement = Measurement::new("test_measurement");
+        for timestamp in 0..10 {
+            measurement.add_entry(
+                timestamp,
+                &HashMap::from([("count".to_string(), Value::Int(timestamp as i128))]),
+                &HashMap::new(),
+            );
+        }
+
+        // A window holding more points than the limit allows is completed when it is the
+        // first of a page, the windows after it are recomputed on the next page
+        let pages = |window: &str, max_scanned_points: u64| {
+            let actions = vec![Action::AggregateWindow(
+                window.to_string(),
+                AggregateFunction::Max,
+            )];
+            let limits = QueryLimits {
+                max_scanned_points,
+                on_limit: LimitBehaviour::Truncate,
+                ..QueryLimits::default()
+            };
+            let mut cursor = None;
+            let mut pages = vec![];
+            loop {
+                let result = measurement.apply(&actions, &limits, cursor).unwrap();
+                let windows: Vec<u64> = result.entries.iter().map(|e| e.timestamp).collect();
+                pages.push(windows);
+                if !result.truncated {
+                    break;
+                }
+                assert!(result.cursor > cursor);
+                cursor = result.cursor;
+            }
+            pages
+        };
+
+        assert_eq!(pages("5ns", 2), vec![vec![0], vec![5], vec![]]);
+        assert_eq!(
+            pages("2ns", 3),
+            vec![vec![0], vec![2], vec![4], vec![6], vec![8]]
+        );
+        assert_eq!(pages("10ns", 1), vec![vec![0], vec![]]);
+    }
 }
@@ -1,14 +1,31 @@
 mod action;
 mod aggregate;
+mod chunk;
+mod duration;
 mod entry;
+mod executor;
 mod expression;
+mod filter;
 mod index;
+mod limits;
 mod measurement;
+mod planner;
 mod query;
+mod registry;
+mod series;
+mod tags;
 mod timedb;
+mod transform;
 
-mod test_helper;
+pub(crate) mod test_helper;
 
 pub use action::Action;
-pub use entry::Entry;
+pub use aggregate::AggregateFunction;
+pub use duration::{format_rfc3339, parse_rfc3339, Duration};
+pub use entry::{Entry, Value};
+pub use expression::Expression;
+pub use limits::{Budget, QueryError, QueryLimits, QueryResult};
+pub use measurement::{MeasurementStats, ReprocessResult};
+pub use registry::{Device, DEVICES};
 pub use timedb::*;
+pub use transform::{TransformRule, TransformRules};
@@ -0,0 +1,246 @@
+use std::{error::Error, fmt};
+
+use super::{duration::Duration, entry::Value, expression::Expression, index::Indexes, Action};
+
+/// Part of a query that is answered directly by the indexes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scan {
+    pub start: u64,
+    pub end: u64,
+    pub tags: Vec<(String, String)>, // tag equality predicates answered by the tag index
+}
+
+impl Scan {
+    pub fn new() -> Self {
+        Self {
+            start: 0,
+            end: u64::MAX,
+            tags: vec![],
+        }
+    }
+}
+
+/// Logical plan of a query, built from the list of actions sent by the client.
+///
+/// `Range` and `Filter` commute, so everything before the first `AggregateWindow` is
+/// reordered: all ranges are merged into the time bounds of the scan and tag equality
+/// predicates are pushed down into the tag index. Filters after an `Enrich` may test tags
+/// added from the device registry, so they stay behind it and are not pushed down. Actions
+/// after an aggregation work on aggregated entries, their ranges are merged and moved to the
+/// front of the segment while everything else keeps its order.
+pub struct QueryPlan {
+    pub scan: Scan,
+    pub steps: Vec<Action>,
+}
+
+impl QueryPlan {
+    pub fn new(actions: &[Action], indexes: &Indexes) -> Result<Self, Box<dyn Error>> {
+        let mut plan = QueryPlan {
+            scan: Scan::new(),
+            steps: vec![],
+        };
+
+        let mut aggregated = false;
+        let mut enriched = false;
+        let mut segment_range: Option<(u64, u64)> = None;
+        let mut segment_steps: Vec<Action> = vec![];
+
+        for action in actions {
+            match action {
+                Action::Range(start, end) => {
+                    let end = end.unwrap_or(u64::MAX);
+
+                    if aggregated {
+                        let (current_start, current_end) = segment_range.unwrap_or((0, u64::MAX));
+                        segment_range = Some((current_start.max(*start), current_end.min(end)));
+                    } else {
+                        plan.scan.start = plan.scan.start.max(*start);
+                        plan.scan.end = plan.scan.end.min(end);
+                    }
+                }
+                Action::Filter(expression) => {
+                    if aggregated {
+                        segment_steps.push(action.clone());
+                    } else if enriched {
+                        plan.steps.push(action.clone());
+                    } else if let Some(rest) =
+                        QueryPlan::push_down(expression, indexes, &mut plan.scan)
+                    {
+                        plan.steps.push(Action::Filter(rest));
+                    }
+                }
+                Action::AggregateWindow(window, _) => {
+                    Duration::parse(window)
+                        .map_err(|err| format!("Invalid aggregate window '{}': {}", window, err))?;
+
+                    plan.flush_segment(segment_range.take(), &mut segment_steps);
+                    plan.steps.push(action.clone());
+                    aggregated = true;
+                }
+                Action::Enrich(_) => {
+                    if aggregated {
+                        segment_steps.push(action.clone());
+                    } else {
+                        plan.steps.push(action.clone());
+                        enriched = true;
+                    }
+                }
+            }
+        }
+
+        plan.flush_segment(segment_range, &mut segment_steps);
+
+        Ok(plan)
+    }
+
+    fn flush_segment(&mut self, range: Option<(u64, u64)>, steps: &mut Vec<Action>) {
+        if let Some((start, end)) = range {
+            self.steps.push(Action::Range(start, Some(end)));
+        }
+
+        self.steps.append(steps);
+    }
+
+    // Moves tag equality conjuncts of the expression into the scan, returns what is left
+    fn push_down(
+        expression: &Expression,
+        indexes: &Indexes,
+        scan: &mut Scan,
+    ) -> Option<Expression> {
+        let mut conjuncts = vec![];
+        expression.conjuncts(&mut conjuncts);
+
+        let mut rest: Option<Expression> = None;
+
+        for conjunct in conjuncts {
+            match conjunct {
+                Expression::Eq(name, Value::String(value)) if indexes.is_tag_indexed(name) => {
+                    let predicate = (name.clone(), value.clone());
+                    if !scan.tags.contains(&predicate) {
+                        scan.tags.push(predicate);
+                    }
+                }
+                _ => {
+                    rest = Some(match rest {
+                        Some(left) => Expression::And(Box::new(left), Box::new(conjunct.clone())),
+                        None => conjunct.clone(),
+                    })
+                }
+            }
+        }
+
+        rest
+    }
+}
+
+impl fmt::Display for QueryPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.scan.tags.is_empty() {
+            write!(f, "IndexScan main")?;
+        } else {
+            write!(f, "IndexScan tag")?;
+            for (tag, value) in &self.scan.tags {
+                write!(f, " {}={:?}", tag, value)?;
+            }
+        }
+        writeln!(f, " time=[{}, {}]", self.scan.start, self.scan.end)?;
+
+        for step in &self.steps {
+            match step {
+                Action::Range(start, end) => {
+                    writeln!(f, "Range [{}, {}]", start, end.unwrap_or(u64::MAX))?
+                }
+                Action::Filter(expression) => writeln!(f, "Filter {:?}", expression)?,
+                Action::AggregateWindow(window, function) => {
+                    writeln!(f, "AggregateWindow {} {:?}", window, function)?
+                }
+                Action::Enrich(tag) => writeln!(f, "Enrich {}", tag)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::timedb::{aggregate::AggregateFunction, Entry};
+
+    use super::*;
+
+    fn sensor_indexes() -> Indexes {
+        let mut indexes = Indexes::new();
+        indexes.insert(
+            1,
+            Entry {
+                timestamp: 1,
+                fields: HashMap::from([("temperature".to_string(), Value::Int(20))]),
+                tags: HashMap::from([(
+                    "sensor_id".to_string(),
+                    Value::String("sensor_1".to_string()),
+                )]),
+            },
+        );
+        indexes
+    }
+
+    #[test]
+    fn test_ranges_and_tags_are_pushed_into_scan() {
+        let indexes = sensor_indexes();
+        let temperature = Expression::Gt("temperature".to_string(), Value::Int(20));
+        let actions = vec![
+            Action::Filter(Expression::And(
+                Box::new(Expression::Eq(
+                    "sensor_id".to_string(),
+                    Value::String("sensor_1".to_string()),
+                )),
+                Box::new(temperature.clone()),
+            )),
+            Action::Range(10, Some(100)),
+            Action::Range(50, None),
+        ];
+
+        let plan = QueryPlan::new(&actions, &indexes).unwrap();
+
+        assert_eq!(
+            plan.scan,
+            Scan {
+                start: 50,
+                end: 100,
+                tags: vec![("sensor_id".to_string(), "sensor_1".to_string())],
+            }
+        );
+        assert_eq!(plan.steps.len(), 1);
+        assert!(
+            matches!(&plan.steps[0], Action::Filter(Expression::Gt(name, _)) if name == "temperature")
+        );
+    }
+
+    #[test]
+    fn test_steps_after_aggregate_keep_their_segment() {
+        let indexes = sensor_indexes();
+        let actions = vec![
+            Action::AggregateWindow("1h".to_string(), AggregateFunction::Mean),
+            Action::Filter(Expression::Eq(
+                "sensor_id".to_string(),
+                Value::String("sensor_1".to_string()),
+            )),
+            Action::Range(10, None),
+        ];
+
+        let plan = QueryPlan::new(&actions, &indexes).unwrap();
+
+        assert_eq!(plan.scan, Scan::new());
+        assert!(matches!(plan.steps[0], Action::AggregateWindow(_, _)));
+        assert!(matches!(plan.steps[1], Action::Range(10, Some(u64::MAX))));
+        assert!(matches!(plan.steps[2], Action::Filter(_)));
+
+        let invalid = vec![Action::AggregateWindow(
+            "".to_string(),
+            AggregateFunction::Mean,
+        )];
+        assert!(QueryPlan::new(&invalid, &indexes).is_err());
+    }
+}
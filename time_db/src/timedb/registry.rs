@@ -0,0 +1,160 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use super::entry::Value;
+
+// Tags added for the built-in attributes of a device
+const RESERVED_TAGS: [&str; 5] = ["device", "room", "unit", "owner", "calibration_offset"];
+
+thread_local! {
+    pub static DEVICES: RefCell<DeviceRegistry> = RefCell::new(DeviceRegistry::default());
+}
+
+/// Metadata of a device, joined to the entries carrying its id in a tag by `Action::Enrich`.
+#[derive(Clone, CandidType, Deserialize, Debug, Default, PartialEq)]
+pub struct Device {
+    pub name: String,
+    pub room: Option<String>,
+    pub unit: Option<String>,
+    pub calibration_offset: Option<f32>,
+    pub owner: Option<String>,
+    pub attributes: Vec<(String, String)>, // any other attributes, e.g. ("building", "B")
+}
+
+impl Device {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, _) in &self.attributes {
+            if name.is_empty() || RESERVED_TAGS.contains(&name.as_str()) {
+                return Err(format!("Invalid attribute name '{}'", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Attributes of the device as tags: `device` holds the name, the other attributes keep
+    /// their names.
+    pub fn tags(&self) -> Vec<(String, Value)> {
+        let mut tags = vec![("device".to_string(), Value::String(self.name.clone()))];
+        let optional = [
+            ("room", &self.room),
+            ("unit", &self.unit),
+            ("owner", &self.owner),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                tags.push((name.to_string(), Value::String(value.clone())));
+            }
+        }
+        if let Some(offset) = self.calibration_offset {
+            tags.push(("calibration_offset".to_string(), Value::Float(offset)));
+        }
+        for (name, value) in &self.attributes {
+            tags.push((name.clone(), Value::String(value.clone())));
+        }
+        tags
+    }
+}
+
+/// Devices by id, the id being the value of a tag such as `sensor_id`.
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: BTreeMap<String, Device>,
+}
+
+impl DeviceRegistry {
+    pub fn set(&mut self, id: String, device: Device) -> Result<(), String> {
+        if id.is_empty() {
+            return Err("Device id can't be empty".to_string());
+        }
+        device.validate()?;
+        self.devices.insert(id, device);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Device> {
+        self.devices.remove(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Device> {
+        self.devices.get(id)
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (&String, &Device)> {
+        self.devices.iter()
+    }
+
+    /// Tags the device referenced by `tag` adds to the tags of an entry. Tags the entry
+    /// already has are kept.
+    pub fn enrichment(&self, tag: &str, tags: &HashMap<String, Value>) -> Vec<(String, Value)> {
+        let device = match tags.get(tag) {
+            Some(Value::String(id)) => self.devices.get(id),
+            _ => None,
+        };
+
+        device.map_or_else(Vec::new, |device| {
+            device
+                .tags()
+                .into_iter()
+                .filter(|(name, _)| !tags.contains_key(name))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timedb::Entry;
+
+    use super::*;
+
+    #[test]
+    fn test_enrichment() {
+        let mut registry = DeviceRegistry::default();
+        let device = Device {
+            name: "Boiler room thermometer".to_string(),
+            room: Some("B-012".to_string()),
+            calibration_offset: Some(-0.5),
+            attributes: vec![("building".to_string(), "B".to_string())],
+            ..Default::default()
+        };
+        registry.set("sensor_6".to_string(), device).unwrap();
+
+        let entry = Entry {
+            timestamp: 1,
+            fields: HashMap::from([("temperature".to_string(), Value::Float(61.5))]),
+            tags: HashMap::from([
+                (
+                    "sensor_id".to_string(),
+                    Value::String("sensor_6".to_string()),
+                ),
+                ("room".to_string(), Value::String("B-013".to_string())),
+            ]),
+        };
+        let tags: HashMap<_, _> = registry
+            .enrichment("sensor_id", &entry.tags)
+            .into_iter()
+            .collect();
+        assert_eq!(
+            tags,
+            HashMap::from([
+                (
+                    "device".to_string(),
+                    Value::String("Boiler room thermometer".to_string())
+                ),
+                ("calibration_offset".to_string(), Value::Float(-0.5)),
+                ("building".to_string(), Value::String("B".to_string())),
+            ])
+        );
+        assert!(registry.enrichment("location", &entry.tags).is_empty());
+
+        let reserved = Device {
+            attributes: vec![("room".to_string(), "A".to_string())],
+            ..Default::default()
+        };
+        assert!(registry.set("sensor_7".to_string(), reserved).is_err());
+        assert!(registry.set("".to_string(), Device::default()).is_err());
+    }
+}
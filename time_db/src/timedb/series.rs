@@ -0,0 +1,338 @@
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{btree_map, BTreeMap, BinaryHeap, HashMap},
+    mem::size_of,
+    rc::Rc,
+    vec,
+};
+
+use super::{
+    chunk::Chunk,
+    entry::{Entry, Record, Value},
+    tags::TagSet,
+};
+
+// Number of points collected in the head before it is sealed into a chunk
+const CHUNK_SIZE: usize = 1024;
+
+type Fields = HashMap<String, Value>;
+type Tags = HashMap<String, Value>;
+
+/// Points of a measurement that share the same tag set.
+///
+/// New points go into a small mutable head, which is sealed into a compressed chunk
+/// once it is full. Chunks are ordered by time and do not overlap, the head only holds
+/// points newer than the last chunk. Neither of them stores tags, they are attached
+/// from the shared tag set when points are read.
+pub struct Series {
+    pub tags: Rc<TagSet>,
+    head: BTreeMap<u64, Fields>,
+    chunks: Vec<Chunk>,
+    raw_tag_bytes: usize, // size of the tags of a point stored as a plain entry
+    sealed_raw_bytes: usize, // size the points in chunks would take as plain entries
+}
+
+impl Series {
+    pub fn new(tags: Rc<TagSet>) -> Self {
+        Self {
+            raw_tag_bytes: Entry::map_memory_size(&tags.to_map()),
+            tags,
+            head: BTreeMap::new(),
+            chunks: vec![],
+            sealed_raw_bytes: 0,
+        }
+    }
+
+    /// Adds a point, replacing an existing point with the same timestamp.
+    pub fn insert(&mut self, timestamp: u64, fields: Fields) {
+        match self.chunks.last() {
+            Some(last) if timestamp <= last.max_timestamp => self.insert_sealed(timestamp, fields),
+            _ => {
+                self.head.insert(timestamp, fields);
+                if self.head.len() >= CHUNK_SIZE {
+                    self.seal();
+                }
+            }
+        }
+    }
+
+    // Late write into the sealed part of the series, re-encodes the chunk that covers it
+    fn insert_sealed(&mut self, timestamp: u64, fields: Fields) {
+        let index = self
+            .chunks
+            .partition_point(|chunk| chunk.max_timestamp < timestamp);
+
+        let mut rows = self.chunks[index].decode();
+        self.sealed_raw_bytes -= rows
+            .iter()
+            .map(|(_, fields)| self.raw_size(fields))
+            .sum::<usize>();
+
+        match rows.binary_search_by_key(&timestamp, |(timestamp, _)| *timestamp) {
+            Ok(i) => rows[i].1 = fields,
+            Err(i) => rows.insert(i, (timestamp, fields)),
+        }
+        self.sealed_raw_bytes += rows
+            .iter()
+            .map(|(_, fields)| self.raw_size(fields))
+            .sum::<usize>();
+
+        let rows: Vec<(u64, &Fields)> = rows
+            .iter()
+            .map(|(timestamp, fields)| (*timestamp, fields))
+            .collect();
+        self.chunks[index] = Chunk::encode(&rows);
+    }
+
+    fn seal(&mut self) {
+        let rows: Vec<(u64, &Fields)> = self
+            .head
+            .iter()
+            .map(|(timestamp, fields)| (*timestamp, fields))
+            .collect();
+
+        self.sealed_raw_bytes += self
+            .head
+            .values()
+            .map(|fields| self.raw_size(fields))
+            .sum::<usize>();
+        self.chunks.push(Chunk::encode(&rows));
+        self.head.clear();
+    }
+
+    fn raw_size(&self, fields: &Fields) -> usize {
+        size_of::<Entry>() + Entry::map_memory_size(fields) + self.raw_tag_bytes
+    }
+
+    /// Points within `start..=end` in timestamp order, sealed points are decoded chunk by chunk.
+    pub fn scan(&self, start: u64, end: u64) -> SeriesIter<'_> {
+        SeriesIter {
+            series: self,
+            tags: Rc::new(self.tags.to_map()),
+            start,
+            end,
+            chunk: self
+                .chunks
+                .partition_point(|chunk| chunk.max_timestamp < start),
+            decoded: Vec::new().into_iter(),
+            head: self.head.range(start..=end),
+        }
+    }
+
+    /// Memory used by the series. Raw bytes are what its points would take as plain entries.
+    pub fn stats(&self) -> SeriesStats {
+        SeriesStats {
+            points: self.chunks.iter().map(|chunk| chunk.len).sum::<usize>() + self.head.len(),
+            sealed_chunks: self.chunks.len(),
+            head_points: self.head.len(),
+            raw_bytes: self.sealed_raw_bytes
+                + self
+                    .head
+                    .values()
+                    .map(|fields| self.raw_size(fields))
+                    .sum::<usize>(),
+            stored_bytes: size_of::<Series>()
+                + self.tags.memory_size()
+                + self.chunks.iter().map(|chunk| chunk.size()).sum::<usize>()
+                + self
+                    .head
+                    .values()
+                    .map(|fields| size_of::<(u64, Fields)>() + Entry::map_memory_size(fields))
+                    .sum::<usize>(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SeriesStats {
+    pub points: usize,
+    pub sealed_chunks: usize,
+    pub head_points: usize,
+    pub raw_bytes: usize,
+    pub stored_bytes: usize,
+}
+
+/// Point read from a series. The fields of points in the head are borrowed, those of sealed
+/// points are decoded. The tags are built once per scan of a series and shared by its points.
+pub struct Point<'a> {
+    pub timestamp: u64,
+    pub fields: Cow<'a, Fields>,
+    pub tags: Rc<Tags>,
+}
+
+impl Point<'_> {
+    pub fn into_entry(self) -> Entry {
+        Entry {
+            timestamp: self.timestamp,
+            fields: self.fields.into_owned(),
+            tags: Rc::unwrap_or_clone(self.tags),
+        }
+    }
+}
+
+// Points computed by a query, e.g. aggregated windows
+impl From<Entry> for Point<'_> {
+    fn from(entry: Entry) -> Self {
+        Point {
+            timestamp: entry.timestamp,
+            fields: Cow::Owned(entry.fields),
+            tags: Rc::new(entry.tags),
+        }
+    }
+}
+
+impl Record for Point<'_> {
+    fn get_value(&self, field: &str) -> Option<&Value> {
+        Entry::lookup(&self.fields, &self.tags, field)
+    }
+}
+
+pub struct SeriesIter<'a> {
+    series: &'a Series,
+    tags: Rc<Tags>, // tags attached to every point read
+    start: u64,
+    end: u64,
+    chunk: usize, // next chunk to decode
+    decoded: vec::IntoIter<(u64, Fields)>,
+    head: btree_map::Range<'a, u64, Fields>,
+}
+
+impl SeriesIter<'_> {
+    pub fn tags(&self) -> &Rc<Tags> {
+        &self.tags
+    }
+}
+
+impl<'a> Iterator for SeriesIter<'a> {
+    type Item = Point<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((timestamp, fields)) = self.decoded.next() {
+                if timestamp < self.start {
+                    continue;
+                }
+                if timestamp > self.end {
+                    self.decoded = Vec::new().into_iter();
+                    self.chunk = self.series.chunks.len();
+                    continue;
+                }
+
+                return Some(Point {
+                    timestamp,
+                    fields: Cow::Owned(fields),
+                    tags: self.tags.clone(),
+                });
+            }
+
+            match self.series.chunks.get(self.chunk) {
+                Some(chunk) if chunk.min_timestamp <= self.end => {
+                    self.decoded = chunk.decode().into_iter();
+                    self.chunk += 1;
+                }
+                _ => {
+                    self.chunk = self.series.chunks.len();
+                    return self.head.next().map(|(timestamp, fields)| Point {
+                        timestamp: *timestamp,
+                        fields: Cow::Borrowed(fields),
+                        tags: self.tags.clone(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Merges the points of several series into one stream ordered by timestamp.
+pub struct MergeIter<'a> {
+    sources: Vec<SeriesIter<'a>>,
+    peeked: Vec<Option<Point<'a>>>,
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+}
+
+impl<'a> MergeIter<'a> {
+    pub fn new(mut sources: Vec<SeriesIter<'a>>) -> Self {
+        let mut heap = BinaryHeap::new();
+        let peeked = sources
+            .iter_mut()
+            .enumerate()
+            .map(|(i, source)| {
+                let entry = source.next();
+                if let Some(entry) = &entry {
+                    heap.push(Reverse((entry.timestamp, i)));
+                }
+                entry
+            })
+            .collect();
+
+        Self {
+            sources,
+            peeked,
+            heap,
+        }
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Point<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, i)) = self.heap.pop()?;
+        let entry = self.peeked[i].take();
+
+        if let Some(next) = self.sources[i].next() {
+            self.heap.push(Reverse((next.timestamp, i)));
+            self.peeked[i] = Some(next);
+        }
+
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timedb::tags::Interner;
+
+    use super::*;
+
+    fn fields(value: i128) -> Fields {
+        HashMap::from([("value".to_string(), Value::Int(value))])
+    }
+
+    #[test]
+    fn test_series_seals_and_scans() {
+        let tags = HashMap::from([("sensor_id".to_string(), Value::String("s1".to_string()))]);
+        let mut series = Series::new(Rc::new(TagSet::new(&tags, &mut Interner::default())));
+        for timestamp in 0..(CHUNK_SIZE as u64 * 2 + 10) {
+            series.insert(timestamp * 10, fields(timestamp as i128));
+        }
+
+        let stats = series.stats();
+        assert_eq!(stats.sealed_chunks, 2);
+        assert_eq!(stats.head_points, 10);
+        assert!(stats.stored_bytes < stats.raw_bytes);
+
+        // late write into a sealed chunk replaces the point
+        series.insert(50, fields(-1));
+        series.insert(55, fields(55));
+
+        let points: Vec<Point> = series.scan(40, 10_300).collect();
+        let timestamps: Vec<u64> = points.iter().map(|entry| entry.timestamp).collect();
+        let mut expected: Vec<u64> = (4..=1030).map(|i| i * 10).collect();
+        expected.insert(2, 55);
+
+        assert_eq!(timestamps, expected);
+        assert_eq!(points[1].fields["value"], Value::Int(-1));
+        assert_eq!(*points[0].tags, tags);
+        assert_eq!(*points.last().unwrap().tags, tags);
+        assert!(Rc::ptr_eq(&points[0].tags, &points.last().unwrap().tags));
+
+        // points of the head are borrowed
+        let head: Vec<Point> = series.scan(CHUNK_SIZE as u64 * 20, u64::MAX).collect();
+        assert_eq!(head.len(), 10);
+        assert!(head
+            .iter()
+            .all(|point| matches!(point.fields, Cow::Borrowed(_))));
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+    mem::size_of,
+    rc::Rc,
+};
+
+use super::entry::Value;
+
+/// Dictionary of the tag names and string tag values of a measurement.
+/// Every distinct string is stored once, tag sets refer to it.
+#[derive(Default)]
+pub struct Interner {
+    strings: HashSet<Rc<str>>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, value: &str) -> Rc<str> {
+        if let Some(interned) = self.strings.get(value) {
+            return interned.clone();
+        }
+
+        let interned: Rc<str> = Rc::from(value);
+        self.strings.insert(interned.clone());
+        interned
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Bytes used by the dictionary, including the strings.
+    pub fn memory_size(&self) -> usize {
+        self.strings
+            .iter()
+            .map(|value| size_of::<Rc<str>>() + 1 + 2 * size_of::<usize>() + value.len()) // bucket and Rc counters
+            .sum()
+    }
+}
+
+#[derive(Debug)]
+pub enum TagValue {
+    String(Rc<str>),
+    Other(Value), // non string tags are rare, they are not interned
+}
+
+impl TagValue {
+    pub fn to_value(&self) -> Value {
+        match self {
+            TagValue::String(value) => Value::String(value.to_string()),
+            TagValue::Other(value) => value.clone(),
+        }
+    }
+}
+
+/// Tags of a series, shared by all of its points. Tags are ordered by name.
+#[derive(Debug, PartialEq)]
+pub struct TagSet {
+    tags: Vec<(Rc<str>, TagValue)>,
+}
+
+impl TagSet {
+    pub fn new(tags: &HashMap<String, Value>, interner: &mut Interner) -> Self {
+        let mut tags: Vec<(Rc<str>, TagValue)> = tags
+            .iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::String(value) => TagValue::String(interner.intern(value)),
+                    value => TagValue::Other(value.clone()),
+                };
+                (interner.intern(name), value)
+            })
+            .collect();
+        tags.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Self { tags }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TagValue> {
+        self.tags
+            .binary_search_by(|(tag, _)| (**tag).cmp(name))
+            .ok()
+            .map(|i| &self.tags[i].1)
+    }
+
+    pub fn string_tags(&self) -> impl Iterator<Item = (&Rc<str>, &Rc<str>)> {
+        self.tags.iter().filter_map(|(tag, value)| match value {
+            TagValue::String(value) => Some((tag, value)),
+            TagValue::Other(_) => None,
+        })
+    }
+
+    /// The tags in the form they are returned by queries.
+    pub fn to_map(&self) -> HashMap<String, Value> {
+        self.tags
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_value()))
+            .collect()
+    }
+
+    /// Bytes used by the tag set, interned strings are accounted for by the interner.
+    pub fn memory_size(&self) -> usize {
+        size_of::<TagSet>()
+            + 2 * size_of::<usize>()
+            + self.tags.len() * size_of::<(Rc<str>, TagValue)>()
+    }
+}
+
+// Float tags are compared by their bits, a NaN tag identifies the same series every time
+impl PartialEq for TagValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (TagValue::String(a), TagValue::String(b)) => a == b,
+            (TagValue::Other(Value::Float(a)), TagValue::Other(Value::Float(b))) => {
+                a.to_bits() == b.to_bits()
+            }
+            (TagValue::Other(a), TagValue::Other(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+// Tag sets are compared by value
+impl Eq for TagSet {}
+
+impl Hash for TagSet {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for (name, value) in &self.tags {
+            name.hash(state);
+            match value {
+                TagValue::String(value) => value.hash(state),
+                TagValue::Other(Value::Float(value)) => value.to_bits().hash(state),
+                TagValue::Other(value) => format!("{:?}", value).hash(state),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_sets_share_interned_strings() {
+        let mut interner = Interner::default();
+        let tags = |sensor: &str| {
+            HashMap::from([
+                ("sensor_id".to_string(), Value::String(sensor.to_string())),
+                (
+                    "location".to_string(),
+                    Value::String("test_location".to_string()),
+                ),
+                ("floor".to_string(), Value::Int(2)),
+            ])
+        };
+
+        let first = TagSet::new(&tags("sensor_1"), &mut interner);
+        let second = TagSet::new(&tags("sensor_2"), &mut interner);
+
+        // sensor_id, location, floor, test_location, sensor_1, sensor_2
+        assert_eq!(interner.len(), 6);
+        match (first.get("location"), second.get("location")) {
+            (Some(TagValue::String(a)), Some(TagValue::String(b))) => assert!(Rc::ptr_eq(a, b)),
+            _ => panic!("location should be an interned string"),
+        }
+
+        assert_ne!(first, second);
+        assert_eq!(first, TagSet::new(&tags("sensor_1"), &mut interner));
+        assert_eq!(first.to_map(), tags("sensor_1"));
+        assert_eq!(first.get("floor"), Some(&TagValue::Other(Value::Int(2))));
+
+        let nan = HashMap::from([("offset".to_string(), Value::Float(f32::NAN))]);
+        assert_eq!(
+            TagSet::new(&nan, &mut interner),
+            TagSet::new(&nan, &mut interner)
+        );
+    }
+}
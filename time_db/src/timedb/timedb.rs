@@ -23,6 +23,16 @@ impl TimeDb {
 
         self.db.get_mut(name).unwrap()
     }
+
+    /// Looks up a measurement without creating it.
+    pub fn find_measurement(&self, name: &str) -> Option<&Measurement> {
+        self.db.get(name)
+    }
+
+    /// Measurements ordered by name.
+    pub fn measurements(&self) -> impl Iterator<Item = (&String, &Measurement)> {
+        self.db.iter()
+    }
 }
 
 thread_local! {
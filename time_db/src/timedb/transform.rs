@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use super::entry::Value;
+
+/// Field holding the version of the rules a point was transformed with.
+pub const VERSION_FIELD: &str = "_transform_version";
+/// Suffix of the field keeping the value as received, e.g. `temperature_raw`.
+pub const RAW_SUFFIX: &str = "_raw";
+/// Field listing the transformed fields of a point whose raw value wasn't kept, as a JSON
+/// array of names.
+pub const LOST_FIELD: &str = "_transform_lost";
+
+#[derive(Clone, Copy, CandidType, Deserialize, Debug, PartialEq)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+    Bar,
+    Kilopascal,
+    Psi,
+}
+
+impl Unit {
+    // Factor and offset turning a value into the base unit of its quantity (°C, bar)
+    fn to_base(self) -> (&'static str, f64, f64) {
+        match self {
+            Unit::Celsius => ("temperature", 1.0, 0.0),
+            Unit::Fahrenheit => ("temperature", 5.0 / 9.0, -32.0 * 5.0 / 9.0),
+            Unit::Kelvin => ("temperature", 1.0, -273.15),
+            Unit::Bar => ("pressure", 1.0, 0.0),
+            Unit::Kilopascal => ("pressure", 0.01, 0.0),
+            Unit::Psi => ("pressure", 0.0689476, 0.0),
+        }
+    }
+}
+
+/// Step of a transformation, applied to numeric values.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub enum Transform {
+    Linear { scale: f64, offset: f64 }, // value * scale + offset
+    Convert(Unit, Unit),                // from, to
+    CurrentLoop { min: f64, max: f64 }, // maps 4-20 mA onto the range of the sensor, e.g. 0-10 bar
+    Clamp { min: Option<f64>, max: Option<f64> },
+}
+
+impl Transform {
+    fn validate(&self) -> Result<(), String> {
+        let finite = |values: &[f64]| values.iter().all(|value| value.is_finite());
+        match self {
+            Transform::Linear { scale, offset } if !finite(&[*scale, *offset]) => {
+                Err("Linear scale and offset must be finite".to_string())
+            }
+            Transform::Convert(from, to) if from.to_base().0 != to.to_base().0 => {
+                Err(format!("Can't convert {:?} to {:?}", from, to))
+            }
+            Transform::CurrentLoop { min, max } if !finite(&[*min, *max]) => {
+                Err("Current loop range must be finite".to_string())
+            }
+            Transform::Clamp {
+                min: Some(min),
+                max: Some(max),
+            } if min > max => Err(format!("Clamp minimum {} is above maximum {}", min, max)),
+            _ => Ok(()),
+        }
+    }
+
+    fn apply(&self, value: f64) -> f64 {
+        match self {
+            Transform::Linear { scale, offset } => value * scale + offset,
+            Transform::Convert(from, to) => {
+                let (_, from_scale, from_offset) = from.to_base();
+                let (_, to_scale, to_offset) = to.to_base();
+                (value * from_scale + from_offset - to_offset) / to_scale
+            }
+            Transform::CurrentLoop { min, max } => min + (value - 4.0) / 16.0 * (max - min),
+            Transform::Clamp { min, max } => {
+                let value = min.map_or(value, |min| value.max(min));
+                max.map_or(value, |max| value.min(max))
+            }
+        }
+    }
+}
+
+/// Transformation of a field, for the points carrying all `tags` (e.g. the id of a device).
+/// Rules without tags apply to every point.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub struct TransformRule {
+    pub field: String,
+    pub tags: Vec<(String, String)>,
+    pub steps: Vec<Transform>,
+    pub keep_raw: bool, // keeps the value as received in `<field>_raw`
+}
+
+impl TransformRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.field.is_empty()
+            || self.field == VERSION_FIELD
+            || self.field == LOST_FIELD
+            || self.field.ends_with(RAW_SUFFIX)
+        {
+            return Err(format!("Invalid field '{}'", self.field));
+        }
+        self.steps.iter().try_for_each(Transform::validate)
+    }
+
+    fn matches(&self, tags: &HashMap<String, Value>) -> bool {
+        self.tags.iter().all(|(name, value)| match tags.get(name) {
+            Some(Value::String(tag)) => tag == value,
+            _ => false,
+        })
+    }
+}
+
+/// Transform rules of a measurement, the version increases with every change.
+#[derive(Clone, CandidType, Deserialize, Debug, Default, PartialEq)]
+pub struct TransformRules {
+    pub version: u64,
+    pub rules: Vec<TransformRule>,
+}
+
+impl TransformRules {
+    /// Transforms the fields of a point with the first rule matching each field, and records
+    /// the version of the rules and the fields transformed without a raw value in the point.
+    /// Fields in `skip` and values that aren't numbers are left as they are, skipped fields
+    /// still count as transformed without a raw value.
+    pub fn apply(
+        &self,
+        fields: &mut HashMap<String, Value>,
+        tags: &HashMap<String, Value>,
+        skip: &[String],
+    ) {
+        let mut transformed = !skip.is_empty();
+        let mut without_raw = skip.to_vec();
+        for (name, value) in fields.clone() {
+            if skip.contains(&name) {
+                continue;
+            }
+            let Some(rule) = self
+                .rules
+                .iter()
+                .find(|rule| rule.field == name && rule.matches(tags))
+            else {
+                continue;
+            };
+            let Some(number) = as_number(&value) else {
+                continue;
+            };
+
+            let result = rule
+                .steps
+                .iter()
+                .fold(number, |value, step| step.apply(value));
+            fields.insert(name.clone(), Value::Float(result as f32));
+            if rule.keep_raw {
+                fields.insert(format!("{}{}", name, RAW_SUFFIX), value);
+            } else {
+                without_raw.push(name);
+            }
+            transformed = true;
+        }
+
+        if transformed {
+            fields.insert(VERSION_FIELD.to_string(), Value::UInt(self.version as u128));
+        }
+        if !without_raw.is_empty() {
+            without_raw.sort();
+            let names = serde_json::to_string(&without_raw).unwrap_or_default();
+            fields.insert(LOST_FIELD.to_string(), Value::String(names));
+        }
+    }
+
+    /// Fields of a stored point as they were received, as far as they were kept. Returns them
+    /// with the names of the fields that were transformed without a raw value, which hold
+    /// transformed values and can't be transformed again.
+    pub fn restore(fields: &HashMap<String, Value>) -> (HashMap<String, Value>, Vec<String>) {
+        let mut restored = fields.clone();
+        if restored.remove(VERSION_FIELD).is_none() {
+            return (restored, vec![]);
+        }
+
+        let lost = match restored.remove(LOST_FIELD) {
+            Some(Value::String(names)) => serde_json::from_str(&names).unwrap_or_default(),
+            _ => vec![],
+        };
+        for (name, value) in fields {
+            if let Some(field) = name.strip_suffix(RAW_SUFFIX) {
+                restored.remove(name);
+                restored.insert(field.to_string(), value.clone());
+            }
+        }
+
+        (restored, lost)
+    }
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Int(value) => Some(*value as f64),
+        Value::UInt(value) => Some(*value as f64),
+        Value::Float(value) => Some(*value as f64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(steps: Vec<Transform>, value: f64) -> f64 {
+        steps.iter().fold(value, |value, step| step.apply(value))
+    }
+
+    #[test]
+    fn test_transforms() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(
+            transform(
+                vec![Transform::Convert(Unit::Fahrenheit, Unit::Celsius)],
+                212.0
+            ),
+            100.0
+        ));
+        assert!(close(
+            transform(vec![Transform::Convert(Unit::Celsius, Unit::Kelvin)], 0.0),
+            273.15
+        ));
+        assert!(close(
+            transform(vec![Transform::Convert(Unit::Bar, Unit::Kilopascal)], 1.5),
+            150.0
+        ));
+        assert!(close(
+            transform(
+                vec![Transform::CurrentLoop {
+                    min: 0.0,
+                    max: 10.0
+                }],
+                12.0
+            ),
+            5.0
+        ));
+        let calibrated = vec![
+            Transform::Linear {
+                scale: 1.0,
+                offset: -0.5,
+            },
+            Transform::Clamp {
+                min: Some(0.0),
+                max: None,
+            },
+        ];
+        assert!(close(transform(calibrated.clone(), 0.2), 0.0));
+        assert!(close(transform(calibrated, 20.5), 20.0));
+
+        assert!(Transform::Convert(Unit::Psi, Unit::Kelvin)
+            .validate()
+            .is_err());
+        assert!(Transform::Clamp {
+            min: Some(1.0),
+            max: Some(0.0)
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_apply_and_restore() {
+        let rules = TransformRules {
+            version: 3,
+            rules: vec![
+                TransformRule {
+                    field: "temperature".to_string(),
+                    tags: vec![("sensor_id".to_string(), "sensor_6".to_string())],
+                    steps: vec![Transform::Convert(Unit::Fahrenheit, Unit::Celsius)],
+                    keep_raw: true,
+                },
+                TransformRule {
+                    field: "pressure".to_string(),
+                    tags: vec![],
+                    steps: vec![Transform::CurrentLoop { min: 0.0, max: 4.0 }],
+                    keep_raw: false,
+                },
+            ],
+        };
+        let tags = HashMap::from([(
+            "sensor_id".to_string(),
+            Value::String("sensor_6".to_string()),
+        )]);
+        let received = HashMap::from([
+            ("temperature".to_string(), Value::Int(50)),
+            ("pressure".to_string(), Value::Float(20.0)),
+            ("state".to_string(), Value::String("ok".to_string())),
+        ]);
+
+        let mut fields = received.clone();
+        rules.apply(&mut fields, &tags, &[]);
+        assert_eq!(fields.get("temperature"), Some(&Value::Float(10.0)));
+        assert_eq!(fields.get("temperature_raw"), Some(&Value::Int(50)));
+        assert_eq!(fields.get("pressure"), Some(&Value::Float(4.0)));
+        assert!(!fields.contains_key("pressure_raw"));
+        assert_eq!(fields.get(VERSION_FIELD), Some(&Value::UInt(3)));
+        assert_eq!(
+            fields.get(LOST_FIELD),
+            Some(&Value::String("[\"pressure\"]".to_string()))
+        );
+
+        // only the fields transformed without a raw value are lost
+        let (restored, lost) = TransformRules::restore(&fields);
+        assert_eq!(restored.get("temperature"), Some(&Value::Int(50)));
+        assert_eq!(
+            restored.get("state"),
+            Some(&Value::String("ok".to_string()))
+        );
+        assert_eq!(restored.len(), 3);
+        assert_eq!(lost, vec!["pressure".to_string()]);
+
+        // other devices and untransformed points are left as they are
+        let mut received = received;
+        received.remove("pressure");
+        let mut fields = received.clone();
+        rules.apply(&mut fields, &HashMap::new(), &[]);
+        assert_eq!(fields, received);
+        assert_eq!(TransformRules::restore(&fields), (received, vec![]));
+    }
+}
@@ -0,0 +1,50 @@
+use candid::candid_method;
+use ic_cdk_macros::{query, update};
+
+use crate::timedb::{ReprocessResult, TransformRule, TransformRules};
+use crate::{caller_query_limits, ensure_owner, TIME_DB};
+
+/// Transform rules of the measurement with their version, no rules for unknown measurements.
+#[query]
+#[candid_method(query)]
+fn get_transform_rules(measurement: String) -> TransformRules {
+    TIME_DB.with(|m| {
+        m.borrow()
+            .find_measurement(&measurement)
+            .map(|measure| measure.transform_rules().clone())
+            .unwrap_or_default()
+    })
+}
+
+/// Replaces the rules transforming the fields of new points of the measurement, returns
+/// the new version of the rules.
+#[update]
+#[candid_method(update)]
+fn set_transform_rules(measurement: String, rules: Vec<TransformRule>) -> Result<u64, String> {
+    ensure_owner()?;
+    TIME_DB.with(|m| {
+        m.borrow_mut()
+            .get_measurement(&measurement)
+            .set_transform_rules(rules)
+    })
+}
+
+/// Transforms the points of the measurement within the time range again with the current
+/// rules, e.g. after a calibration changed. Returns the number of points that changed. The
+/// work of a call is bounded by the query limits of the caller, when they cut the time range
+/// short the result holds the cursor to call again with as `start`.
+#[update]
+#[candid_method(update)]
+fn reprocess(measurement: String, start: u64, end: Option<u64>) -> Result<ReprocessResult, String> {
+    ensure_owner()?;
+    let limits = caller_query_limits();
+    TIME_DB.with(|m| {
+        let mut db = m.borrow_mut();
+        if db.find_measurement(&measurement).is_none() {
+            return Err(format!("Measurement '{}' not found", measurement));
+        }
+        Ok(db
+            .get_measurement(&measurement)
+            .reprocess(start, end.unwrap_or(u64::MAX), &limits))
+    })
+}